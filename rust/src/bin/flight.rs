@@ -0,0 +1,30 @@
+//! Serves a portal over Arrow Flight.
+//!
+//! Usage: `sqd-portal-flight <portal url> [listen address]`
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use sqd_portal_client::flight::PortalFlightService;
+use sqd_portal_client::{Client, ClientConfig, StreamConfig};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let url = args
+        .next()
+        .context("usage: sqd-portal-flight <portal url> [listen address]")?;
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:50051".to_owned());
+
+    let client = Client::new(
+        url.parse().context("parse portal url")?,
+        ClientConfig::default(),
+    );
+    let service = PortalFlightService::new(Arc::new(client), StreamConfig::default());
+
+    tonic::transport::Server::builder()
+        .add_service(service.into_server())
+        .serve(addr.parse().context("parse listen address")?)
+        .await
+        .context("run server")
+}
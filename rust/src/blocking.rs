@@ -0,0 +1,175 @@
+//! Synchronous wrapper around [`Client`](crate::Client).
+//!
+//! Each [`BlockingClient`] owns a single threaded tokio runtime and blocks the calling thread
+//! while requests are running. Its methods panic if they are called from inside an async context.
+
+use std::pin::Pin;
+
+use anyhow::{Context, Result};
+use futures_lite::{Stream, StreamExt};
+use reqwest::Url;
+use tokio::runtime::Runtime;
+
+use crate::{evm, stop_when, svm, Client, ClientConfig, StreamConfig, StreamItem};
+
+pub struct BlockingClient {
+    inner: Client,
+    rt: Runtime,
+}
+
+impl BlockingClient {
+    pub fn new(url: Url, config: ClientConfig) -> Result<Self> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("build tokio runtime")?;
+
+        Ok(Self {
+            inner: Client::new(url, config),
+            rt,
+        })
+    }
+
+    pub fn svm_arrow_finalized_query(
+        &self,
+        query: &svm::Query,
+    ) -> Result<Option<svm::ArrowResponse>> {
+        self.rt
+            .block_on(self.inner.svm_arrow_finalized_query(query))
+    }
+
+    pub fn svm_arrow_finalized_stream(
+        &self,
+        query: svm::Query,
+        config: StreamConfig,
+    ) -> BlockingStream<'_, StreamItem<svm::ArrowResponse>> {
+        BlockingStream::new(
+            &self.rt,
+            self.inner.svm_arrow_finalized_lazy_stream(query, config),
+        )
+    }
+
+    /// Same as `svm_arrow_finalized_stream` but ends the stream after the first response `pred`
+    /// returns true for, that response is yielded as well.
+    pub fn svm_arrow_finalized_stream_until<'a>(
+        &'a self,
+        query: svm::Query,
+        config: StreamConfig,
+        pred: impl FnMut(&svm::ArrowResponse) -> bool + Send + 'a,
+    ) -> BlockingStream<'a, StreamItem<svm::ArrowResponse>> {
+        BlockingStream::new(
+            &self.rt,
+            stop_when(
+                self.inner.svm_arrow_finalized_lazy_stream(query, config),
+                pred,
+            ),
+        )
+    }
+
+    pub fn evm_arrow_finalized_query(
+        &self,
+        query: &evm::Query,
+    ) -> Result<Option<evm::ArrowResponse>> {
+        self.rt
+            .block_on(self.inner.evm_arrow_finalized_query(query))
+    }
+
+    pub fn evm_arrow_finalized_stream(
+        &self,
+        query: evm::Query,
+        config: StreamConfig,
+    ) -> BlockingStream<'_, StreamItem<evm::ArrowResponse>> {
+        BlockingStream::new(
+            &self.rt,
+            self.inner.evm_arrow_finalized_lazy_stream(query, config),
+        )
+    }
+
+    /// Same as `evm_arrow_finalized_stream` but ends the stream after the first response `pred`
+    /// returns true for, that response is yielded as well.
+    pub fn evm_arrow_finalized_stream_until<'a>(
+        &'a self,
+        query: evm::Query,
+        config: StreamConfig,
+        pred: impl FnMut(&evm::ArrowResponse) -> bool + Send + 'a,
+    ) -> BlockingStream<'a, StreamItem<evm::ArrowResponse>> {
+        BlockingStream::new(
+            &self.rt,
+            stop_when(
+                self.inner.evm_arrow_finalized_lazy_stream(query, config),
+                pred,
+            ),
+        )
+    }
+
+    pub fn finalized_height(&self) -> Result<u64> {
+        self.rt.block_on(self.inner.finalized_height())
+    }
+}
+
+/// Iterator over a stream created by [`BlockingClient`].
+///
+/// The next response is only requested when `next` is called.
+pub struct BlockingStream<'a, T> {
+    rt: &'a Runtime,
+    stream: Pin<Box<dyn Stream<Item = Result<T>> + Send + 'a>>,
+}
+
+impl<'a, T> BlockingStream<'a, T> {
+    fn new(rt: &'a Runtime, stream: impl Stream<Item = Result<T>> + Send + 'a) -> Self {
+        Self {
+            rt,
+            stream: Box::pin(stream),
+        }
+    }
+}
+
+impl<T> Iterator for BlockingStream<'_, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rt.block_on(self.stream.next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_ends_on_empty_range() {
+        let url = "http://127.0.0.1:1".parse().unwrap();
+        let client = BlockingClient::new(url, ClientConfig::default()).unwrap();
+
+        let query = svm::Query {
+            from_block: 10,
+            to_block: Some(9),
+            ..Default::default()
+        };
+
+        let mut stream = client.svm_arrow_finalized_stream(query, StreamConfig::default());
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    #[ignore]
+    fn blocking_evm_query() {
+        let url = "https://portal.sqd.dev/datasets/ethereum-mainnet"
+            .parse()
+            .unwrap();
+        let client = BlockingClient::new(url, ClientConfig::default()).unwrap();
+
+        let height = client.finalized_height().unwrap();
+
+        let query = evm::Query {
+            from_block: height - 10,
+            to_block: Some(height),
+            include_all_blocks: true,
+            ..Default::default()
+        };
+
+        for arrow_data in client.evm_arrow_finalized_stream(query, StreamConfig::default()) {
+            dbg!(arrow_data.unwrap().data.blocks.num_rows());
+        }
+    }
+}
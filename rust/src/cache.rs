@@ -0,0 +1,385 @@
+//! On-disk cache of responses.
+//!
+//! The portal only serves finalized blocks so a response to the same query from the same block
+//! never changes and can be served from disk the next time it is requested.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use serde::Deserialize;
+
+use crate::query::PortalQuery;
+use crate::{telemetry, Client};
+
+const EXTENSION: &str = "ndjson.zst";
+const TMP_EXTENSION: &str = "tmp";
+
+/// Limits and compression of a `ResponseCache`.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    /// Maximum size of the cached files, the least recently used ones are removed above it.
+    pub max_bytes: u64,
+    /// zstd compression level of the cached files.
+    pub compression_level: i32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1 << 30,
+            compression_level: 3,
+        }
+    }
+}
+
+/// Stores response bodies as zstd compressed NDJSON files in a directory per query.
+///
+/// The directory is named after a hash of the query without its `from_block` and each file is
+/// named `{from}-{to}.ndjson.zst` after the block range of the response.
+///
+/// File IO is blocking.
+pub struct ResponseCache {
+    dir: PathBuf,
+    config: CacheConfig,
+    index: Mutex<Index>,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<(String, u64), Entry>,
+    num_bytes: u64,
+    /// Incremented on every access to order the entries by their last use.
+    clock: u64,
+}
+
+struct Entry {
+    to_block: u64,
+    num_bytes: u64,
+    last_used: u64,
+}
+
+impl ResponseCache {
+    /// Opens the cache in the directory, creating it if it doesn't exist.
+    ///
+    /// Files that are already in the directory are used, in the order of their modification time
+    /// for eviction.
+    pub fn open(dir: impl Into<PathBuf>, config: CacheConfig) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).context("create directory")?;
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir).context("read directory")? {
+            let entry = entry.context("read directory entry")?;
+            if !entry.file_type().context("get file type")?.is_dir() {
+                continue;
+            }
+            let Some(key) = entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            for file in fs::read_dir(entry.path()).context("read query directory")? {
+                let file = file.context("read query directory entry")?;
+                let path = file.path();
+                let name = file.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                if name.ends_with(TMP_EXTENSION) {
+                    fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+                    continue;
+                }
+                let Some((from_block, to_block)) = parse_range(name) else {
+                    continue;
+                };
+                let meta = file.metadata().context("read file metadata")?;
+                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                files.push((modified, key.clone(), from_block, to_block, meta.len()));
+            }
+        }
+        files.sort_by_key(|(modified, ..)| *modified);
+
+        let mut index = Index::default();
+        for (_, key, from_block, to_block, num_bytes) in files {
+            index.clock += 1;
+            index.num_bytes += num_bytes;
+            index.entries.insert(
+                (key, from_block),
+                Entry {
+                    to_block,
+                    num_bytes,
+                    last_used: index.clock,
+                },
+            );
+        }
+
+        let cache = Self {
+            dir,
+            config,
+            index: Mutex::new(index),
+        };
+        cache.evict(&mut cache.index.lock().unwrap());
+
+        Ok(cache)
+    }
+
+    /// Total size of the cached files.
+    pub fn num_bytes(&self) -> u64 {
+        self.index.lock().unwrap().num_bytes
+    }
+
+    /// Removes every cached file.
+    ///
+    /// Files that can't be removed are logged and dropped from the cache like the others, so
+    /// they are no longer served or counted.
+    pub fn clear(&self) {
+        let mut index = self.index.lock().unwrap();
+        for ((key, from_block), entry) in index.entries.drain() {
+            let path = self.path(&key, from_block, entry.to_block);
+            if let Err(e) = remove_file(&path) {
+                log::warn!("failed to remove cached response: {:?}", e);
+            }
+        }
+        index.num_bytes = 0;
+    }
+
+    /// Hash of the query with its `from_block` left out.
+    fn key<Q: PortalQuery>(query: &Q) -> Result<String> {
+        let mut query = query.clone();
+        query.set_cursor(0);
+        let json = query.to_json().context("serialize query")?;
+        Ok(faster_hex::hex_string(
+            alloy_primitives::keccak256(json).as_slice(),
+        ))
+    }
+
+    fn path(&self, key: &str, from_block: u64, to_block: u64) -> PathBuf {
+        self.dir
+            .join(key)
+            .join(format!("{}-{}.{}", from_block, to_block, EXTENSION))
+    }
+
+    fn get(&self, key: &str, from_block: u64) -> Result<Option<Bytes>> {
+        let path = {
+            let mut index = self.index.lock().unwrap();
+            index.clock += 1;
+            let clock = index.clock;
+            match index.entries.get_mut(&(key.to_owned(), from_block)) {
+                Some(entry) => {
+                    entry.last_used = clock;
+                    self.path(key, from_block, entry.to_block)
+                }
+                None => return Ok(None),
+            }
+        };
+
+        let file = File::open(&path).with_context(|| format!("open {}", path.display()))?;
+        let mut data = Vec::new();
+        zstd::Decoder::new(file)
+            .context("create decoder")?
+            .read_to_end(&mut data)
+            .with_context(|| format!("read {}", path.display()))?;
+
+        Ok(Some(Bytes::from(data)))
+    }
+
+    fn put(&self, key: &str, from_block: u64, data: &[u8]) -> Result<()> {
+        let to_block = last_block(data).context("get last block of response")?;
+        let path = self.path(key, from_block, to_block);
+        let tmp_path = path.with_extension(TMP_EXTENSION);
+
+        fs::create_dir_all(self.dir.join(key)).context("create query directory")?;
+        let file =
+            File::create(&tmp_path).with_context(|| format!("create {}", tmp_path.display()))?;
+        let mut encoder =
+            zstd::Encoder::new(file, self.config.compression_level).context("create encoder")?;
+        encoder.write_all(data).context("write response")?;
+        encoder.finish().context("finish file")?.sync_all().ok();
+        fs::rename(&tmp_path, &path).with_context(|| format!("rename {}", tmp_path.display()))?;
+        let num_bytes = fs::metadata(&path).context("read file metadata")?.len();
+
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+        let entry = Entry {
+            to_block,
+            num_bytes,
+            last_used: index.clock,
+        };
+        if let Some(old) = index.entries.insert((key.to_owned(), from_block), entry) {
+            index.num_bytes -= old.num_bytes;
+            if old.to_block != to_block {
+                remove_file(&self.path(key, from_block, old.to_block))?;
+            }
+        }
+        index.num_bytes += num_bytes;
+        self.evict(&mut index);
+
+        Ok(())
+    }
+
+    /// Removes the least recently used files until the cache fits into `max_bytes`.
+    fn evict(&self, index: &mut Index) {
+        while index.num_bytes > self.config.max_bytes {
+            let Some(oldest) = index
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(k, _)| k.clone())
+            else {
+                break;
+            };
+            let entry = index.entries.remove(&oldest).unwrap();
+            index.num_bytes -= entry.num_bytes;
+
+            let path = self.path(&oldest.0, oldest.1, entry.to_block);
+            if let Err(e) = remove_file(&path) {
+                log::warn!("failed to evict cached response: {:?}", e);
+            }
+        }
+    }
+}
+
+impl Client {
+    /// Serves responses from the cache when possible and stores the ones that are fetched.
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub(crate) async fn cached_finalized_query<Q: PortalQuery>(
+        &self,
+        query: &Q,
+        body: Bytes,
+    ) -> Result<Option<Bytes>> {
+        let cache = match self.cache.as_ref() {
+            Some(cache) => cache,
+            None => return self.finalized_query(body).await,
+        };
+
+        let key = ResponseCache::key(query).context("compute cache key")?;
+        let from_block = query.cursor();
+
+        match cache.get(&key, from_block) {
+            Ok(Some(data)) => {
+                telemetry::record_cache_hit(&self.url);
+                return Ok(Some(data));
+            }
+            Ok(None) => (),
+            Err(e) => log::warn!("failed to read cached response: {:?}", e),
+        }
+
+        let response = self.finalized_query(body).await?;
+        if let Some(data) = response.as_ref() {
+            if let Err(e) = cache.put(&key, from_block, data) {
+                log::warn!("failed to cache response: {:?}", e);
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+#[derive(Deserialize)]
+struct Line {
+    header: Header,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    number: u64,
+}
+
+fn last_block(data: &[u8]) -> Result<u64> {
+    let line = data
+        .split(|x| *x == b'\n')
+        .rfind(|line| !line.is_empty())
+        .context("response is empty")?;
+    let line: Line = serde_json::from_slice(line).context("parse block header")?;
+    Ok(line.header.number)
+}
+
+fn parse_range(name: &str) -> Option<(u64, u64)> {
+    let range = name.strip_suffix(EXTENSION)?.strip_suffix('.')?;
+    let (from, to) = range.split_once('-')?;
+    Some((from.parse().ok()?, to.parse().ok()?))
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evm;
+    use crate::test_util::test_dir;
+
+    fn response(from_block: u64, to_block: u64) -> Vec<u8> {
+        format!(
+            "{{\"header\":{{\"number\":{}}}}}\n{{\"header\":{{\"number\":{}}}}}\n",
+            from_block, to_block
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn key_ignores_from_block() {
+        let query = evm::Query {
+            from_block: 10,
+            ..Default::default()
+        };
+        let key = ResponseCache::key(&query).unwrap();
+        let other = evm::Query {
+            from_block: 20,
+            ..query.clone()
+        };
+        assert_eq!(ResponseCache::key(&other).unwrap(), key);
+        let other = evm::Query {
+            to_block: Some(30),
+            ..query
+        };
+        assert_ne!(ResponseCache::key(&other).unwrap(), key);
+    }
+
+    #[test]
+    fn stores_and_evicts_responses() {
+        let dir = test_dir("cache");
+        let cache = ResponseCache::open(&dir, CacheConfig::default()).unwrap();
+
+        cache.put("a", 0, &response(0, 9)).unwrap();
+        cache.put("a", 10, &response(10, 19)).unwrap();
+        assert!(dir.join("a").join("0-9.ndjson.zst").exists());
+        assert_eq!(cache.get("a", 0).unwrap().unwrap().as_ref(), response(0, 9));
+        assert!(cache.get("a", 5).unwrap().is_none());
+        assert!(cache.get("b", 0).unwrap().is_none());
+
+        // picks up the files that are already there and evicts the least recently used one
+        let size = cache.num_bytes();
+        let cache = ResponseCache::open(
+            &dir,
+            CacheConfig {
+                max_bytes: size - 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(cache.num_bytes() < size);
+        assert!(cache.get("a", 0).unwrap().is_none());
+        assert!(cache.get("a", 10).unwrap().is_some());
+
+        cache.clear();
+        assert_eq!(cache.num_bytes(), 0);
+        assert!(!dir.join("a").join("10-19.ndjson.zst").exists());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
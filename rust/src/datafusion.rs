@@ -0,0 +1,597 @@
+//! DataFusion tables that run their scans against a portal.
+//!
+//! Filters on the block number column and on the columns the portal can filter on are pushed
+//! down into the query, e.g. `address = X'..' AND block_number BETWEEN 100 AND 200` on the
+//! `logs` table. Binary columns are compared against binary literals like `X'ab01'`. Only the
+//! projected columns are selected in the query.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use ::datafusion::arrow::datatypes::{DataType, Schema, SchemaRef};
+use ::datafusion::catalog::{Session, TableProvider};
+use ::datafusion::common::{DataFusionError, ScalarValue};
+use ::datafusion::datasource::TableType;
+use ::datafusion::execution::{SendableRecordBatchStream, TaskContext};
+use ::datafusion::logical_expr::{
+    expr::InList, Between, BinaryExpr, Expr, Operator, TableProviderFilterPushDown,
+};
+use ::datafusion::physical_plan::empty::EmptyExec;
+use ::datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use ::datafusion::physical_plan::streaming::{PartitionStream, StreamingTableExec};
+use ::datafusion::physical_plan::ExecutionPlan;
+use ::datafusion::prelude::SessionContext;
+use anyhow::{Context, Result};
+use futures_lite::StreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::sink::ArrowTables;
+use crate::source::SourceQuery;
+use crate::stream::lazy_stream;
+use crate::{Client, StreamConfig};
+
+mod evm;
+mod svm;
+
+/// How the values of a column are written in portal queries.
+#[derive(Debug, Clone, Copy)]
+enum Encoding {
+    Hex,
+    Base58,
+    Utf8,
+    Bool,
+}
+
+/// A table of a dataset along with the columns that can be pushed down to the portal.
+struct TableSpec {
+    name: &'static str,
+    /// Key of the table in the `Fields` of the query.
+    fields_key: &'static str,
+    block_column: &'static str,
+    filter_columns: &'static [(&'static str, Encoding)],
+    schema: fn() -> Schema,
+}
+
+impl TableSpec {
+    fn encoding(&self, column: &str) -> Option<(&'static str, Encoding)> {
+        self.filter_columns
+            .iter()
+            .find(|(c, _)| *c == column)
+            .copied()
+    }
+}
+
+/// Filters that are pushed down into a query.
+#[derive(Debug, Default)]
+struct Filters {
+    from_block: u64,
+    to_block: Option<u64>,
+    /// Values that each filtered column can have, as they are written in queries.
+    values: BTreeMap<&'static str, Vec<String>>,
+}
+
+impl Filters {
+    fn from_exprs(spec: &TableSpec, exprs: &[Expr]) -> Self {
+        let mut filters = Self::default();
+        for expr in exprs {
+            match parse_filter(spec, expr) {
+                Some(Filter::Blocks(from, to)) => {
+                    filters.from_block = filters.from_block.max(from);
+                    filters.to_block = match (filters.to_block, to) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b),
+                    };
+                }
+                Some(Filter::Values(column, mut values)) => {
+                    values.sort();
+                    values.dedup();
+                    filters
+                        .values
+                        .entry(column)
+                        .and_modify(|v| v.retain(|x| values.contains(x)))
+                        .or_insert(values);
+                }
+                None => (),
+            }
+        }
+        filters
+    }
+
+    /// Returns true if no row can pass the filters.
+    fn is_empty(&self) -> bool {
+        self.to_block.is_some_and(|to| to < self.from_block)
+            || self.values.values().any(|v| v.is_empty())
+    }
+
+    fn get(&self, column: &str) -> Vec<String> {
+        self.values.get(column).cloned().unwrap_or_default()
+    }
+
+    /// Returns the value of a boolean column if only one value is allowed.
+    fn flag(&self, column: &str) -> Option<bool> {
+        match self.values.get(column).map(|v| v.as_slice()) {
+            Some([value]) => value.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+enum Filter {
+    /// Inclusive block range.
+    Blocks(u64, Option<u64>),
+    Values(&'static str, Vec<String>),
+}
+
+fn parse_filter(spec: &TableSpec, expr: &Expr) -> Option<Filter> {
+    match expr {
+        Expr::BinaryExpr(BinaryExpr { left, op, right }) => {
+            let (column, op, value) = match (left.as_ref(), right.as_ref()) {
+                (Expr::Column(c), Expr::Literal(v, _)) => (c, *op, v),
+                (Expr::Literal(v, _), Expr::Column(c)) => (c, op.swap()?, v),
+                _ => return None,
+            };
+            if column.name == spec.block_column {
+                let n = block_number(value)?;
+                return match op {
+                    Operator::Eq => Some(Filter::Blocks(n, Some(n))),
+                    Operator::Gt => Some(Filter::Blocks(n.checked_add(1)?, None)),
+                    Operator::GtEq => Some(Filter::Blocks(n, None)),
+                    // nothing is below block zero
+                    Operator::Lt => Some(match n.checked_sub(1) {
+                        Some(to) => Filter::Blocks(0, Some(to)),
+                        None => Filter::Blocks(1, Some(0)),
+                    }),
+                    Operator::LtEq => Some(Filter::Blocks(0, Some(n))),
+                    _ => None,
+                };
+            }
+            let (column, encoding) = spec.encoding(&column.name)?;
+            match op {
+                Operator::Eq => Some(Filter::Values(column, vec![encode(value, encoding)?])),
+                _ => None,
+            }
+        }
+        Expr::Between(Between {
+            expr,
+            negated: false,
+            low,
+            high,
+        }) => match (expr.as_ref(), low.as_ref(), high.as_ref()) {
+            (Expr::Column(c), Expr::Literal(low, _), Expr::Literal(high, _))
+                if c.name == spec.block_column =>
+            {
+                Some(Filter::Blocks(
+                    block_number(low)?,
+                    Some(block_number(high)?),
+                ))
+            }
+            _ => None,
+        },
+        Expr::InList(InList {
+            expr,
+            list,
+            negated: false,
+        }) => {
+            let Expr::Column(c) = expr.as_ref() else {
+                return None;
+            };
+            let (column, encoding) = spec.encoding(&c.name)?;
+            let values = list
+                .iter()
+                .map(|e| match e {
+                    Expr::Literal(v, _) => encode(v, encoding),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some(Filter::Values(column, values))
+        }
+        Expr::Column(c) => match spec.encoding(&c.name)? {
+            (column, Encoding::Bool) => Some(Filter::Values(column, vec!["true".to_owned()])),
+            _ => None,
+        },
+        Expr::Not(inner) => match inner.as_ref() {
+            Expr::Column(c) => match spec.encoding(&c.name)? {
+                (column, Encoding::Bool) => Some(Filter::Values(column, vec!["false".to_owned()])),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn block_number(value: &ScalarValue) -> Option<u64> {
+    match value.cast_to(&DataType::UInt64).ok()? {
+        ScalarValue::UInt64(n) => n,
+        _ => None,
+    }
+}
+
+fn encode(value: &ScalarValue, encoding: Encoding) -> Option<String> {
+    match (value, encoding) {
+        (
+            ScalarValue::Binary(Some(b))
+            | ScalarValue::LargeBinary(Some(b))
+            | ScalarValue::BinaryView(Some(b))
+            | ScalarValue::FixedSizeBinary(_, Some(b)),
+            Encoding::Hex,
+        ) => Some(format!("0x{}", faster_hex::hex_string(b))),
+        (
+            ScalarValue::Binary(Some(b))
+            | ScalarValue::LargeBinary(Some(b))
+            | ScalarValue::BinaryView(Some(b))
+            | ScalarValue::FixedSizeBinary(_, Some(b)),
+            Encoding::Base58,
+        ) => Some(bs58::encode(b).into_string()),
+        (
+            ScalarValue::Utf8(Some(s))
+            | ScalarValue::LargeUtf8(Some(s))
+            | ScalarValue::Utf8View(Some(s)),
+            Encoding::Utf8,
+        ) => Some(s.clone()),
+        (ScalarValue::Boolean(Some(b)), Encoding::Bool) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Selects the fields that fill in the columns.
+///
+/// `column_fields` maps columns to the `(table, field)` that fills them in when the names differ.
+/// Other columns that don't have a field of the same name select every field of the table.
+fn select_fields<F: Default + Serialize + DeserializeOwned>(
+    spec: &TableSpec,
+    columns: &[&str],
+    column_fields: &[(&str, &str, &str)],
+) -> Result<F> {
+    let mut fields = serde_json::to_value(F::default()).context("serialize fields")?;
+
+    for column in columns {
+        if *column == spec.block_column {
+            continue;
+        }
+        let mapped = column_fields
+            .iter()
+            .find(|(c, table, _)| c == column && (*table == "block" || *table == spec.fields_key));
+        if let Some((_, table, field)) = mapped {
+            fields[*table][*field] = true.into();
+            continue;
+        }
+
+        let field = snake_to_camel(column);
+        let table = fields[spec.fields_key]
+            .as_object_mut()
+            .context("get table fields")?;
+        match table.get_mut(&field) {
+            Some(value) => *value = true.into(),
+            None => table.values_mut().for_each(|v| *v = true.into()),
+        }
+    }
+
+    serde_json::from_value(fields).context("deserialize fields")
+}
+
+fn snake_to_camel(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            out.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Dataset {
+    Evm,
+    Svm,
+}
+
+/// A table of an EVM or SVM dataset that is read from a portal.
+///
+/// Scans end at the upper bound of the block number filter, or at the head of the portal if
+/// `StreamConfig::stop_on_head` is set. Scans without either never end.
+pub struct PortalTable {
+    client: Arc<Client>,
+    config: StreamConfig,
+    dataset: Dataset,
+    spec: &'static TableSpec,
+    schema: SchemaRef,
+}
+
+impl fmt::Debug for PortalTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PortalTable")
+            .field("dataset", &self.dataset)
+            .field("table", &self.spec.name)
+            .finish()
+    }
+}
+
+impl PortalTable {
+    /// Creates a table of an EVM dataset, like `logs` or `transactions`.
+    pub fn evm(client: Arc<Client>, table: &str, config: StreamConfig) -> Result<Self> {
+        Self::new(client, Dataset::Evm, evm::TABLES, table, config)
+    }
+
+    /// Creates a table of an SVM dataset, like `instructions` or `balances`.
+    pub fn svm(client: Arc<Client>, table: &str, config: StreamConfig) -> Result<Self> {
+        Self::new(client, Dataset::Svm, svm::TABLES, table, config)
+    }
+
+    fn new(
+        client: Arc<Client>,
+        dataset: Dataset,
+        tables: &'static [TableSpec],
+        table: &str,
+        config: StreamConfig,
+    ) -> Result<Self> {
+        let spec = tables
+            .iter()
+            .find(|spec| spec.name == table)
+            .with_context(|| format!("unknown table {}", table))?;
+
+        Ok(Self {
+            client,
+            config,
+            dataset,
+            spec,
+            schema: Arc::new((spec.schema)()),
+        })
+    }
+}
+
+/// Registers every table of an EVM dataset under its name.
+pub fn register_evm_tables(
+    ctx: &SessionContext,
+    client: Arc<Client>,
+    config: StreamConfig,
+) -> Result<()> {
+    for spec in evm::TABLES {
+        let table = PortalTable::evm(client.clone(), spec.name, config)?;
+        ctx.register_table(spec.name, Arc::new(table))
+            .with_context(|| format!("register {} table", spec.name))?;
+    }
+    Ok(())
+}
+
+/// Registers every table of an SVM dataset under its name.
+pub fn register_svm_tables(
+    ctx: &SessionContext,
+    client: Arc<Client>,
+    config: StreamConfig,
+) -> Result<()> {
+    for spec in svm::TABLES {
+        let table = PortalTable::svm(client.clone(), spec.name, config)?;
+        ctx.register_table(spec.name, Arc::new(table))
+            .with_context(|| format!("register {} table", spec.name))?;
+    }
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl TableProvider for PortalTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    fn supports_filters_pushdown(
+        &self,
+        filters: &[&Expr],
+    ) -> ::datafusion::common::Result<Vec<TableProviderFilterPushDown>> {
+        Ok(filters
+            .iter()
+            .map(|expr| match parse_filter(self.spec, expr) {
+                Some(_) => TableProviderFilterPushDown::Exact,
+                None => TableProviderFilterPushDown::Unsupported,
+            })
+            .collect())
+    }
+
+    async fn scan(
+        &self,
+        _state: &dyn Session,
+        projection: Option<&Vec<usize>>,
+        filters: &[Expr],
+        limit: Option<usize>,
+    ) -> ::datafusion::common::Result<Arc<dyn ExecutionPlan>> {
+        let filters = Filters::from_exprs(self.spec, filters);
+        if filters.is_empty() {
+            let schema = match projection {
+                Some(p) => Arc::new(self.schema.project(p)?),
+                None => self.schema.clone(),
+            };
+            return Ok(Arc::new(EmptyExec::new(schema)));
+        }
+
+        let columns = match projection {
+            Some(p) => p
+                .iter()
+                .map(|i| self.schema.field(*i).name().as_str())
+                .collect(),
+            None => self
+                .schema
+                .fields()
+                .iter()
+                .map(|f| f.name().as_str())
+                .collect::<Vec<_>>(),
+        };
+
+        let partition: Arc<dyn PartitionStream> = match self.dataset {
+            Dataset::Evm => self.partition(evm::query(self.spec, &filters, &columns))?,
+            Dataset::Svm => self.partition(svm::query(self.spec, &filters, &columns))?,
+        };
+        let infinite = filters.to_block.is_none() && !self.config.stop_on_head;
+        let exec = StreamingTableExec::try_new(
+            self.schema.clone(),
+            vec![partition],
+            projection,
+            [],
+            infinite,
+            limit,
+        )?;
+
+        Ok(Arc::new(exec))
+    }
+}
+
+impl PortalTable {
+    fn partition<Q: SourceQuery<Client>>(
+        &self,
+        query: Result<Q>,
+    ) -> ::datafusion::common::Result<Arc<dyn PartitionStream>>
+    where
+        Q::Response: ArrowTables,
+    {
+        let query = query.map_err(|e| DataFusionError::External(e.into()))?;
+        let index = Q::Response::TABLE_NAMES
+            .iter()
+            .position(|t| *t == self.spec.name)
+            .ok_or_else(|| DataFusionError::Internal(format!("no {} table", self.spec.name)))?;
+
+        Ok(Arc::new(QueryPartition {
+            client: self.client.clone(),
+            query,
+            config: self.config,
+            index,
+            schema: self.schema.clone(),
+        }))
+    }
+}
+
+struct QueryPartition<Q> {
+    client: Arc<Client>,
+    query: Q,
+    config: StreamConfig,
+    index: usize,
+    schema: SchemaRef,
+}
+
+impl<Q> fmt::Debug for QueryPartition<Q> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueryPartition")
+            .field("index", &self.index)
+            .finish()
+    }
+}
+
+impl<Q: SourceQuery<Client>> PartitionStream for QueryPartition<Q>
+where
+    Q::Response: ArrowTables,
+{
+    fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    fn execute(&self, _ctx: Arc<TaskContext>) -> SendableRecordBatchStream {
+        let client = self.client.clone();
+        let query = self.query.clone();
+        let config = self.config;
+        let index = self.index;
+
+        let batches = async_stream::stream! {
+            let items = lazy_stream(&*client, query, config);
+            futures_lite::pin!(items);
+
+            while let Some(item) = items.next().await {
+                match item {
+                    Ok(item) => yield Ok(item.data.tables()[index].1.clone()),
+                    Err(e) => {
+                        yield Err(DataFusionError::External(e.into()));
+                        return;
+                    }
+                }
+            }
+        };
+
+        Box::pin(RecordBatchStreamAdapter::new(self.schema.clone(), batches))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::datafusion::logical_expr::{col, lit};
+
+    use super::*;
+    use crate::ClientConfig;
+
+    fn table(dataset: Dataset, name: &str) -> PortalTable {
+        let client = Client::new("http://localhost".parse().unwrap(), ClientConfig::default());
+        let client = Arc::new(client);
+        match dataset {
+            Dataset::Evm => PortalTable::evm(client, name, StreamConfig::default()).unwrap(),
+            Dataset::Svm => PortalTable::svm(client, name, StreamConfig::default()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn pushes_down_supported_filters() {
+        let logs = table(Dataset::Evm, "logs");
+        let address = ScalarValue::Binary(Some(vec![0xab; 20]));
+        let exprs = [
+            col("address").eq(lit(address)),
+            col("block_number").between(lit(10u64), lit(20u64)),
+            col("block_number").gt(lit(12i64)),
+            col("data").eq(lit(ScalarValue::Binary(Some(vec![1])))),
+        ];
+        let pushdown = logs
+            .supports_filters_pushdown(&exprs.iter().collect::<Vec<_>>())
+            .unwrap();
+        assert_eq!(
+            pushdown,
+            [
+                TableProviderFilterPushDown::Exact,
+                TableProviderFilterPushDown::Exact,
+                TableProviderFilterPushDown::Exact,
+                TableProviderFilterPushDown::Unsupported,
+            ]
+        );
+
+        let filters = Filters::from_exprs(logs.spec, &exprs);
+        assert_eq!((filters.from_block, filters.to_block), (13, Some(20)));
+        let query = evm::query(logs.spec, &filters, &["address", "topic0"]).unwrap();
+        assert_eq!(query.logs.len(), 1);
+        assert_eq!(query.logs[0].address, [format!("0x{}", "ab".repeat(20))]);
+        assert!(query.fields.log.address && query.fields.log.topics);
+        assert!(!query.fields.log.data);
+    }
+
+    #[test]
+    fn contradicting_filters_match_nothing() {
+        let instructions = table(Dataset::Svm, "instructions");
+        let a = ScalarValue::Binary(Some(vec![1; 32]));
+        let b = ScalarValue::Binary(Some(vec![2; 32]));
+        let exprs = [
+            col("program_id").in_list(vec![lit(a.clone()), lit(b)], false),
+            col("program_id").eq(lit(a)),
+            col("is_committed"),
+        ];
+        let filters = Filters::from_exprs(instructions.spec, &exprs);
+        assert!(!filters.is_empty());
+        assert_eq!(filters.flag("is_committed"), Some(true));
+        let query = svm::query(instructions.spec, &filters, &["program_id"]).unwrap();
+        assert_eq!(
+            query.instructions[0].program_id,
+            [bs58::encode([1; 32]).into_string()]
+        );
+        assert_eq!(query.instructions[0].is_committed, Some(true));
+
+        let exprs = [col("block_slot").lt(lit(0u64))];
+        assert!(Filters::from_exprs(instructions.spec, &exprs).is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+use anyhow::Result;
+
+use super::{select_fields, Encoding, Filters, TableSpec};
+use crate::evm::{LogRequest, Query, TraceRequest, TransactionRequest};
+
+pub(super) static TABLES: &[TableSpec] = &[
+    TableSpec {
+        name: "blocks",
+        fields_key: "block",
+        block_column: "number",
+        filter_columns: &[],
+        schema: cherry_evm_schema::blocks_schema,
+    },
+    TableSpec {
+        name: "transactions",
+        fields_key: "transaction",
+        block_column: "block_number",
+        filter_columns: &[
+            ("from", Encoding::Hex),
+            ("to", Encoding::Hex),
+            ("sighash", Encoding::Hex),
+        ],
+        schema: cherry_evm_schema::transactions_schema,
+    },
+    TableSpec {
+        name: "logs",
+        fields_key: "log",
+        block_column: "block_number",
+        filter_columns: &[
+            ("address", Encoding::Hex),
+            ("topic0", Encoding::Hex),
+            ("topic1", Encoding::Hex),
+            ("topic2", Encoding::Hex),
+            ("topic3", Encoding::Hex),
+        ],
+        schema: cherry_evm_schema::logs_schema,
+    },
+    TableSpec {
+        name: "traces",
+        fields_key: "trace",
+        block_column: "block_number",
+        filter_columns: &[("type", Encoding::Utf8)],
+        schema: cherry_evm_schema::traces_schema,
+    },
+];
+
+/// Columns that are filled in by a field of another name.
+const COLUMN_FIELDS: &[(&str, &str, &str)] = &[
+    ("block_hash", "block", "hash"),
+    ("topic0", "log", "topics"),
+    ("topic1", "log", "topics"),
+    ("topic2", "log", "topics"),
+    ("topic3", "log", "topics"),
+];
+
+pub(super) fn query(spec: &TableSpec, filters: &Filters, columns: &[&str]) -> Result<Query> {
+    let mut query = Query {
+        from_block: filters.from_block,
+        to_block: filters.to_block,
+        fields: select_fields(spec, columns, COLUMN_FIELDS)?,
+        ..Default::default()
+    };
+
+    match spec.name {
+        "transactions" => query.transactions.push(TransactionRequest {
+            from: filters.get("from"),
+            to: filters.get("to"),
+            sighash: filters.get("sighash"),
+            ..Default::default()
+        }),
+        "logs" => query.logs.push(LogRequest {
+            address: filters.get("address"),
+            topic0: filters.get("topic0"),
+            topic1: filters.get("topic1"),
+            topic2: filters.get("topic2"),
+            topic3: filters.get("topic3"),
+            ..Default::default()
+        }),
+        "traces" => query.traces.push(TraceRequest {
+            type_: filters.get("type"),
+            ..Default::default()
+        }),
+        _ => query.include_all_blocks = true,
+    }
+
+    Ok(query)
+}
@@ -0,0 +1,165 @@
+use anyhow::Result;
+
+use super::{select_fields, Encoding, Filters, TableSpec};
+use crate::svm::{
+    BalanceRequest, InstructionRequest, LogRequest, Query, RewardRequest, TokenBalanceRequest,
+    TransactionRequest,
+};
+
+pub(super) static TABLES: &[TableSpec] = &[
+    TableSpec {
+        name: "blocks",
+        fields_key: "block",
+        block_column: "slot",
+        filter_columns: &[],
+        schema: cherry_svm_schema::blocks_schema,
+    },
+    TableSpec {
+        name: "transactions",
+        fields_key: "transaction",
+        block_column: "block_slot",
+        filter_columns: &[("fee_payer", Encoding::Base58)],
+        schema: cherry_svm_schema::transactions_schema,
+    },
+    TableSpec {
+        name: "instructions",
+        fields_key: "instruction",
+        block_column: "block_slot",
+        filter_columns: &[
+            ("program_id", Encoding::Base58),
+            ("d1", Encoding::Hex),
+            ("d2", Encoding::Hex),
+            ("d4", Encoding::Hex),
+            ("d8", Encoding::Hex),
+            ("a0", Encoding::Base58),
+            ("a1", Encoding::Base58),
+            ("a2", Encoding::Base58),
+            ("a3", Encoding::Base58),
+            ("a4", Encoding::Base58),
+            ("a5", Encoding::Base58),
+            ("a6", Encoding::Base58),
+            ("a7", Encoding::Base58),
+            ("a8", Encoding::Base58),
+            ("a9", Encoding::Base58),
+            ("is_committed", Encoding::Bool),
+        ],
+        schema: cherry_svm_schema::instructions_schema,
+    },
+    TableSpec {
+        name: "logs",
+        fields_key: "log",
+        block_column: "block_slot",
+        filter_columns: &[("program_id", Encoding::Base58), ("kind", Encoding::Utf8)],
+        schema: cherry_svm_schema::logs_schema,
+    },
+    TableSpec {
+        name: "balances",
+        fields_key: "balance",
+        block_column: "block_slot",
+        filter_columns: &[("account", Encoding::Base58)],
+        schema: cherry_svm_schema::balances_schema,
+    },
+    TableSpec {
+        name: "token_balances",
+        fields_key: "tokenBalance",
+        block_column: "block_slot",
+        filter_columns: &[
+            ("account", Encoding::Base58),
+            ("pre_program_id", Encoding::Base58),
+            ("post_program_id", Encoding::Base58),
+            ("pre_mint", Encoding::Base58),
+            ("post_mint", Encoding::Base58),
+            ("pre_owner", Encoding::Base58),
+            ("post_owner", Encoding::Base58),
+        ],
+        schema: cherry_svm_schema::token_balances_schema,
+    },
+    TableSpec {
+        name: "rewards",
+        fields_key: "reward",
+        block_column: "block_slot",
+        filter_columns: &[
+            ("pubkey", Encoding::Base58),
+            ("reward_type", Encoding::Utf8),
+        ],
+        schema: cherry_svm_schema::rewards_schema,
+    },
+];
+
+/// Columns that are filled in by a field of another name.
+const COLUMN_FIELDS: &[(&str, &str, &str)] = &[
+    ("block_hash", "block", "hash"),
+    ("parent_slot", "block", "parentNumber"),
+    ("a0", "instruction", "accounts"),
+    ("a1", "instruction", "accounts"),
+    ("a2", "instruction", "accounts"),
+    ("a3", "instruction", "accounts"),
+    ("a4", "instruction", "accounts"),
+    ("a5", "instruction", "accounts"),
+    ("a6", "instruction", "accounts"),
+    ("a7", "instruction", "accounts"),
+    ("a8", "instruction", "accounts"),
+    ("a9", "instruction", "accounts"),
+    ("rest_of_accounts", "instruction", "accounts"),
+];
+
+pub(super) fn query(spec: &TableSpec, filters: &Filters, columns: &[&str]) -> Result<Query> {
+    let mut query = Query {
+        from_block: filters.from_block,
+        to_block: filters.to_block,
+        fields: select_fields(spec, columns, COLUMN_FIELDS)?,
+        ..Default::default()
+    };
+
+    match spec.name {
+        "transactions" => query.transactions.push(TransactionRequest {
+            fee_payer: filters.get("fee_payer"),
+            ..Default::default()
+        }),
+        "instructions" => query.instructions.push(InstructionRequest {
+            program_id: filters.get("program_id"),
+            d1: filters.get("d1"),
+            d2: filters.get("d2"),
+            d4: filters.get("d4"),
+            d8: filters.get("d8"),
+            a0: filters.get("a0"),
+            a1: filters.get("a1"),
+            a2: filters.get("a2"),
+            a3: filters.get("a3"),
+            a4: filters.get("a4"),
+            a5: filters.get("a5"),
+            a6: filters.get("a6"),
+            a7: filters.get("a7"),
+            a8: filters.get("a8"),
+            a9: filters.get("a9"),
+            is_committed: filters.flag("is_committed"),
+            ..Default::default()
+        }),
+        "logs" => query.logs.push(LogRequest {
+            program_id: filters.get("program_id"),
+            kind: filters.get("kind"),
+            ..Default::default()
+        }),
+        "balances" => query.balances.push(BalanceRequest {
+            account: filters.get("account"),
+            ..Default::default()
+        }),
+        "token_balances" => query.token_balances.push(TokenBalanceRequest {
+            account: filters.get("account"),
+            pre_program_id: filters.get("pre_program_id"),
+            post_program_id: filters.get("post_program_id"),
+            pre_mint: filters.get("pre_mint"),
+            post_mint: filters.get("post_mint"),
+            pre_owner: filters.get("pre_owner"),
+            post_owner: filters.get("post_owner"),
+            ..Default::default()
+        }),
+        "rewards" => query.rewards.push(RewardRequest {
+            pubkey: filters.get("pubkey"),
+            reward_type: filters.get("reward_type"),
+        }),
+        _ => query.include_all_blocks = true,
+    }
+
+    Ok(query)
+}
@@ -1,11 +1,21 @@
 use anyhow::{Context, Result};
-use arrow::array::UInt64Array;
+use arrow::array::{Decimal256Array, UInt64Array};
 use arrow::{datatypes::i256, record_batch::RecordBatch};
 use cherry_evm_schema::{BlocksBuilder, LogsBuilder, TracesBuilder, TransactionsBuilder};
 use serde::{Deserialize, Serialize};
 use simd_json::base::ValueAsScalar;
 use simd_json::derived::TypedScalarValue;
 
+use crate::query::slice_blocks;
+use crate::split::{merge_table, pack, split_request};
+
+mod builder;
+mod mux;
+mod rows;
+
+pub use builder::QueryBuilder;
+pub use rows::{Block, BlockData, Log, Trace, TraceAction, TraceResult, Transaction};
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Query {
@@ -21,18 +31,13 @@ pub struct Query {
     pub state_diffs: Vec<StateDiffRequest>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum QueryType {
+    #[default]
     Evm,
 }
 
-impl Default for QueryType {
-    fn default() -> Self {
-        Self::Evm
-    }
-}
-
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LogRequest {
@@ -49,6 +54,7 @@ pub struct LogRequest {
     pub transaction: bool,
     pub transaction_traces: bool,
     pub transaction_logs: bool,
+    pub transaction_state_diffs: bool,
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -60,6 +66,12 @@ pub struct TransactionRequest {
     pub to: Vec<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub sighash: Vec<String>,
+    /// Only match transactions with a nonce at or above this.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_nonce: Option<u64>,
+    /// Only match transactions with a nonce at or below this.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_nonce: Option<u64>,
     pub logs: bool,
     pub traces: bool,
     pub state_diffs: bool,
@@ -83,8 +95,11 @@ pub struct TraceRequest {
     pub suicide_refund_address: Vec<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub reward_author: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub create_result_address: Vec<String>,
     pub transaction: bool,
     pub transaction_logs: bool,
+    pub transaction_state_diffs: bool,
     pub subtraces: bool,
     pub parents: bool,
 }
@@ -99,6 +114,118 @@ pub struct StateDiffRequest {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub kind: Vec<String>,
     pub transaction: bool,
+    pub transaction_logs: bool,
+    pub transaction_traces: bool,
+    pub transaction_state_diffs: bool,
+}
+
+impl Query {
+    /// Splits the query into queries over the same block range that have at most `max_values`
+    /// filter values each, so they fit into a portal request.
+    ///
+    /// The key fields of every table are selected in the parts so their responses can be merged
+    /// with `ArrowResponse::merge`.
+    pub(crate) fn split(&self, max_values: usize) -> Vec<Query> {
+        let mut requests = Vec::new();
+        for req in self.logs.iter() {
+            let parts = split_request(req.clone(), max_values, LogRequest::filters);
+            requests.extend(parts.into_iter().map(|(req, n)| (Request::Log(req), n)));
+        }
+        for req in self.transactions.iter() {
+            let parts = split_request(req.clone(), max_values, TransactionRequest::filters);
+            requests.extend(
+                parts
+                    .into_iter()
+                    .map(|(req, n)| (Request::Transaction(req), n)),
+            );
+        }
+        for req in self.traces.iter() {
+            let parts = split_request(req.clone(), max_values, TraceRequest::filters);
+            requests.extend(parts.into_iter().map(|(req, n)| (Request::Trace(req), n)));
+        }
+        for req in self.state_diffs.iter() {
+            let parts = split_request(req.clone(), max_values, StateDiffRequest::filters);
+            requests.extend(
+                parts
+                    .into_iter()
+                    .map(|(req, n)| (Request::StateDiff(req), n)),
+            );
+        }
+
+        let groups = pack(requests, max_values);
+        if groups.len() <= 1 {
+            return vec![self.clone()];
+        }
+
+        groups
+            .into_iter()
+            .map(|group| {
+                let mut query = Query {
+                    logs: Vec::new(),
+                    transactions: Vec::new(),
+                    traces: Vec::new(),
+                    state_diffs: Vec::new(),
+                    ..self.clone()
+                };
+                builder::select_join_keys(&mut query.fields);
+                for req in group {
+                    match req {
+                        Request::Log(req) => query.logs.push(req),
+                        Request::Transaction(req) => query.transactions.push(req),
+                        Request::Trace(req) => query.traces.push(req),
+                        Request::StateDiff(req) => query.state_diffs.push(req),
+                    }
+                }
+                query
+            })
+            .collect()
+    }
+}
+
+enum Request {
+    Log(LogRequest),
+    Transaction(TransactionRequest),
+    Trace(TraceRequest),
+    StateDiff(StateDiffRequest),
+}
+
+impl LogRequest {
+    fn filters(&mut self) -> Vec<&mut Vec<String>> {
+        vec![
+            &mut self.address,
+            &mut self.topic0,
+            &mut self.topic1,
+            &mut self.topic2,
+            &mut self.topic3,
+        ]
+    }
+}
+
+impl TransactionRequest {
+    fn filters(&mut self) -> Vec<&mut Vec<String>> {
+        vec![&mut self.from, &mut self.to, &mut self.sighash]
+    }
+}
+
+impl TraceRequest {
+    fn filters(&mut self) -> Vec<&mut Vec<String>> {
+        vec![
+            &mut self.type_,
+            &mut self.create_from,
+            &mut self.call_from,
+            &mut self.call_to,
+            &mut self.call_sighash,
+            &mut self.suicide_refund_address,
+            &mut self.reward_author,
+            &mut self.create_result_address,
+        ]
+    }
+}
+
+impl StateDiffRequest {
+    fn filters(&mut self) -> Vec<&mut Vec<String>> {
+        vec![&mut self.address, &mut self.key, &mut self.kind]
+    }
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -372,6 +499,93 @@ pub struct ArrowResponse {
 }
 
 impl ArrowResponse {
+    /// Returns every table along with its name.
+    pub fn tables(&self) -> [(&'static str, &RecordBatch); 4] {
+        [
+            ("blocks", &self.blocks),
+            ("transactions", &self.transactions),
+            ("logs", &self.logs),
+            ("traces", &self.traces),
+        ]
+    }
+
+    /// Returns the number and timestamp of each block in the response.
+    ///
+    /// Requires `BlockFields::timestamp` to be selected.
+    pub fn block_timestamps(&self) -> Result<Vec<(u64, u64)>> {
+        let numbers = self
+            .blocks
+            .column_by_name("number")
+            .context("get number col")?
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .context("get number col as u64")?;
+        let timestamps = self
+            .blocks
+            .column_by_name("timestamp")
+            .context("get timestamp col")?
+            .as_any()
+            .downcast_ref::<Decimal256Array>()
+            .context("get timestamp col as decimal256")?;
+
+        numbers
+            .iter()
+            .zip(timestamps.iter())
+            .map(|(n, t)| {
+                let n = n.context("null block number")?;
+                let t = t.context("null block timestamp")?;
+                let t = t
+                    .to_i128()
+                    .and_then(|t| u64::try_from(t).ok())
+                    .context("timestamp out of range")?;
+                Ok((n, t))
+            })
+            .collect()
+    }
+
+    /// Returns a copy of the response that only contains data up to and including `to_block`.
+    pub fn truncate(&self, to_block: u64) -> Result<Self> {
+        self.slice(0, to_block)
+    }
+
+    /// Returns a copy of the response that only contains data of the blocks in
+    /// `from_block..=to_block`.
+    pub fn slice(&self, from_block: u64, to_block: u64) -> Result<Self> {
+        let slice =
+            |batch: &RecordBatch, column: &str| slice_blocks(batch, column, from_block, to_block);
+        Ok(Self {
+            blocks: slice(&self.blocks, "number").context("blocks")?,
+            transactions: slice(&self.transactions, "block_number").context("transactions")?,
+            logs: slice(&self.logs, "block_number").context("logs")?,
+            traces: slice(&self.traces, "block_number").context("traces")?,
+        })
+    }
+
+    /// Merges the responses of queries that were split from the same query.
+    ///
+    /// The responses have to cover the same block range, use `truncate` to cut them to the same
+    /// block first. Rows are sorted in block order and rows that appear in more than one response
+    /// are only kept once.
+    pub(crate) fn merge(responses: &[Self]) -> Result<Self> {
+        Ok(Self {
+            blocks: merge_table(responses, |r| &r.blocks, &["number"]).context("blocks")?,
+            transactions: merge_table(
+                responses,
+                |r| &r.transactions,
+                &["block_number", "transaction_index"],
+            )
+            .context("transactions")?,
+            logs: merge_table(responses, |r| &r.logs, &["block_number", "log_index"])
+                .context("logs")?,
+            traces: merge_table(
+                responses,
+                |r| &r.traces,
+                &["block_number", "transaction_position", "trace_address"],
+            )
+            .context("traces")?,
+        })
+    }
+
     pub fn next_block(&self) -> Result<u64> {
         let numbers = self
             .blocks
@@ -388,8 +602,28 @@ impl ArrowResponse {
     }
 }
 
+pub(crate) fn parse_response(data: &[u8]) -> Result<ArrowResponse> {
+    let mut parser = ArrowResponseParser::default();
+
+    let lines = data.split(|x| *x == b'\n');
+    let mut scratch = Vec::new();
+
+    for line in lines {
+        if line.is_empty() {
+            continue;
+        }
+
+        scratch.extend_from_slice(line);
+        let tape = simd_json::to_tape(&mut scratch).context("json to tape")?;
+        parser.parse_tape(&tape).context("parse tape")?;
+        scratch.clear();
+    }
+
+    Ok(parser.finish())
+}
+
 #[derive(Default)]
-pub(crate) struct ArrowResponseParser {
+struct ArrowResponseParser {
     blocks: BlocksBuilder,
     transactions: TransactionsBuilder,
     logs: LogsBuilder,
@@ -397,7 +631,7 @@ pub(crate) struct ArrowResponseParser {
 }
 
 impl ArrowResponseParser {
-    pub(crate) fn parse_tape(&mut self, tape: &simd_json::tape::Tape<'_>) -> Result<()> {
+    fn parse_tape(&mut self, tape: &simd_json::tape::Tape<'_>) -> Result<()> {
         let obj = tape.as_value().as_object().context("tape as object")?;
         let header = obj.get("header").context("get header")?;
 
@@ -764,7 +998,7 @@ impl ArrowResponseParser {
         Ok(BlockInfo { number, hash })
     }
 
-    pub(crate) fn finish(self) -> ArrowResponse {
+    fn finish(self) -> ArrowResponse {
         ArrowResponse {
             blocks: self.blocks.finish(),
             transactions: self.transactions.finish(),
@@ -928,3 +1162,160 @@ fn decode_hex(hex: &str) -> Result<Vec<u8>> {
 
     Ok(dst)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_json<T: Serialize>(value: &T) -> simd_json::OwnedValue {
+        simd_json::to_owned_value(&mut simd_json::to_vec(value).unwrap()).unwrap()
+    }
+
+    fn expected(json: &str) -> simd_json::OwnedValue {
+        simd_json::to_owned_value(&mut json.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn log_request_json() {
+        let req = LogRequest {
+            transaction_state_diffs: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            to_json(&req),
+            expected(
+                r#"{"transaction":false,"transactionTraces":false,"transactionLogs":false,
+                "transactionStateDiffs":true}"#
+            )
+        );
+    }
+
+    #[test]
+    fn transaction_request_json() {
+        let req = TransactionRequest {
+            first_nonce: Some(1),
+            last_nonce: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            to_json(&req),
+            expected(
+                r#"{"firstNonce":1,"lastNonce":5,"logs":false,"traces":false,"stateDiffs":false}"#
+            )
+        );
+
+        // nonce bounds are left out when they are not set
+        assert_eq!(
+            to_json(&TransactionRequest::default()),
+            expected(r#"{"logs":false,"traces":false,"stateDiffs":false}"#)
+        );
+    }
+
+    #[test]
+    fn trace_request_json() {
+        let req = TraceRequest {
+            create_result_address: vec!["0x01".to_owned()],
+            transaction_state_diffs: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            to_json(&req),
+            expected(
+                r#"{"createResultAddress":["0x01"],"transaction":false,"transactionLogs":false,
+                "transactionStateDiffs":true,"subtraces":false,"parents":false}"#
+            )
+        );
+    }
+
+    #[test]
+    fn state_diff_request_json() {
+        let req = StateDiffRequest {
+            transaction_logs: true,
+            transaction_traces: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            to_json(&req),
+            expected(
+                r#"{"transaction":false,"transactionLogs":true,"transactionTraces":true,
+                "transactionStateDiffs":false}"#
+            )
+        );
+    }
+
+    #[test]
+    fn split_large_filter_lists() {
+        let addresses = (0..2500)
+            .map(|i| format!("0x{:040x}", i))
+            .collect::<Vec<_>>();
+        let query = Query {
+            from_block: 10,
+            logs: vec![LogRequest {
+                address: addresses.clone(),
+                transaction: true,
+                ..Default::default()
+            }],
+            transactions: vec![TransactionRequest {
+                sighash: vec!["0xa9059cbb".to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(query.split(5000).len(), 1);
+
+        let parts = query.split(1000);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0].logs[0].address, addresses[..1000]);
+        assert_eq!(parts[2].logs[0].address, addresses[2000..]);
+        // the last part has room for the transaction request
+        assert_eq!(parts[2].transactions.len(), 1);
+        for part in parts.iter() {
+            let mut part = part.clone();
+            let num_values = part
+                .logs
+                .iter_mut()
+                .flat_map(LogRequest::filters)
+                .chain(
+                    part.transactions
+                        .iter_mut()
+                        .flat_map(TransactionRequest::filters),
+                )
+                .map(|list| list.len())
+                .sum::<usize>();
+            assert!(num_values <= 1000);
+            assert_eq!(part.from_block, 10);
+            assert!(part.logs[0].transaction);
+            assert!(part.fields.log.log_index);
+            assert!(part.fields.block.number);
+        }
+    }
+
+    #[test]
+    fn merge_split_responses() {
+        let a = parse_response(
+            br#"{"header":{"number":1},"logs":[{"logIndex":0,"transactionIndex":0}]}
+{"header":{"number":2},"logs":[{"logIndex":3,"transactionIndex":1}]}"#,
+        )
+        .unwrap();
+        let b = parse_response(
+            br#"{"header":{"number":1},"logs":[{"logIndex":1,"transactionIndex":0}]}
+{"header":{"number":2},"logs":[{"logIndex":3,"transactionIndex":1}]}
+{"header":{"number":3}}"#,
+        )
+        .unwrap();
+
+        let merged = ArrowResponse::merge(&[a, b.truncate(2).unwrap()]).unwrap();
+        assert_eq!(merged.blocks.num_rows(), 2);
+        assert_eq!(merged.next_block().unwrap(), 3);
+
+        let log_index = merged
+            .logs
+            .column_by_name("log_index")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(log_index.values(), &[0, 1, 3]);
+    }
+}
@@ -0,0 +1,286 @@
+use super::{Fields, LogRequest, Query, StateDiffRequest, TraceRequest, TransactionRequest};
+use crate::validate::{check_block_range, check_one_of, normalize_hex, ValidationError};
+
+const ADDRESS_LEN: usize = 20;
+const HASH_LEN: usize = 32;
+const SIGHASH_LEN: usize = 4;
+
+const TRACE_TYPES: &[&str] = &["create", "call", "suicide", "reward"];
+const STATE_DIFF_KINDS: &[&str] = &["=", "+", "*", "-"];
+
+/// Builds an `evm::Query`, validating and normalising the filters before anything is sent.
+///
+/// `build` also selects the fields that the requests filter on or need to join
+/// the returned items to each other.
+#[derive(Debug, Clone, Default)]
+pub struct QueryBuilder {
+    query: Query,
+}
+
+impl Query {
+    pub fn builder() -> QueryBuilder {
+        QueryBuilder::default()
+    }
+}
+
+impl QueryBuilder {
+    pub fn from_block(mut self, from_block: u64) -> Self {
+        self.query.from_block = from_block;
+        self
+    }
+
+    pub fn to_block(mut self, to_block: u64) -> Self {
+        self.query.to_block = Some(to_block);
+        self
+    }
+
+    pub fn include_all_blocks(mut self, include_all_blocks: bool) -> Self {
+        self.query.include_all_blocks = include_all_blocks;
+        self
+    }
+
+    /// Sets the selected fields, `build` adds to these.
+    pub fn fields(mut self, fields: Fields) -> Self {
+        self.query.fields = fields;
+        self
+    }
+
+    pub fn log(mut self, req: LogRequest) -> Self {
+        self.query.logs.push(req);
+        self
+    }
+
+    pub fn transaction(mut self, req: TransactionRequest) -> Self {
+        self.query.transactions.push(req);
+        self
+    }
+
+    pub fn trace(mut self, req: TraceRequest) -> Self {
+        self.query.traces.push(req);
+        self
+    }
+
+    pub fn state_diff(mut self, req: StateDiffRequest) -> Self {
+        self.query.state_diffs.push(req);
+        self
+    }
+
+    pub fn build(self) -> Result<Query, ValidationError> {
+        let mut query = self.query;
+
+        check_block_range(query.from_block, query.to_block)?;
+
+        for (i, req) in query.logs.iter_mut().enumerate() {
+            let path = |name: &str| format!("logs[{}].{}", i, name);
+            normalize_hex(&path("address"), &mut req.address, ADDRESS_LEN)?;
+            normalize_hex(&path("topic0"), &mut req.topic0, HASH_LEN)?;
+            normalize_hex(&path("topic1"), &mut req.topic1, HASH_LEN)?;
+            normalize_hex(&path("topic2"), &mut req.topic2, HASH_LEN)?;
+            normalize_hex(&path("topic3"), &mut req.topic3, HASH_LEN)?;
+        }
+
+        for (i, req) in query.transactions.iter_mut().enumerate() {
+            let path = |name: &str| format!("transactions[{}].{}", i, name);
+            normalize_hex(&path("from"), &mut req.from, ADDRESS_LEN)?;
+            normalize_hex(&path("to"), &mut req.to, ADDRESS_LEN)?;
+            normalize_hex(&path("sighash"), &mut req.sighash, SIGHASH_LEN)?;
+        }
+
+        for (i, req) in query.traces.iter_mut().enumerate() {
+            let path = |name: &str| format!("traces[{}].{}", i, name);
+            check_one_of(&path("type"), &req.type_, TRACE_TYPES)?;
+            normalize_hex(&path("create_from"), &mut req.create_from, ADDRESS_LEN)?;
+            normalize_hex(&path("call_from"), &mut req.call_from, ADDRESS_LEN)?;
+            normalize_hex(&path("call_to"), &mut req.call_to, ADDRESS_LEN)?;
+            normalize_hex(&path("call_sighash"), &mut req.call_sighash, SIGHASH_LEN)?;
+            normalize_hex(
+                &path("suicide_refund_address"),
+                &mut req.suicide_refund_address,
+                ADDRESS_LEN,
+            )?;
+            normalize_hex(&path("reward_author"), &mut req.reward_author, ADDRESS_LEN)?;
+            normalize_hex(
+                &path("create_result_address"),
+                &mut req.create_result_address,
+                ADDRESS_LEN,
+            )?;
+        }
+
+        for (i, req) in query.state_diffs.iter_mut().enumerate() {
+            let path = |name: &str| format!("state_diffs[{}].{}", i, name);
+            normalize_hex(&path("address"), &mut req.address, ADDRESS_LEN)?;
+            normalize_hex(&path("key"), &mut req.key, HASH_LEN)?;
+            check_one_of(&path("kind"), &req.kind, STATE_DIFF_KINDS)?;
+        }
+
+        select_required_fields(&mut query);
+
+        Ok(query)
+    }
+}
+
+/// Selects the fields that the requests filter on and the keys of the related items.
+pub(super) fn select_required_fields(query: &mut Query) {
+    let fields = &mut query.fields;
+
+    fields.block.number = true;
+
+    for req in query.logs.iter() {
+        select_log_keys(fields);
+        fields.log.address |= !req.address.is_empty();
+        fields.log.topics |= !req.topic0.is_empty()
+            || !req.topic1.is_empty()
+            || !req.topic2.is_empty()
+            || !req.topic3.is_empty();
+        if req.transaction {
+            select_transaction_keys(fields);
+        }
+        if req.transaction_logs {
+            select_log_keys(fields);
+        }
+        if req.transaction_traces {
+            select_trace_keys(fields);
+        }
+    }
+
+    for req in query.transactions.iter() {
+        select_transaction_keys(fields);
+        fields.transaction.from |= !req.from.is_empty();
+        fields.transaction.to |= !req.to.is_empty();
+        fields.transaction.sighash |= !req.sighash.is_empty();
+        fields.transaction.nonce |= req.first_nonce.is_some() || req.last_nonce.is_some();
+        if req.logs {
+            select_log_keys(fields);
+        }
+        if req.traces {
+            select_trace_keys(fields);
+        }
+    }
+
+    for req in query.traces.iter() {
+        select_trace_keys(fields);
+        fields.trace.type_ |= !req.type_.is_empty();
+        fields.trace.create_from |= !req.create_from.is_empty();
+        fields.trace.call_from |= !req.call_from.is_empty();
+        fields.trace.call_to |= !req.call_to.is_empty();
+        fields.trace.call_sighash |= !req.call_sighash.is_empty();
+        fields.trace.suicide_refund_address |= !req.suicide_refund_address.is_empty();
+        fields.trace.reward_author |= !req.reward_author.is_empty();
+        fields.trace.create_result_address |= !req.create_result_address.is_empty();
+        if req.transaction {
+            select_transaction_keys(fields);
+        }
+        if req.transaction_logs {
+            select_log_keys(fields);
+        }
+        if req.subtraces || req.parents {
+            select_trace_keys(fields);
+        }
+    }
+
+    // state diffs are not part of the response so only the related items need keys
+    for req in query.state_diffs.iter() {
+        if req.transaction {
+            select_transaction_keys(fields);
+        }
+        if req.transaction_logs {
+            select_log_keys(fields);
+        }
+        if req.transaction_traces {
+            select_trace_keys(fields);
+        }
+    }
+}
+
+/// Selects the fields that identify the rows of every table.
+pub(super) fn select_join_keys(fields: &mut Fields) {
+    fields.block.number = true;
+    select_log_keys(fields);
+    select_transaction_keys(fields);
+    select_trace_keys(fields);
+}
+
+fn select_log_keys(fields: &mut Fields) {
+    fields.log.log_index = true;
+    fields.log.transaction_index = true;
+}
+
+fn select_transaction_keys(fields: &mut Fields) {
+    fields.transaction.transaction_index = true;
+}
+
+fn select_trace_keys(fields: &mut Fields) {
+    fields.trace.transaction_index = true;
+    fields.trace.trace_address = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::ValidationErrorKind;
+
+    const TRANSFER: &str = "0xDDF252AD1BE2C89B69C2B068FC378DAA952BA7F163C4A11628F55A4DF523B3EF";
+
+    #[test]
+    fn build_normalizes_and_selects_fields() {
+        let query = Query::builder()
+            .from_block(10)
+            .to_block(20)
+            .log(LogRequest {
+                address: vec!["A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48".to_owned()],
+                topic0: vec![TRANSFER.to_owned()],
+                transaction: true,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            query.logs[0].address,
+            ["0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48"]
+        );
+        assert_eq!(query.logs[0].topic0, [TRANSFER.to_lowercase()]);
+        assert!(query.fields.block.number);
+        assert!(query.fields.log.address);
+        assert!(query.fields.log.topics);
+        assert!(query.fields.log.log_index);
+        assert!(query.fields.transaction.transaction_index);
+        assert!(!query.fields.trace.transaction_index);
+    }
+
+    #[test]
+    fn build_rejects_invalid_filters() {
+        let err = Query::builder()
+            .transaction(TransactionRequest {
+                sighash: vec!["0xa9059c".to_owned()],
+                ..Default::default()
+            })
+            .build()
+            .unwrap_err();
+        assert_eq!(err.path, "transactions[0].sighash[0]");
+        assert!(matches!(
+            err.kind,
+            ValidationErrorKind::InvalidLength {
+                expected: 4,
+                actual: 3,
+                ..
+            }
+        ));
+
+        let err = Query::builder()
+            .trace(TraceRequest {
+                type_: vec!["delegatecall".to_owned()],
+                ..Default::default()
+            })
+            .build()
+            .unwrap_err();
+        assert_eq!(err.path, "traces[0].type[0]");
+
+        let err = Query::builder()
+            .from_block(10)
+            .to_block(9)
+            .build()
+            .unwrap_err();
+        assert_eq!(err.path, "to_block");
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use arrow::record_batch::RecordBatch;
+
+use super::{builder, decode_prefixed_hex, ArrowResponse, LogRequest, Query};
+use super::{TraceRequest, TransactionRequest};
+use crate::mux::{
+    ancestors, and, binary_in, block_mask, descendant_of, filter, in_block_range, item_keys,
+    kept_blocks, key_in, matched, or, tx_keys, tx_of, u64_values, union_fields, utf8_in, Mask,
+};
+
+impl Query {
+    /// Combines the queries into one query that returns everything that any of them returns.
+    ///
+    /// The fields that are needed to tell which rows match which query are selected.
+    pub(crate) fn union(queries: &[Query]) -> Result<Query> {
+        let first = queries.first().context("no queries to combine")?;
+
+        let mut query = Query {
+            type_: first.type_.clone(),
+            from_block: queries
+                .iter()
+                .map(|q| q.from_block)
+                .min()
+                .unwrap_or_default(),
+            to_block: queries
+                .iter()
+                .map(|q| q.to_block)
+                .collect::<Option<Vec<_>>>()
+                .and_then(|to| to.into_iter().max()),
+            include_all_blocks: queries.iter().any(|q| q.include_all_blocks),
+            fields: union_fields(queries.iter().map(|q| &q.fields)).context("combine fields")?,
+            logs: queries.iter().flat_map(|q| q.logs.clone()).collect(),
+            transactions: queries
+                .iter()
+                .flat_map(|q| q.transactions.clone())
+                .collect(),
+            traces: queries.iter().flat_map(|q| q.traces.clone()).collect(),
+            state_diffs: queries.iter().flat_map(|q| q.state_diffs.clone()).collect(),
+        };
+        builder::select_required_fields(&mut query);
+        builder::select_join_keys(&mut query.fields);
+
+        Ok(query)
+    }
+}
+
+impl ArrowResponse {
+    /// Returns the rows of a response to a combined query that `query` would have returned
+    /// on its own.
+    ///
+    /// The columns are not changed so fields that were selected by the other queries are
+    /// filled in too. Items that are only related to state diffs can't be matched since state
+    /// diffs are not part of the response.
+    pub(crate) fn demux(&self, query: &Query) -> Result<Self> {
+        let log_txs = tx_keys(&self.logs, "block_number", "transaction_index")?;
+        let tx_txs = tx_keys(&self.transactions, "block_number", "transaction_index")?;
+        let trace_keys = item_keys(
+            &self.traces,
+            "block_number",
+            "transaction_position",
+            "trace_address",
+        )?;
+
+        let mut logs = vec![false; self.logs.num_rows()];
+        let mut transactions = vec![false; self.transactions.num_rows()];
+        let mut traces = vec![false; self.traces.num_rows()];
+
+        let mut txs_of = HashSet::new();
+        let mut logs_of = HashSet::new();
+        let mut traces_of = HashSet::new();
+        let mut subtraces_of = HashSet::new();
+        let mut parents_of = HashSet::new();
+
+        for req in query.logs.iter() {
+            let mask = log_mask(&self.logs, req).context("match logs")?;
+            for (i, key) in matched(&mask, &log_txs) {
+                logs[i] = true;
+                if req.transaction {
+                    txs_of.insert(key);
+                }
+                if req.transaction_logs {
+                    logs_of.insert(key);
+                }
+                if req.transaction_traces {
+                    traces_of.insert(key);
+                }
+            }
+        }
+
+        for req in query.transactions.iter() {
+            let mask = transaction_mask(&self.transactions, req).context("match transactions")?;
+            for (i, key) in matched(&mask, &tx_txs) {
+                transactions[i] = true;
+                if req.logs {
+                    logs_of.insert(key);
+                }
+                if req.traces {
+                    traces_of.insert(key);
+                }
+            }
+        }
+
+        for req in query.traces.iter() {
+            let mask = trace_mask(&self.traces, req).context("match traces")?;
+            for (i, key) in matched(&mask, &trace_keys) {
+                traces[i] = true;
+                let tx = (key.0, key.1);
+                if req.transaction {
+                    txs_of.insert(tx);
+                }
+                if req.transaction_logs {
+                    logs_of.insert(tx);
+                }
+                if req.parents {
+                    parents_of.extend(ancestors(&key));
+                }
+                if req.subtraces {
+                    subtraces_of.insert(key);
+                }
+            }
+        }
+
+        or(&mut transactions, &key_in(&tx_txs, &txs_of));
+        or(&mut logs, &key_in(&log_txs, &logs_of));
+        or(&mut traces, &key_in(&tx_of(&trace_keys), &traces_of));
+        or(&mut traces, &key_in(&trace_keys, &parents_of));
+        or(&mut traces, &descendant_of(&trace_keys, &subtraces_of));
+
+        let range = |batch: &RecordBatch, column: &str| {
+            in_block_range(batch, column, query.from_block, query.to_block)
+        };
+        and(
+            &mut transactions,
+            &range(&self.transactions, "block_number")?,
+        );
+        and(&mut logs, &range(&self.logs, "block_number")?);
+        and(&mut traces, &range(&self.traces, "block_number")?);
+
+        let mut with_rows = HashSet::new();
+        kept_blocks(
+            &self.transactions,
+            "block_number",
+            &transactions,
+            &mut with_rows,
+        )?;
+        kept_blocks(&self.logs, "block_number", &logs, &mut with_rows)?;
+        kept_blocks(&self.traces, "block_number", &traces, &mut with_rows)?;
+        let mut blocks = block_mask(&self.blocks, "number", &with_rows, query.include_all_blocks)?;
+        and(&mut blocks, &range(&self.blocks, "number")?);
+
+        Ok(Self {
+            blocks: filter(&self.blocks, blocks).context("blocks")?,
+            transactions: filter(&self.transactions, transactions).context("transactions")?,
+            logs: filter(&self.logs, logs).context("logs")?,
+            traces: filter(&self.traces, traces).context("traces")?,
+        })
+    }
+}
+
+fn decode(values: &[String]) -> Result<Vec<Vec<u8>>> {
+    values
+        .iter()
+        .map(|v| {
+            let digits = v.strip_prefix("0x").unwrap_or(v);
+            decode_prefixed_hex(&format!("0x{}", digits))
+                .with_context(|| format!("decode filter value {}", v))
+        })
+        .collect()
+}
+
+fn log_mask(logs: &RecordBatch, req: &LogRequest) -> Result<Mask> {
+    let mut mask = vec![true; logs.num_rows()];
+    for (column, values) in [
+        ("address", &req.address),
+        ("topic0", &req.topic0),
+        ("topic1", &req.topic1),
+        ("topic2", &req.topic2),
+        ("topic3", &req.topic3),
+    ] {
+        and(&mut mask, &binary_in(logs, column, &decode(values)?)?);
+    }
+    Ok(mask)
+}
+
+fn transaction_mask(txs: &RecordBatch, req: &TransactionRequest) -> Result<Mask> {
+    let mut mask = vec![true; txs.num_rows()];
+    for (column, values) in [
+        ("from", &req.from),
+        ("to", &req.to),
+        ("sighash", &req.sighash),
+    ] {
+        and(&mut mask, &binary_in(txs, column, &decode(values)?)?);
+    }
+    if req.first_nonce.is_some() || req.last_nonce.is_some() {
+        let first = req.first_nonce.unwrap_or(0);
+        let last = req.last_nonce.unwrap_or(u64::MAX);
+        let nonces = u64_values(txs, "nonce")?
+            .into_iter()
+            .map(|n| n.is_some_and(|n| n >= first && n <= last))
+            .collect::<Vec<_>>();
+        and(&mut mask, &nonces);
+    }
+    Ok(mask)
+}
+
+fn trace_mask(traces: &RecordBatch, req: &TraceRequest) -> Result<Mask> {
+    let mut mask = utf8_in(traces, "type", &req.type_)?;
+
+    // filters on the action of a trace only match traces of that type
+    for (type_, column, values) in [
+        ("create", "from", &req.create_from),
+        ("call", "from", &req.call_from),
+        ("call", "to", &req.call_to),
+        ("call", "sighash", &req.call_sighash),
+        ("suicide", "refund_address", &req.suicide_refund_address),
+        ("reward", "author", &req.reward_author),
+        ("create", "address", &req.create_result_address),
+    ] {
+        if values.is_empty() {
+            continue;
+        }
+        and(&mut mask, &utf8_in(traces, "type", &[type_.to_owned()])?);
+        and(&mut mask, &binary_in(traces, column, &decode(values)?)?);
+    }
+
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evm::parse_response;
+    use arrow::array::UInt64Array;
+
+    const A: &str = "0x000000000000000000000000000000000000000a";
+    const B: &str = "0x000000000000000000000000000000000000000b";
+
+    fn response() -> ArrowResponse {
+        let data = [
+            simd_json::json!({
+                "header": {"number": 1},
+                "logs": [
+                    {"logIndex": 0, "transactionIndex": 0, "address": A},
+                    {"logIndex": 1, "transactionIndex": 1, "address": B},
+                ],
+                "transactions": [{"transactionIndex": 0}, {"transactionIndex": 1}],
+            }),
+            simd_json::json!({
+                "header": {"number": 2},
+                "logs": [{"logIndex": 0, "transactionIndex": 0, "address": B}],
+            }),
+            simd_json::json!({"header": {"number": 3}}),
+        ]
+        .iter()
+        .map(|block| simd_json::to_string(block).unwrap())
+        .collect::<Vec<_>>()
+        .join("\n");
+        parse_response(data.as_bytes()).unwrap()
+    }
+
+    fn column(batch: &RecordBatch, name: &str) -> Vec<u64> {
+        batch
+            .column_by_name(name)
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap()
+            .values()
+            .to_vec()
+    }
+
+    #[test]
+    fn union_combines_requests() {
+        let a = Query {
+            from_block: 5,
+            to_block: Some(10),
+            logs: vec![LogRequest {
+                address: vec![A.to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let b = Query {
+            from_block: 1,
+            to_block: Some(7),
+            include_all_blocks: true,
+            logs: vec![LogRequest {
+                address: vec![B.to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let query = Query::union(&[a.clone(), b]).unwrap();
+        assert_eq!(query.from_block, 1);
+        assert_eq!(query.to_block, Some(10));
+        assert!(query.include_all_blocks);
+        assert_eq!(query.logs.len(), 2);
+        assert!(query.fields.log.address);
+
+        let c = Query {
+            to_block: None,
+            ..Default::default()
+        };
+        assert_eq!(Query::union(&[a, c]).unwrap().to_block, None);
+    }
+
+    #[test]
+    fn demux_keeps_matching_rows() {
+        let res = response();
+
+        let query = Query {
+            logs: vec![LogRequest {
+                address: vec![B.to_owned()],
+                transaction: true,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let demuxed = res.demux(&query).unwrap();
+        assert_eq!(column(&demuxed.logs, "block_number"), [1, 2]);
+        assert_eq!(column(&demuxed.logs, "log_index"), [1, 0]);
+        assert_eq!(column(&demuxed.transactions, "transaction_index"), [1]);
+        // block 3 is kept since it is the end of the response
+        assert_eq!(column(&demuxed.blocks, "number"), [1, 2, 3]);
+
+        let query = Query {
+            from_block: 2,
+            logs: vec![LogRequest {
+                address: vec![A.to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let demuxed = res.demux(&query).unwrap();
+        assert_eq!(demuxed.logs.num_rows(), 0);
+        assert_eq!(demuxed.transactions.num_rows(), 0);
+        assert_eq!(column(&demuxed.blocks, "number"), [3]);
+    }
+
+    #[test]
+    fn demux_filters_by_nonce_range() {
+        let data = simd_json::json!({
+            "header": {"number": 1},
+            "transactions": [
+                {"transactionIndex": 0, "nonce": 4},
+                {"transactionIndex": 1, "nonce": 5},
+                {"transactionIndex": 2, "nonce": 7},
+            ],
+        });
+        let res = parse_response(simd_json::to_string(&data).unwrap().as_bytes()).unwrap();
+
+        let query = Query {
+            transactions: vec![TransactionRequest {
+                first_nonce: Some(5),
+                last_nonce: Some(6),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        // the combined query has to select the nonce for the demux to see it
+        let union = Query::union(&[query.clone()]).unwrap();
+        assert!(union.fields.transaction.nonce);
+
+        let demuxed = res.demux(&query).unwrap();
+        assert_eq!(column(&demuxed.transactions, "transaction_index"), [1]);
+    }
+}
@@ -0,0 +1,207 @@
+//! Typed blocks that are deserialized straight from the portal response.
+
+use alloy_primitives::{Address, Bytes, B256, B64, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::rows::{dedup_by_key, BlockRows};
+
+/// A block along with its items that matched the query.
+///
+/// Fields that weren't selected in the query are `None`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockData {
+    pub header: Block,
+    #[serde(default)]
+    pub transactions: Vec<Transaction>,
+    #[serde(default)]
+    pub logs: Vec<Log>,
+    #[serde(default)]
+    pub traces: Vec<Trace>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Block {
+    /// Always selected by the client.
+    pub number: u64,
+    pub hash: Option<B256>,
+    pub parent_hash: Option<B256>,
+    pub timestamp: Option<u64>,
+    pub transactions_root: Option<B256>,
+    pub receipts_root: Option<B256>,
+    pub state_root: Option<B256>,
+    pub logs_bloom: Option<Bytes>,
+    pub sha3_uncles: Option<B256>,
+    pub extra_data: Option<Bytes>,
+    pub miner: Option<Address>,
+    pub nonce: Option<B64>,
+    pub mix_hash: Option<B256>,
+    pub size: Option<u64>,
+    pub gas_limit: Option<U256>,
+    pub gas_used: Option<U256>,
+    pub difficulty: Option<U256>,
+    pub total_difficulty: Option<U256>,
+    pub base_fee_per_gas: Option<U256>,
+    pub blob_gas_used: Option<U256>,
+    pub excess_blob_gas: Option<U256>,
+    pub l1_block_number: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Transaction {
+    pub transaction_index: Option<u64>,
+    pub hash: Option<B256>,
+    pub nonce: Option<u64>,
+    pub from: Option<Address>,
+    pub to: Option<Address>,
+    pub input: Option<Bytes>,
+    pub value: Option<U256>,
+    pub gas: Option<U256>,
+    pub gas_price: Option<U256>,
+    pub max_fee_per_gas: Option<U256>,
+    pub max_priority_fee_per_gas: Option<U256>,
+    pub v: Option<U256>,
+    pub r: Option<U256>,
+    pub s: Option<U256>,
+    pub y_parity: Option<u8>,
+    pub chain_id: Option<u64>,
+    pub sighash: Option<Bytes>,
+    pub contract_address: Option<Address>,
+    pub gas_used: Option<U256>,
+    pub cumulative_gas_used: Option<U256>,
+    pub effective_gas_price: Option<U256>,
+    #[serde(rename = "type")]
+    pub type_: Option<u8>,
+    pub status: Option<u8>,
+    pub max_fee_per_blob_gas: Option<U256>,
+    pub blob_versioned_hashes: Option<Vec<B256>>,
+    pub l1_fee: Option<U256>,
+    pub l1_fee_scalar: Option<U256>,
+    pub l1_gas_price: Option<U256>,
+    pub l1_gas_used: Option<U256>,
+    pub l1_blob_base_fee: Option<U256>,
+    pub l1_blob_base_fee_scalar: Option<U256>,
+    pub l1_base_fee_scalar: Option<U256>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Log {
+    pub log_index: Option<u64>,
+    pub transaction_index: Option<u64>,
+    pub transaction_hash: Option<B256>,
+    pub address: Option<Address>,
+    pub data: Option<Bytes>,
+    pub topics: Option<Vec<B256>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Trace {
+    pub transaction_index: Option<u64>,
+    pub trace_address: Option<Vec<u64>>,
+    pub subtraces: Option<u64>,
+    /// One of `create`, `call`, `suicide` or `reward`.
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub error: Option<String>,
+    pub revert_reason: Option<String>,
+    pub action: Option<TraceAction>,
+    pub result: Option<TraceResult>,
+}
+
+/// Fields of the action of a trace, which ones are set depends on the type of the trace.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceAction {
+    pub from: Option<Address>,
+    pub to: Option<Address>,
+    pub value: Option<U256>,
+    pub gas: Option<U256>,
+    pub input: Option<Bytes>,
+    pub init: Option<Bytes>,
+    pub sighash: Option<Bytes>,
+    /// Call type of call traces or reward type of reward traces.
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub call_type: Option<String>,
+    pub address: Option<Address>,
+    pub refund_address: Option<Address>,
+    pub balance: Option<U256>,
+    pub author: Option<Address>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceResult {
+    pub gas_used: Option<U256>,
+    pub code: Option<Bytes>,
+    pub address: Option<Address>,
+    pub output: Option<Bytes>,
+}
+
+impl BlockRows for BlockData {
+    fn number(&self) -> u64 {
+        self.header.number
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        self.header.timestamp
+    }
+
+    fn row_counts(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("blocks", 1),
+            ("transactions", self.transactions.len()),
+            ("logs", self.logs.len()),
+            ("traces", self.traces.len()),
+        ]
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.transactions.extend(other.transactions);
+        dedup_by_key(&mut self.transactions, |tx| tx.transaction_index);
+        self.logs.extend(other.logs);
+        dedup_by_key(&mut self.logs, |log| log.log_index);
+        self.traces.extend(other.traces);
+        dedup_by_key(&mut self.traces, |trace| {
+            (trace.transaction_index, trace.trace_address.clone())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserializes_block() {
+        let block: BlockData = serde_json::from_str(
+            r#"{
+                "header":{"number":7,"hash":"0x0000000000000000000000000000000000000000000000000000000000000001","baseFeePerGas":"0x10"},
+                "logs":[{"logIndex":3,"address":"0x0000000000000000000000000000000000000002","data":"0x0102","topics":[]}],
+                "traces":[{"type":"call","traceAddress":[0,1],"action":{"from":"0x0000000000000000000000000000000000000003","value":"0x0"}}]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(block.header.number, 7);
+        assert_eq!(block.header.hash, Some(B256::with_last_byte(1)));
+        assert_eq!(block.header.base_fee_per_gas, Some(U256::from(16)));
+        assert_eq!(block.header.miner, None);
+        assert!(block.transactions.is_empty());
+
+        let log = &block.logs[0];
+        assert_eq!(log.log_index, Some(3));
+        assert_eq!(log.address, Some(Address::with_last_byte(2)));
+        assert_eq!(log.data, Some(Bytes::from_static(&[1, 2])));
+        assert_eq!(log.topics, Some(Vec::new()));
+
+        let trace = &block.traces[0];
+        assert_eq!(trace.trace_address, Some(vec![0, 1]));
+        let action = trace.action.as_ref().unwrap();
+        assert_eq!(action.from, Some(Address::with_last_byte(3)));
+        assert_eq!(action.value, Some(U256::ZERO));
+    }
+}
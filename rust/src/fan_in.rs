@@ -0,0 +1,559 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use futures_lite::StreamExt;
+use tokio::sync::mpsc::{self, error::TryRecvError};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::source::SourceQuery;
+use crate::stream::{events, Event};
+use crate::{evm, svm, Client, StreamConfig, StreamEnd, StreamHandle, StreamItem};
+
+/// Response of one of the chains of a `FanIn`.
+#[derive(Debug)]
+pub enum ChainResponse {
+    Evm(evm::ArrowResponse),
+    Svm(svm::ArrowResponse),
+}
+
+impl ChainResponse {
+    fn slice(&self, from_block: u64, to_block: u64) -> Result<Self> {
+        match self {
+            Self::Evm(res) => res.slice(from_block, to_block).map(Self::Evm),
+            Self::Svm(res) => res.slice(from_block, to_block).map(Self::Svm),
+        }
+    }
+}
+
+impl From<evm::ArrowResponse> for ChainResponse {
+    fn from(res: evm::ArrowResponse) -> Self {
+        Self::Evm(res)
+    }
+}
+
+impl From<svm::ArrowResponse> for ChainResponse {
+    fn from(res: svm::ArrowResponse) -> Self {
+        Self::Svm(res)
+    }
+}
+
+/// An item yielded by a `FanIn` stream.
+///
+/// Responses of the sources are split into an item per block. The first item of a response
+/// also covers the range the response skipped before its first block, and carries the size and
+/// duration of the response, the other items have them set to zero.
+#[derive(Debug)]
+pub struct FanInItem {
+    /// Name that was given to the source of the item.
+    pub dataset: Arc<str>,
+    /// Timestamp of the block of the item, in unix seconds.
+    pub timestamp: u64,
+    /// Items yielded after this one have a timestamp at or after this value.
+    ///
+    /// It is the lowest timestamp that the running sources have reached. Sources that didn't
+    /// return anything yet count as zero.
+    pub watermark: u64,
+    pub item: StreamItem<ChainResponse>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FanInConfig {
+    /// Number of items to buffer per source. Values below 1 are treated as 1.
+    pub buffer_size: usize,
+    /// Maximum time to hold back the other sources while waiting for a source to return
+    /// its next response.
+    ///
+    /// Sources that are waiting for new blocks at the head don't hold back the others at all.
+    pub max_wait_millis: u64,
+}
+
+impl Default for FanInConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: 4,
+            max_wait_millis: 10_000,
+        }
+    }
+}
+
+enum Source {
+    Evm(Arc<Client>, evm::Query),
+    Svm(Arc<Client>, svm::Query),
+}
+
+/// Runs streams of several chains and yields their responses as a single stream ordered by
+/// block timestamp.
+///
+/// Responses are split at block boundaries and the blocks are ordered by their timestamp. A block
+/// is yielded once every source has a block buffered, is waiting for new blocks at the head, or
+/// made the others wait for longer than `FanInConfig::max_wait_millis`. Blocks of a source that
+/// fell behind this way can come after blocks with a later timestamp.
+pub struct FanIn {
+    config: FanInConfig,
+    sources: Vec<(Arc<str>, Source, StreamConfig)>,
+}
+
+impl FanIn {
+    pub fn new(config: FanInConfig) -> Self {
+        Self {
+            config,
+            sources: Vec::new(),
+        }
+    }
+
+    /// Adds an EVM stream. `dataset` is used to tag the items of the stream.
+    pub fn evm(
+        mut self,
+        dataset: impl Into<Arc<str>>,
+        client: Arc<Client>,
+        query: evm::Query,
+        config: StreamConfig,
+    ) -> Self {
+        self.sources
+            .push((dataset.into(), Source::Evm(client, query), config));
+        self
+    }
+
+    /// Adds an SVM stream. `dataset` is used to tag the items of the stream.
+    pub fn svm(
+        mut self,
+        dataset: impl Into<Arc<str>>,
+        client: Arc<Client>,
+        query: svm::Query,
+        config: StreamConfig,
+    ) -> Self {
+        self.sources
+            .push((dataset.into(), Source::Svm(client, query), config));
+        self
+    }
+
+    /// Starts the streams in the background.
+    ///
+    /// The first error of any source stops all of them. When the streams end without an error,
+    /// the handle returns the reason the last of them stopped.
+    pub fn spawn(self) -> (mpsc::Receiver<Result<FanInItem>>, StreamHandle) {
+        let buffer_size = self.config.buffer_size.max(1);
+        let (tx, rx) = mpsc::channel(buffer_size);
+        let cancel = CancellationToken::new();
+        let sources_cancel = cancel.child_token();
+        let notify = Arc::new(Notify::new());
+
+        let receivers = self
+            .sources
+            .into_iter()
+            .map(|(dataset, source, config)| {
+                let (tx, rx) = mpsc::channel(buffer_size);
+                let notify = notify.clone();
+                let cancel = sources_cancel.clone();
+                match source {
+                    Source::Evm(client, query) => tokio::spawn(run_source(
+                        client, dataset, query, config, tx, notify, cancel,
+                    )),
+                    Source::Svm(client, query) => tokio::spawn(run_source(
+                        client, dataset, query, config, tx, notify, cancel,
+                    )),
+                };
+                rx
+            })
+            .collect();
+
+        let config = self.config;
+        let task_cancel = cancel.clone();
+        let task = tokio::spawn(async move {
+            let _stop_sources = sources_cancel.drop_guard();
+            run_fan_in(receivers, notify, config, tx, task_cancel).await
+        });
+
+        (rx, StreamHandle::new(cancel, task))
+    }
+}
+
+enum SourceEvent {
+    Data(Box<FanInItem>),
+    Idle,
+    End(StreamEnd),
+}
+
+async fn run_source<Q: SourceQuery<Client>>(
+    client: Arc<Client>,
+    dataset: Arc<str>,
+    query: Q,
+    config: StreamConfig,
+    tx: mpsc::Sender<Result<SourceEvent>>,
+    notify: Arc<Notify>,
+    cancel: CancellationToken,
+) where
+    Q::Response: Into<ChainResponse>,
+{
+    let mut query = query;
+    query.select_timestamp_field();
+
+    let events = events(&*client, query, config, None);
+    futures_lite::pin!(events);
+
+    let mut timestamp = 0;
+
+    loop {
+        let ev = tokio::select! {
+            _ = cancel.cancelled() => return,
+            ev = events.next() => ev,
+        };
+
+        let evs = match ev {
+            Some(Ok(Event::Data(item))) => match Q::block_timestamps(&item.data) {
+                Ok(timestamps) => {
+                    let items = block_items(&dataset, item.map(Into::into), &timestamps, timestamp);
+                    if let Some((_, ts)) = timestamps.last() {
+                        timestamp = *ts;
+                    }
+                    match items {
+                        Ok(items) => items
+                            .into_iter()
+                            .map(|item| Ok(SourceEvent::Data(Box::new(item))))
+                            .collect(),
+                        Err(e) => vec![Err(e.context("split response into blocks"))],
+                    }
+                }
+                Err(e) => vec![Err(e.context("get block timestamps"))],
+            },
+            Some(Ok(Event::Idle(_))) => vec![Ok(SourceEvent::Idle)],
+            Some(Ok(Event::End(end))) => vec![Ok(SourceEvent::End(end))],
+            Some(Err(e)) => vec![Err(e)],
+            None => vec![Err(anyhow!("stream ended without an end event"))],
+        };
+
+        for ev in evs {
+            let ev = ev.with_context(|| format!("stream {}", dataset));
+            let stop = !matches!(ev, Ok(SourceEvent::Data(_)) | Ok(SourceEvent::Idle));
+
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                res = tx.send(ev) => {
+                    if res.is_err() {
+                        return;
+                    }
+                }
+            }
+            notify.notify_one();
+
+            if stop {
+                return;
+            }
+        }
+    }
+}
+
+/// Splits the response into an item per block, given the timestamps of its blocks.
+///
+/// A response without timestamps is kept whole with the timestamp of the previous response.
+fn block_items(
+    dataset: &Arc<str>,
+    item: StreamItem<ChainResponse>,
+    timestamps: &[(u64, u64)],
+    last_timestamp: u64,
+) -> Result<Vec<FanInItem>> {
+    if timestamps.is_empty() {
+        return Ok(vec![FanInItem {
+            dataset: dataset.clone(),
+            timestamp: last_timestamp,
+            watermark: 0,
+            item,
+        }]);
+    }
+
+    let mut items = Vec::with_capacity(timestamps.len());
+    let mut from_block = item.from_block;
+    for (i, (number, timestamp)) in timestamps.iter().enumerate() {
+        let first = i == 0;
+        let to_block = if i + 1 == timestamps.len() {
+            item.to_block
+        } else {
+            *number
+        };
+        let data = item
+            .data
+            .slice(from_block, to_block)
+            .with_context(|| format!("slice block {}", number))?;
+        items.push(FanInItem {
+            dataset: dataset.clone(),
+            timestamp: *timestamp,
+            watermark: 0,
+            item: StreamItem {
+                from_block,
+                to_block,
+                head: item.head,
+                num_bytes: if first { item.num_bytes } else { 0 },
+                duration: if first { item.duration } else { Duration::ZERO },
+                data,
+            },
+        });
+        from_block = to_block + 1;
+    }
+
+    Ok(items)
+}
+
+struct SourceState {
+    rx: mpsc::Receiver<Result<SourceEvent>>,
+    next: Option<FanInItem>,
+    timestamp: u64,
+    idle: bool,
+    end: Option<StreamEnd>,
+    waiting_since: Instant,
+}
+
+impl SourceState {
+    /// Reads buffered events until there is a response to yield.
+    fn poll(&mut self) -> Result<()> {
+        while self.next.is_none() && self.end.is_none() {
+            match self.rx.try_recv() {
+                Ok(Ok(SourceEvent::Data(item))) => {
+                    self.timestamp = item.timestamp;
+                    self.idle = false;
+                    self.next = Some(*item);
+                }
+                Ok(Ok(SourceEvent::Idle)) => self.idle = true,
+                Ok(Ok(SourceEvent::End(end))) => self.end = Some(end),
+                Ok(Err(e)) => return Err(e),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    return Err(anyhow!("source stopped without an end event"))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_done(&self) -> bool {
+        self.end.is_some() && self.next.is_none()
+    }
+
+    /// Time left until the source stops holding back the others, if it is doing so.
+    fn blocks_for(&self, max_wait: Duration) -> Option<Duration> {
+        if self.next.is_some() || self.end.is_some() || self.idle {
+            return None;
+        }
+        max_wait
+            .checked_sub(self.waiting_since.elapsed())
+            .filter(|d| !d.is_zero())
+    }
+}
+
+async fn run_fan_in(
+    receivers: Vec<mpsc::Receiver<Result<SourceEvent>>>,
+    notify: Arc<Notify>,
+    config: FanInConfig,
+    tx: mpsc::Sender<Result<FanInItem>>,
+    cancel: CancellationToken,
+) -> Result<StreamEnd> {
+    let max_wait = Duration::from_millis(config.max_wait_millis);
+    let start = Instant::now();
+    let mut sources = receivers
+        .into_iter()
+        .map(|rx| SourceState {
+            rx,
+            next: None,
+            timestamp: 0,
+            idle: false,
+            end: None,
+            waiting_since: start,
+        })
+        .collect::<Vec<_>>();
+    let mut last_end = StreamEnd::ReachedToBlock;
+
+    loop {
+        for source in sources.iter_mut() {
+            let was_done = source.is_done();
+            if let Err(e) = source.poll() {
+                tx.send(Err(anyhow!("{:?}", e))).await.ok();
+                return Err(e);
+            }
+            if !was_done && source.is_done() {
+                last_end = source.end.unwrap_or(last_end);
+            }
+        }
+
+        if sources.iter().all(|s| s.is_done()) {
+            return Ok(last_end);
+        }
+
+        let wait = sources.iter().filter_map(|s| s.blocks_for(max_wait)).min();
+        let next = sources
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.next.as_ref().map(|item| (item.timestamp, i)))
+            .min();
+
+        if let (None, Some((_, i))) = (wait, next) {
+            let source = &mut sources[i];
+            let mut item = source.next.take().context("take next item")?;
+            source.waiting_since = Instant::now();
+            if source.is_done() {
+                last_end = source.end.unwrap_or(last_end);
+            }
+            item.watermark = sources
+                .iter()
+                .filter(|s| !s.is_done())
+                .map(|s| s.timestamp)
+                .min()
+                .unwrap_or(item.timestamp);
+
+            tokio::select! {
+                _ = cancel.cancelled() => return Ok(StreamEnd::Cancelled),
+                res = tx.send(Ok(item)) => {
+                    if res.is_err() {
+                        log::debug!("receiver is closed so quitting fan-in stream");
+                        return Ok(StreamEnd::ReceiverClosed);
+                    }
+                }
+            }
+            continue;
+        }
+
+        // nothing to yield until a source sends something or the wait runs out
+        let wait = wait.filter(|_| next.is_some());
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(StreamEnd::Cancelled),
+            _ = notify.notified() => {}
+            _ = tokio::time::sleep(wait.unwrap_or_default()), if wait.is_some() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::stream_item;
+
+    fn item(dataset: &str, number: u64, timestamp: u64) -> Result<SourceEvent> {
+        let data = format!(
+            r#"{{"header":{{"number":{},"timestamp":{}}}}}"#,
+            number, timestamp
+        );
+        Ok(SourceEvent::Data(Box::new(FanInItem {
+            dataset: dataset.into(),
+            timestamp,
+            watermark: 0,
+            item: stream_item(number, number, &data).map(Into::into),
+        })))
+    }
+
+    fn spawn(
+        receivers: Vec<mpsc::Receiver<Result<SourceEvent>>>,
+        notify: Arc<Notify>,
+        max_wait_millis: u64,
+    ) -> (mpsc::Receiver<Result<FanInItem>>, StreamHandle) {
+        let config = FanInConfig {
+            buffer_size: 4,
+            max_wait_millis,
+        };
+        let (tx, rx) = mpsc::channel(config.buffer_size);
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn(run_fan_in(receivers, notify, config, tx, cancel.clone()));
+        (rx, StreamHandle::new(cancel, task))
+    }
+
+    #[tokio::test]
+    async fn orders_items_by_timestamp() {
+        let (a_tx, a_rx) = mpsc::channel(4);
+        let (b_tx, b_rx) = mpsc::channel(4);
+        for ev in [item("a", 1, 10), item("a", 2, 30)] {
+            a_tx.send(ev).await.unwrap();
+        }
+        a_tx.send(Ok(SourceEvent::End(StreamEnd::ReachedToBlock)))
+            .await
+            .unwrap();
+        for ev in [item("b", 7, 20), item("b", 8, 40)] {
+            b_tx.send(ev).await.unwrap();
+        }
+        b_tx.send(Ok(SourceEvent::End(StreamEnd::ReachedHead)))
+            .await
+            .unwrap();
+
+        let (mut rx, handle) = spawn(vec![a_rx, b_rx], Arc::new(Notify::new()), 60_000);
+        let mut items = Vec::new();
+        while let Some(item) = rx.recv().await {
+            let item = item.unwrap();
+            items.push((item.dataset.to_string(), item.timestamp, item.watermark));
+        }
+
+        let expected = [("a", 10, 10), ("b", 20, 20), ("a", 30, 30), ("b", 40, 40)];
+        assert_eq!(
+            items,
+            expected.map(|(d, ts, wm)| (d.to_owned(), ts, wm)).to_vec()
+        );
+        assert_eq!(handle.join().await.unwrap(), StreamEnd::ReachedHead);
+    }
+
+    #[tokio::test]
+    async fn slow_source_does_not_block_others() {
+        let notify = Arc::new(Notify::new());
+        let (a_tx, a_rx) = mpsc::channel(4);
+        let (b_tx, b_rx) = mpsc::channel(4);
+        a_tx.send(item("a", 1, 10)).await.unwrap();
+
+        let (mut rx, handle) = spawn(vec![a_rx, b_rx], notify.clone(), 50);
+        let start = Instant::now();
+        let item = rx.recv().await.unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            (&*item.dataset, item.timestamp, item.watermark),
+            ("a", 10, 0)
+        );
+
+        b_tx.send(Err(anyhow!("portal is down"))).await.unwrap();
+        notify.notify_one();
+        assert!(rx.recv().await.unwrap().is_err());
+        assert!(handle.join().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn idle_source_does_not_block_others() {
+        let notify = Arc::new(Notify::new());
+        let (a_tx, a_rx) = mpsc::channel(4);
+        let (b_tx, b_rx) = mpsc::channel(4);
+        b_tx.send(Ok(SourceEvent::Idle)).await.unwrap();
+        a_tx.send(item("a", 1, 10)).await.unwrap();
+
+        let (mut rx, handle) = spawn(vec![a_rx, b_rx], notify, 60_000);
+        let item = rx.recv().await.unwrap().unwrap();
+        assert_eq!(item.timestamp, 10);
+
+        handle.cancel();
+        assert_eq!(handle.join().await.unwrap(), StreamEnd::Cancelled);
+    }
+
+    #[test]
+    fn splits_responses_into_blocks() {
+        let data = r#"{"header":{"number":10,"timestamp":100},"logs":[{"logIndex":0}]}
+{"header":{"number":11,"timestamp":112},"logs":[{"logIndex":0},{"logIndex":1}]}
+{"header":{"number":13,"timestamp":136}}"#;
+        let item = stream_item(8, 13, data).map(ChainResponse::from);
+        let timestamps = [(10, 100), (11, 112), (13, 136)];
+
+        let items = block_items(&"a".into(), item, &timestamps, 0).unwrap();
+        let blocks = items
+            .iter()
+            .map(|item| {
+                let ChainResponse::Evm(res) = &item.item.data else {
+                    panic!("expected an evm response");
+                };
+                (
+                    item.item.from_block,
+                    item.item.to_block,
+                    item.timestamp,
+                    res.blocks.num_rows(),
+                    res.logs.num_rows(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            blocks,
+            [(8, 10, 100, 1, 1), (11, 11, 112, 1, 2), (12, 13, 136, 1, 0)]
+        );
+        assert_eq!(items[0].item.num_bytes, data.len());
+        assert_eq!(items[1].item.num_bytes, 0);
+    }
+}
@@ -0,0 +1,261 @@
+//! Arrow Flight service that runs portal streams.
+//!
+//! A `do_get` ticket is a JSON object that holds the query and the name of the table to return:
+//!
+//! ```json
+//! {"table": "logs", "query": {"type": "evm", "fromBlock": 0, ...}}
+//! ```
+//!
+//! The query is run as a stream and the table is sent back batch by batch.
+
+// the service trait requires `tonic::Status` as the error type
+#![allow(clippy::result_large_err)]
+
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use arrow::record_batch::RecordBatch;
+use arrow_flight::encode::FlightDataEncoderBuilder;
+use arrow_flight::error::FlightError;
+use arrow_flight::flight_service_server::{FlightService, FlightServiceServer};
+use arrow_flight::{
+    Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightInfo,
+    HandshakeRequest, HandshakeResponse, PollInfo, PutResult, SchemaResult, Ticket,
+};
+use futures_lite::{Stream, StreamExt};
+use serde::Serialize;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::sink::ArrowTables;
+use crate::source::SourceQuery;
+use crate::stream::lazy_stream;
+use crate::{evm, svm, Client, StreamConfig};
+
+/// Ticket of a `do_get` request.
+#[derive(Debug, Clone, Serialize)]
+pub struct FlightTicket {
+    /// Name of the table to return, like `blocks` or `logs`.
+    pub table: String,
+    pub query: TicketQuery,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum TicketQuery {
+    Evm(evm::Query),
+    Svm(svm::Query),
+}
+
+impl FlightTicket {
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(self).context("serialize ticket")
+    }
+
+    /// Parses the ticket. The kind of the query is decided by its `type` field.
+    pub fn from_json(data: &[u8]) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_slice(data).context("parse ticket")?;
+        let table = value
+            .get("table")
+            .and_then(|t| t.as_str())
+            .context("ticket has no table")?
+            .to_owned();
+        let query = value.get("query").context("ticket has no query")?;
+        let query = match query.get("type").and_then(|t| t.as_str()) {
+            Some("evm") => {
+                TicketQuery::Evm(serde_json::from_value(query.clone()).context("parse evm query")?)
+            }
+            Some("solana") => {
+                TicketQuery::Svm(serde_json::from_value(query.clone()).context("parse svm query")?)
+            }
+            Some(other) => return Err(anyhow!("unknown query type {}", other)),
+            None => return Err(anyhow!("query has no type")),
+        };
+
+        Ok(Self { table, query })
+    }
+}
+
+type BoxStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + 'static>>;
+
+/// Runs the queries of `do_get` tickets against a portal. Other methods are not implemented.
+pub struct PortalFlightService {
+    client: Arc<Client>,
+    config: StreamConfig,
+}
+
+impl PortalFlightService {
+    pub fn new(client: Arc<Client>, config: StreamConfig) -> Self {
+        Self { client, config }
+    }
+
+    pub fn into_server(self) -> FlightServiceServer<Self> {
+        FlightServiceServer::new(self)
+    }
+}
+
+fn table_stream<Q: SourceQuery<Client>>(
+    client: Arc<Client>,
+    query: Q,
+    config: StreamConfig,
+    table: &str,
+) -> Result<impl Stream<Item = Result<RecordBatch, FlightError>> + Send + 'static>
+where
+    Q::Response: ArrowTables,
+{
+    let index = Q::Response::TABLE_NAMES
+        .iter()
+        .position(|t| *t == table)
+        .with_context(|| format!("unknown table {}", table))?;
+
+    Ok(async_stream::stream! {
+        let items = lazy_stream(&*client, query, config);
+        futures_lite::pin!(items);
+
+        while let Some(item) = items.next().await {
+            match item {
+                Ok(item) => yield Ok(item.data.tables()[index].1.clone()),
+                Err(e) => {
+                    yield Err(FlightError::ExternalError(e.into()));
+                    return;
+                }
+            }
+        }
+    })
+}
+
+#[tonic::async_trait]
+impl FlightService for PortalFlightService {
+    type HandshakeStream = BoxStream<HandshakeResponse>;
+    type ListFlightsStream = BoxStream<FlightInfo>;
+    type DoGetStream = BoxStream<FlightData>;
+    type DoPutStream = BoxStream<PutResult>;
+    type DoActionStream = BoxStream<arrow_flight::Result>;
+    type ListActionsStream = BoxStream<ActionType>;
+    type DoExchangeStream = BoxStream<FlightData>;
+
+    async fn handshake(
+        &self,
+        _request: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented("handshake"))
+    }
+
+    async fn list_flights(
+        &self,
+        _request: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        Err(Status::unimplemented("list_flights"))
+    }
+
+    async fn get_flight_info(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        Err(Status::unimplemented("get_flight_info"))
+    }
+
+    async fn poll_flight_info(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> Result<Response<PollInfo>, Status> {
+        Err(Status::unimplemented("poll_flight_info"))
+    }
+
+    async fn get_schema(
+        &self,
+        _request: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        Err(Status::unimplemented("get_schema"))
+    }
+
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> Result<Response<Self::DoGetStream>, Status> {
+        let invalid = |e: anyhow::Error| Status::invalid_argument(format!("{:?}", e));
+
+        let ticket = FlightTicket::from_json(&request.into_inner().ticket).map_err(invalid)?;
+        let client = self.client.clone();
+        let batches: Pin<Box<dyn Stream<Item = _> + Send>> = match ticket.query {
+            TicketQuery::Evm(query) => {
+                Box::pin(table_stream(client, query, self.config, &ticket.table).map_err(invalid)?)
+            }
+            TicketQuery::Svm(query) => {
+                Box::pin(table_stream(client, query, self.config, &ticket.table).map_err(invalid)?)
+            }
+        };
+
+        let data = FlightDataEncoderBuilder::new()
+            .build(batches)
+            .map(|data| data.map_err(Status::from));
+        Ok(Response::new(Box::pin(data)))
+    }
+
+    async fn do_put(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented("do_put"))
+    }
+
+    async fn do_action(
+        &self,
+        _request: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("do_action"))
+    }
+
+    async fn list_actions(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Err(Status::unimplemented("list_actions"))
+    }
+
+    async fn do_exchange(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented("do_exchange"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientConfig;
+
+    #[test]
+    fn ticket_roundtrip() {
+        let ticket = FlightTicket {
+            table: "instructions".to_owned(),
+            query: TicketQuery::Svm(svm::Query {
+                from_block: 5,
+                ..Default::default()
+            }),
+        };
+        let parsed = FlightTicket::from_json(&ticket.to_json().unwrap()).unwrap();
+        assert_eq!(parsed.table, "instructions");
+        assert!(matches!(parsed.query, TicketQuery::Svm(q) if q.from_block == 5));
+
+        let ticket = br#"{"table": "logs", "query": {"type": "tron"}}"#;
+        assert!(FlightTicket::from_json(ticket).is_err());
+    }
+
+    #[tokio::test]
+    async fn do_get_rejects_unknown_table() {
+        let client = Client::new("http://localhost".parse().unwrap(), ClientConfig::default());
+        let service = PortalFlightService::new(Arc::new(client), StreamConfig::default());
+
+        let ticket = FlightTicket {
+            table: "instructions".to_owned(),
+            query: TicketQuery::Evm(Default::default()),
+        };
+        let request = Request::new(Ticket {
+            ticket: ticket.to_json().unwrap().into(),
+        });
+        let status = service.do_get(request).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -1,15 +1,51 @@
 #![allow(clippy::get_first)]
 
+use std::ops::Range;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
+use futures_lite::Stream;
 use reqwest::{header::CONTENT_TYPE, Client as HttpClient, Method, StatusCode, Url};
 use tokio::sync::mpsc;
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "cache")]
+pub mod cache;
+#[cfg(feature = "datafusion")]
+pub mod datafusion;
 pub mod evm;
+mod fan_in;
+#[cfg(feature = "flight")]
+pub mod flight;
+mod mux;
+mod query;
+mod raw;
+mod rows;
+pub mod sink;
+pub mod source;
+mod split;
+mod stream;
 pub mod svm;
+mod telemetry;
+#[cfg(test)]
+mod test_util;
+mod timestamp;
+mod validate;
+
+pub use fan_in::{ChainResponse, FanIn, FanInConfig, FanInItem};
+use query::PortalQuery;
+use raw::Raw;
+pub use raw::{RawBlock, RawResponse};
+use rows::Rows;
+pub use source::DataSource;
+pub use stream::{
+    stop_when, Idle, StopPredicate, StreamEnd, StreamEvent, StreamHandle, StreamItem,
+    StreamReceiver,
+};
+pub use validate::{ValidationError, ValidationErrorKind};
 
 #[derive(Debug, Clone, Copy)]
 pub struct ClientConfig {
@@ -18,6 +54,12 @@ pub struct ClientConfig {
     pub retry_base_ms: u64,
     pub retry_ceiling_ms: u64,
     pub http_req_timeout_millis: u64,
+    /// Maximum number of filter values, like addresses or topics, to send in a single request.
+    ///
+    /// Queries with more values are split into several requests over the same block range and
+    /// the responses are merged into one. A request that filters on more lists than this still
+    /// gets a value of each list, so it can go over the limit.
+    pub max_filter_values: usize,
 }
 
 impl Default for ClientConfig {
@@ -28,6 +70,7 @@ impl Default for ClientConfig {
             retry_base_ms: 250,
             retry_ceiling_ms: 2000,
             http_req_timeout_millis: 40_000,
+            max_filter_values: 1_000,
         }
     }
 }
@@ -35,8 +78,33 @@ impl Default for ClientConfig {
 #[derive(Debug, Clone, Copy)]
 pub struct StreamConfig {
     pub stop_on_head: bool,
+    /// Minimum time to wait before polling again when the stream reaches the finalized head.
     pub head_poll_interval_millis: u64,
+    /// Maximum time to wait before polling again when the stream reaches the finalized head.
+    ///
+    /// The wait time is estimated from the observed block time of the chain
+    /// and backs off exponentially until the block time is known.
+    /// Setting this to `head_poll_interval_millis` makes the poll interval fixed.
+    pub max_head_poll_interval_millis: u64,
     pub buffer_size: usize,
+    /// Stop at the finalized head of the portal as it was when the stream started.
+    ///
+    /// This makes the stream return the same data every time it is run even if the chain moves on.
+    pub stop_at_start_head: bool,
+    /// Stop after this many blocks, starting from the query's `from_block`.
+    ///
+    /// The stream ends with `StreamEnd::MaxBlocks` if this comes before the query's `to_block`,
+    /// without running any query if it is 0.
+    pub max_blocks: Option<u64>,
+    /// Stop after the response that brings the number of received rows to this value.
+    ///
+    /// Rows of the blocks table are not counted.
+    pub max_rows: Option<u64>,
+    /// Stop before the first block that has a timestamp at or after this value, in unix seconds.
+    ///
+    /// Only blocks that appear in responses are checked so the scanned range of the last item can
+    /// include blocks after the timestamp if `include_all_blocks` isn't set on the query.
+    pub stop_at_timestamp: Option<u64>,
 }
 
 impl Default for StreamConfig {
@@ -44,7 +112,12 @@ impl Default for StreamConfig {
         Self {
             stop_on_head: false,
             head_poll_interval_millis: 1_000,
+            max_head_poll_interval_millis: 10_000,
             buffer_size: 10,
+            stop_at_start_head: false,
+            max_blocks: None,
+            max_rows: None,
+            stop_at_timestamp: None,
         }
     }
 }
@@ -56,6 +129,10 @@ pub struct Client {
     retry_backoff_ms: u64,
     retry_base_ms: u64,
     retry_ceiling_ms: u64,
+    max_filter_values: usize,
+    timestamp_cache: timestamp::TimestampCache,
+    #[cfg(feature = "cache")]
+    cache: Option<cache::ResponseCache>,
 }
 
 static APP_USER_AGENT: &str = concat!("sqd-portal-client-rust/", env!("CARGO_PKG_VERSION"),);
@@ -77,6 +154,10 @@ impl Client {
             retry_backoff_ms: config.retry_backoff_ms,
             retry_base_ms: config.retry_base_ms,
             retry_ceiling_ms: config.retry_ceiling_ms,
+            max_filter_values: config.max_filter_values,
+            timestamp_cache: Default::default(),
+            #[cfg(feature = "cache")]
+            cache: None,
         }
     }
 
@@ -84,198 +165,437 @@ impl Client {
         &self,
         query: &svm::Query,
     ) -> Result<Option<svm::ArrowResponse>> {
-        let query = simd_json::to_vec(query).context("serliaze query")?;
-        let query = bytes::Bytes::from(query);
+        self.arrow_finalized_query(query)
+            .await
+            .map(|res| res.map(|(res, _)| res))
+    }
 
-        let response = self.finalized_query(query).await.context("execute query")?;
-        let response = match response {
-            Some(r) => r,
-            None => return Ok(None),
-        };
+    /// Returns the first block that has a timestamp at or after the given unix timestamp in seconds,
+    /// or `None` if the portal hasn't finalized such a block yet.
+    ///
+    /// Binary searches the portal with minimal queries, blocks seen during the search are cached
+    /// to speed up later calls.
+    pub async fn svm_block_at_timestamp(&self, timestamp: u64) -> Result<Option<u64>> {
+        self.block_at_timestamp::<svm::Query>(timestamp).await
+    }
 
-        let mut parser = svm::ArrowResponseParser::default();
+    /// Sets `from_block` and `to_block` of the query so it covers the blocks with timestamps
+    /// in the given range.
+    ///
+    /// `to_block` is set to `None` if the end of the range is after the finalized head,
+    /// `StreamConfig::stop_at_timestamp` can be used to end a stream at the right block in that case.
+    /// Errors if no block has a timestamp in the range.
+    pub async fn svm_query_for_time_range(
+        &self,
+        query: svm::Query,
+        time_range: Range<u64>,
+    ) -> Result<svm::Query> {
+        self.query_for_time_range(query, time_range).await
+    }
 
-        let lines = response.split(|x| *x == b'\n');
-        let mut scratch = Vec::new();
+    /// Spawns a tokio task that runs the query and prefetches up to `StreamConfig::buffer_size`
+    /// responses into the returned channel.
+    pub fn svm_arrow_finalized_stream(
+        self: Arc<Self>,
+        query: svm::Query,
+        config: StreamConfig,
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<svm::ArrowResponse>>>,
+        StreamHandle,
+    ) {
+        stream::spawn_stream(self, query, config, None)
+    }
 
-        for line in lines {
-            if line.is_empty() {
-                continue;
-            }
+    /// Same as `svm_arrow_finalized_stream` but ends the stream with `StreamEnd::StopConditionMet`
+    /// after the first response `pred` returns true for, that response is sent as well.
+    pub fn svm_arrow_finalized_stream_until(
+        self: Arc<Self>,
+        query: svm::Query,
+        config: StreamConfig,
+        pred: impl FnMut(&svm::ArrowResponse) -> bool + Send + 'static,
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<svm::ArrowResponse>>>,
+        StreamHandle,
+    ) {
+        stream::spawn_stream(self, query, config, Some(Box::new(pred)))
+    }
 
-            scratch.extend_from_slice(line);
-            let tape = simd_json::to_tape(&mut scratch).context("json to tape")?;
-            parser.parse_tape(&tape).context("parse tape")?;
-            scratch.clear();
-        }
+    /// Streams the query without spawning a task. The portal is polled when the stream is polled
+    /// so backpressure comes from the consumer.
+    ///
+    /// `StreamConfig::buffer_size` is ignored since nothing is prefetched,
+    /// use `svm_arrow_finalized_stream` to prefetch responses in a background task.
+    pub fn svm_arrow_finalized_lazy_stream(
+        &self,
+        query: svm::Query,
+        config: StreamConfig,
+    ) -> impl Stream<Item = Result<StreamItem<svm::ArrowResponse>>> + Send + '_ {
+        stream::lazy_stream(self, query, config)
+    }
 
-        Ok(Some(parser.finish()))
+    /// Runs the queries as a single combined query and sends each receiver the rows that match
+    /// the query at the same position, so blocks that are shared by the queries are only
+    /// downloaded once.
+    ///
+    /// The responses contain the union of the fields selected by the queries. `StreamConfig`
+    /// applies to the combined stream and a slow receiver holds back every other receiver.
+    pub fn svm_arrow_finalized_multi_stream(
+        self: Arc<Self>,
+        queries: Vec<svm::Query>,
+        config: StreamConfig,
+    ) -> Result<(Vec<StreamReceiver<svm::ArrowResponse>>, StreamHandle)> {
+        mux::spawn_multi_stream(self, queries, config)
     }
 
-    pub fn svm_arrow_finalized_stream(
+    /// Same as `svm_arrow_finalized_lazy_stream` but also yields a `StreamEvent::Idle` every time
+    /// the stream polls the portal while waiting for new blocks.
+    pub fn svm_arrow_finalized_event_stream(
+        &self,
+        query: svm::Query,
+        config: StreamConfig,
+    ) -> impl Stream<Item = Result<StreamEvent<svm::ArrowResponse>>> + Send + '_ {
+        stream::event_stream(self, query, config)
+    }
+
+    /// Same as `svm_arrow_finalized_query` but deserializes the response into typed blocks
+    /// instead of building Arrow tables.
+    pub async fn svm_rows_finalized_query(
+        &self,
+        query: &svm::Query,
+    ) -> Result<Option<Vec<svm::BlockData>>> {
+        self.arrow_finalized_query(&Rows::new(query.clone()))
+            .await
+            .map(|res| res.map(|(res, _)| res))
+    }
+
+    /// Same as `svm_arrow_finalized_stream` but yields typed blocks.
+    pub fn svm_rows_finalized_stream(
         self: Arc<Self>,
         query: svm::Query,
         config: StreamConfig,
-    ) -> mpsc::Receiver<Result<svm::ArrowResponse>> {
-        let (tx, rx) = mpsc::channel(config.buffer_size);
-
-        let mut query = query;
-        // we need this to iterate
-        query.fields.block.number = true;
-
-        tokio::spawn(async move {
-            loop {
-                if let Some(tb) = query.to_block {
-                    if tb < query.from_block {
-                        break;
-                    }
-                }
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<Vec<svm::BlockData>>>>,
+        StreamHandle,
+    ) {
+        stream::spawn_stream(self, Rows::new(query), config, None)
+    }
 
-                let res = match self
-                    .svm_arrow_finalized_query(&query)
-                    .await
-                    .context("run query")
-                {
-                    Ok(r) => r,
-                    Err(e) => {
-                        tx.send(Err(e)).await.ok();
-                        return;
-                    }
-                };
-                let res = match res {
-                    Some(r) => r,
-                    None => {
-                        if config.stop_on_head {
-                            break;
-                        }
-                        tokio::time::sleep(Duration::from_millis(config.head_poll_interval_millis))
-                            .await;
-                        log::debug!("waiting for block {}", query.from_block);
-                        continue;
-                    }
-                };
-
-                let next_block = match res.next_block().context("get next block from response") {
-                    Ok(nb) => nb,
-                    Err(e) => {
-                        tx.send(Err(e)).await.ok();
-                        return;
-                    }
-                };
-
-                query.from_block = next_block;
-
-                if tx.send(Ok(res)).await.is_err() {
-                    log::debug!("receiver is closed so quitting stream");
-                    return;
-                }
-            }
-        });
+    /// Same as `svm_arrow_finalized_lazy_stream` but yields typed blocks.
+    pub fn svm_rows_finalized_lazy_stream(
+        &self,
+        query: svm::Query,
+        config: StreamConfig,
+    ) -> impl Stream<Item = Result<StreamItem<Vec<svm::BlockData>>>> + Send + '_ {
+        stream::lazy_stream(self, Rows::new(query), config)
+    }
+
+    /// Runs the query and returns the response as the portal sent it.
+    ///
+    /// The query isn't split by `ClientConfig::max_filter_values`.
+    pub async fn svm_raw_finalized_query(&self, query: &svm::Query) -> Result<Option<RawResponse>> {
+        self.arrow_finalized_query(&Raw::new(query.clone()))
+            .await
+            .map(|res| res.map(|(res, _)| res))
+    }
 
-        rx
+    /// Same as `svm_arrow_finalized_stream` but yields the responses as the portal sent them.
+    ///
+    /// The query isn't split by `ClientConfig::max_filter_values`.
+    pub fn svm_raw_finalized_stream(
+        self: Arc<Self>,
+        query: svm::Query,
+        config: StreamConfig,
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<RawResponse>>>,
+        StreamHandle,
+    ) {
+        stream::spawn_stream(self, Raw::new(query), config, None)
+    }
+
+    /// Same as `svm_arrow_finalized_lazy_stream` but yields the responses as the portal sent them.
+    ///
+    /// The query isn't split by `ClientConfig::max_filter_values`.
+    pub fn svm_raw_finalized_lazy_stream(
+        &self,
+        query: svm::Query,
+        config: StreamConfig,
+    ) -> impl Stream<Item = Result<StreamItem<RawResponse>>> + Send + '_ {
+        stream::lazy_stream(self, Raw::new(query), config)
+    }
+
+    /// Same as `svm_arrow_finalized_lazy_stream` but also writes the lines of every response
+    /// into `out`, e.g. a file, before parsing them.
+    ///
+    /// Writes are blocking. The query isn't split by `ClientConfig::max_filter_values`.
+    pub fn svm_arrow_finalized_tee_stream<'a, W: std::io::Write + Send + 'a>(
+        &'a self,
+        query: svm::Query,
+        config: StreamConfig,
+        out: W,
+    ) -> impl Stream<Item = Result<StreamItem<svm::ArrowResponse>>> + Send + 'a {
+        raw::tee_stream(self, query, config, out)
     }
 
     pub async fn evm_arrow_finalized_query(
         &self,
         query: &evm::Query,
     ) -> Result<Option<evm::ArrowResponse>> {
-        let query = simd_json::to_vec(query).context("serialize query")?;
-        let query = bytes::Bytes::from(query);
+        self.arrow_finalized_query(query)
+            .await
+            .map(|res| res.map(|(res, _)| res))
+    }
 
-        let response = self.finalized_query(query).await.context("execute query")?;
-        let response = match response {
-            Some(r) => r,
-            None => return Ok(None),
-        };
+    /// Returns the first block that has a timestamp at or after the given unix timestamp in seconds,
+    /// or `None` if the portal hasn't finalized such a block yet.
+    ///
+    /// Binary searches the portal with minimal queries, blocks seen during the search are cached
+    /// to speed up later calls.
+    pub async fn evm_block_at_timestamp(&self, timestamp: u64) -> Result<Option<u64>> {
+        self.block_at_timestamp::<evm::Query>(timestamp).await
+    }
 
-        let mut parser = evm::ArrowResponseParser::default();
+    /// Sets `from_block` and `to_block` of the query so it covers the blocks with timestamps
+    /// in the given range.
+    ///
+    /// `to_block` is set to `None` if the end of the range is after the finalized head,
+    /// `StreamConfig::stop_at_timestamp` can be used to end a stream at the right block in that case.
+    /// Errors if no block has a timestamp in the range.
+    pub async fn evm_query_for_time_range(
+        &self,
+        query: evm::Query,
+        time_range: Range<u64>,
+    ) -> Result<evm::Query> {
+        self.query_for_time_range(query, time_range).await
+    }
 
-        let lines = response.split(|x| *x == b'\n');
-        let mut scratch = Vec::new();
+    /// Spawns a tokio task that runs the query and prefetches up to `StreamConfig::buffer_size`
+    /// responses into the returned channel.
+    pub fn evm_arrow_finalized_stream(
+        self: Arc<Self>,
+        query: evm::Query,
+        config: StreamConfig,
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<evm::ArrowResponse>>>,
+        StreamHandle,
+    ) {
+        stream::spawn_stream(self, query, config, None)
+    }
 
-        for line in lines {
-            if line.is_empty() {
-                continue;
-            }
+    /// Same as `evm_arrow_finalized_stream` but ends the stream with `StreamEnd::StopConditionMet`
+    /// after the first response `pred` returns true for, that response is sent as well.
+    pub fn evm_arrow_finalized_stream_until(
+        self: Arc<Self>,
+        query: evm::Query,
+        config: StreamConfig,
+        pred: impl FnMut(&evm::ArrowResponse) -> bool + Send + 'static,
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<evm::ArrowResponse>>>,
+        StreamHandle,
+    ) {
+        stream::spawn_stream(self, query, config, Some(Box::new(pred)))
+    }
 
-            scratch.extend_from_slice(line);
-            let tape = simd_json::to_tape(&mut scratch).context("json to tape")?;
-            parser.parse_tape(&tape).context("parse tape")?;
-            scratch.clear();
-        }
+    /// Streams the query without spawning a task. The portal is polled when the stream is polled
+    /// so backpressure comes from the consumer.
+    ///
+    /// `StreamConfig::buffer_size` is ignored since nothing is prefetched,
+    /// use `evm_arrow_finalized_stream` to prefetch responses in a background task.
+    pub fn evm_arrow_finalized_lazy_stream(
+        &self,
+        query: evm::Query,
+        config: StreamConfig,
+    ) -> impl Stream<Item = Result<StreamItem<evm::ArrowResponse>>> + Send + '_ {
+        stream::lazy_stream(self, query, config)
+    }
 
-        Ok(Some(parser.finish()))
+    /// Runs the queries as a single combined query and sends each receiver the rows that match
+    /// the query at the same position, so blocks that are shared by the queries are only
+    /// downloaded once.
+    ///
+    /// The responses contain the union of the fields selected by the queries. `StreamConfig`
+    /// applies to the combined stream and a slow receiver holds back every other receiver.
+    pub fn evm_arrow_finalized_multi_stream(
+        self: Arc<Self>,
+        queries: Vec<evm::Query>,
+        config: StreamConfig,
+    ) -> Result<(Vec<StreamReceiver<evm::ArrowResponse>>, StreamHandle)> {
+        mux::spawn_multi_stream(self, queries, config)
     }
 
-    pub fn evm_arrow_finalized_stream(
+    /// Same as `evm_arrow_finalized_lazy_stream` but also yields a `StreamEvent::Idle` every time
+    /// the stream polls the portal while waiting for new blocks.
+    pub fn evm_arrow_finalized_event_stream(
+        &self,
+        query: evm::Query,
+        config: StreamConfig,
+    ) -> impl Stream<Item = Result<StreamEvent<evm::ArrowResponse>>> + Send + '_ {
+        stream::event_stream(self, query, config)
+    }
+
+    /// Same as `evm_arrow_finalized_query` but deserializes the response into typed blocks
+    /// instead of building Arrow tables.
+    pub async fn evm_rows_finalized_query(
+        &self,
+        query: &evm::Query,
+    ) -> Result<Option<Vec<evm::BlockData>>> {
+        self.arrow_finalized_query(&Rows::new(query.clone()))
+            .await
+            .map(|res| res.map(|(res, _)| res))
+    }
+
+    /// Same as `evm_arrow_finalized_stream` but yields typed blocks.
+    pub fn evm_rows_finalized_stream(
         self: Arc<Self>,
         query: evm::Query,
         config: StreamConfig,
-    ) -> mpsc::Receiver<Result<evm::ArrowResponse>> {
-        let (tx, rx) = mpsc::channel(config.buffer_size);
-
-        let mut query = query;
-        // we need this to iterate
-        query.fields.block.number = true;
-
-        tokio::spawn(async move {
-            loop {
-                if let Some(tb) = query.to_block {
-                    if tb < query.from_block {
-                        break;
-                    }
-                }
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<Vec<evm::BlockData>>>>,
+        StreamHandle,
+    ) {
+        stream::spawn_stream(self, Rows::new(query), config, None)
+    }
 
-                let res = match self
-                    .evm_arrow_finalized_query(&query)
-                    .await
-                    .context("run query")
-                {
-                    Ok(r) => r,
-                    Err(e) => {
-                        tx.send(Err(e)).await.ok();
-                        return;
-                    }
-                };
-                let res = match res {
-                    Some(r) => r,
-                    None => {
-                        if config.stop_on_head {
-                            break;
-                        }
-                        tokio::time::sleep(Duration::from_millis(config.head_poll_interval_millis))
-                            .await;
-                        log::debug!("waiting for block {}", query.from_block);
-                        continue;
-                    }
-                };
-
-                let next_block = match res.next_block().context("get next block from response") {
-                    Ok(nb) => nb,
-                    Err(e) => {
-                        tx.send(Err(e)).await.ok();
-                        return;
-                    }
-                };
-
-                query.from_block = next_block;
-
-                if tx.send(Ok(res)).await.is_err() {
-                    log::debug!("receiver is closed so quitting stream");
-                    return;
-                }
-            }
-        });
+    /// Same as `evm_arrow_finalized_lazy_stream` but yields typed blocks.
+    pub fn evm_rows_finalized_lazy_stream(
+        &self,
+        query: evm::Query,
+        config: StreamConfig,
+    ) -> impl Stream<Item = Result<StreamItem<Vec<evm::BlockData>>>> + Send + '_ {
+        stream::lazy_stream(self, Rows::new(query), config)
+    }
 
-        rx
+    /// Runs the query and returns the response as the portal sent it.
+    ///
+    /// The query isn't split by `ClientConfig::max_filter_values`.
+    pub async fn evm_raw_finalized_query(&self, query: &evm::Query) -> Result<Option<RawResponse>> {
+        self.arrow_finalized_query(&Raw::new(query.clone()))
+            .await
+            .map(|res| res.map(|(res, _)| res))
     }
 
-    pub async fn finalized_height(&self) -> Result<u64> {
-        let res = self
-            .finalized_req(Method::GET, &["finalized-stream", "height"], None)
+    /// Same as `evm_arrow_finalized_stream` but yields the responses as the portal sent them.
+    ///
+    /// The query isn't split by `ClientConfig::max_filter_values`.
+    pub fn evm_raw_finalized_stream(
+        self: Arc<Self>,
+        query: evm::Query,
+        config: StreamConfig,
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<RawResponse>>>,
+        StreamHandle,
+    ) {
+        stream::spawn_stream(self, Raw::new(query), config, None)
+    }
+
+    /// Same as `evm_arrow_finalized_lazy_stream` but yields the responses as the portal sent them.
+    ///
+    /// The query isn't split by `ClientConfig::max_filter_values`.
+    pub fn evm_raw_finalized_lazy_stream(
+        &self,
+        query: evm::Query,
+        config: StreamConfig,
+    ) -> impl Stream<Item = Result<StreamItem<RawResponse>>> + Send + '_ {
+        stream::lazy_stream(self, Raw::new(query), config)
+    }
+
+    /// Same as `evm_arrow_finalized_lazy_stream` but also writes the lines of every response
+    /// into `out`, e.g. a file, before parsing them.
+    ///
+    /// Writes are blocking. The query isn't split by `ClientConfig::max_filter_values`.
+    pub fn evm_arrow_finalized_tee_stream<'a, W: std::io::Write + Send + 'a>(
+        &'a self,
+        query: evm::Query,
+        config: StreamConfig,
+        out: W,
+    ) -> impl Stream<Item = Result<StreamItem<evm::ArrowResponse>>> + Send + 'a {
+        raw::tee_stream(self, query, config, out)
+    }
+
+    /// Runs the query and returns the parsed response along with the size of the response body.
+    ///
+    /// Queries with more than `ClientConfig::max_filter_values` filter values are split. The parts
+    /// are run one after the other, each up to the block the previous parts reached, and the
+    /// responses are merged.
+    pub(crate) async fn arrow_finalized_query<Q: PortalQuery>(
+        &self,
+        query: &Q,
+    ) -> Result<Option<(Q::Response, usize)>> {
+        let parts = query.split(self.max_filter_values);
+        if parts.len() <= 1 {
+            return self.arrow_finalized_query_impl(query).await;
+        }
+
+        let mut responses = Vec::with_capacity(parts.len());
+        let mut num_bytes = 0;
+        let mut to_block = query.to_block();
+
+        for (i, mut part) in parts.into_iter().enumerate() {
+            part.set_to_block(to_block);
+            let (res, part_bytes) = match self
+                .arrow_finalized_query_impl(&part)
+                .await
+                .with_context(|| format!("run part {} of split query", i))?
+            {
+                Some(res) => res,
+                None => return Ok(None),
+            };
+            let last_block = Q::next_block(&res).context("get next block")? - 1;
+            to_block = Some(to_block.map_or(last_block, |b| b.min(last_block)));
+            num_bytes += part_bytes;
+            responses.push(res);
+        }
+
+        // the parts that ran first can end after the parts that ran later
+        let to_block = to_block.context("no parts were run")?;
+        let responses = responses
+            .iter()
+            .map(|res| Q::truncate(res, to_block))
+            .collect::<Result<Vec<_>>>()
+            .context("truncate responses")?;
+        let res = Q::merge(&responses).context("merge responses")?;
+
+        Ok(Some((res, num_bytes)))
+    }
+
+    async fn arrow_finalized_query_impl<Q: PortalQuery>(
+        &self,
+        query: &Q,
+    ) -> Result<Option<(Q::Response, usize)>> {
+        let from_block = query.cursor();
+        let span = telemetry::request_span(&self.url, Some(from_block));
+
+        let body = query.to_json().context("serialize query")?;
+        let body = bytes::Bytes::from(body);
+
+        #[cfg(feature = "cache")]
+        let response = self.cached_finalized_query(query, body);
+        #[cfg(not(feature = "cache"))]
+        let response = self.finalized_query(body);
+
+        let response = telemetry::instrument(response, span)
             .await
-            .context("make req")?
-            .context("no response data")?;
+            .context("execute query")?;
+        let response = match response {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+
+        let res = Q::parse_response(&response).context("parse response")?;
+        let to_block = Q::next_block(&res).ok().map(|nb| nb - 1);
+        telemetry::record_response(&self.url, from_block, to_block, &Q::row_counts(&res));
+
+        Ok(Some((res, response.len())))
+    }
+
+    pub async fn finalized_height(&self) -> Result<u64> {
+        let span = telemetry::request_span(&self.url, None);
+        let res = telemetry::instrument(
+            self.finalized_req(Method::GET, &["finalized-stream", "height"], None),
+            span,
+        )
+        .await
+        .context("make req")?
+        .context("no response data")?;
 
         let height = std::str::from_utf8(&res).context("check body is utf8")?;
         let height = u64::from_str(height).context("parse height as number")?;
@@ -298,12 +618,22 @@ impl Client {
 
         let mut err = anyhow!("");
 
-        for _ in 0..self.max_num_retries + 1 {
+        let start = Instant::now();
+
+        for i in 0..self.max_num_retries + 1 {
+            if i > 0 {
+                telemetry::record_retry(&self.url);
+            }
+
             match self
                 .finalized_req_impl(method.clone(), url_segments, body.clone())
                 .await
             {
-                Ok(res) => return Ok(res),
+                Ok(res) => {
+                    let response_bytes = res.as_ref().map(|r| r.len());
+                    telemetry::record_request(&self.url, start.elapsed(), response_bytes);
+                    return Ok(res);
+                }
                 Err(e) => {
                     log::error!(
                         "failed to get data from server, retrying... The error was: {:?}",
@@ -369,6 +699,76 @@ impl Client {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures_lite::StreamExt;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_stream_interrupts_retries() {
+        let url = "http://127.0.0.1:1".parse().unwrap();
+        let client = Client::new(
+            url,
+            ClientConfig {
+                retry_base_ms: 60_000,
+                retry_ceiling_ms: 60_000,
+                ..Default::default()
+            },
+        );
+
+        let client = Arc::new(client);
+
+        let (mut receiver, handle) =
+            client.evm_arrow_finalized_stream(evm::Query::default(), StreamConfig::default());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        handle.cancel();
+
+        let end = tokio::time::timeout(Duration::from_secs(5), handle.join())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(end, StreamEnd::Cancelled);
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn lazy_stream_ends_on_empty_range() {
+        let url = "http://127.0.0.1:1".parse().unwrap();
+        let client = Client::new(url, ClientConfig::default());
+
+        let query = evm::Query {
+            from_block: 10,
+            to_block: Some(9),
+            ..Default::default()
+        };
+
+        let stream = client.evm_arrow_finalized_lazy_stream(query, StreamConfig::default());
+        futures_lite::pin!(stream);
+
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn lazy_stream_svm() {
+        let url = "https://portal.sqd.dev/datasets/solana-beta"
+            .parse()
+            .unwrap();
+        let client = Client::new(url, ClientConfig::default());
+
+        let query = svm::Query {
+            from_block: 317617480,
+            to_block: Some(317617500),
+            include_all_blocks: true,
+            ..Default::default()
+        };
+
+        let stream = client.svm_arrow_finalized_lazy_stream(query, StreamConfig::default());
+        futures_lite::pin!(stream);
+
+        while let Some(arrow_data) = stream.next().await {
+            let arrow_data = arrow_data.unwrap().data;
+            dbg!(arrow_data.blocks.num_rows());
+        }
+    }
 
     #[tokio::test(flavor = "multi_thread")]
     #[ignore]
@@ -396,10 +796,11 @@ mod tests {
 
         let client = Arc::new(client);
 
-        let mut receiver = client.evm_arrow_finalized_stream(query, StreamConfig::default());
+        let (mut receiver, _handle) =
+            client.evm_arrow_finalized_stream(query, StreamConfig::default());
 
         while let Some(arrow_data) = receiver.recv().await {
-            let arrow_data = arrow_data.unwrap();
+            let arrow_data = arrow_data.unwrap().data;
             let block_num = arrow_data
                 .blocks
                 .column_by_name("number")
@@ -444,10 +845,11 @@ mod tests {
 
         let client = Arc::new(client);
 
-        let mut receiver = client.svm_arrow_finalized_stream(query, StreamConfig::default());
+        let (mut receiver, _handle) =
+            client.svm_arrow_finalized_stream(query, StreamConfig::default());
 
         while let Some(arrow_data) = receiver.recv().await {
-            let arrow_data = arrow_data.unwrap();
+            let arrow_data = arrow_data.unwrap().data;
             let tx_hash = arrow_data
                 .transactions
                 .column_by_name("block_slot")
@@ -489,10 +891,11 @@ mod tests {
 
         let client = Arc::new(client);
 
-        let mut receiver = client.evm_arrow_finalized_stream(query, StreamConfig::default());
+        let (mut receiver, _handle) =
+            client.evm_arrow_finalized_stream(query, StreamConfig::default());
 
         while let Some(arrow_data) = receiver.recv().await {
-            let arrow_data = arrow_data.unwrap();
+            let arrow_data = arrow_data.unwrap().data;
             let tx_hash = arrow_data
                 .transactions
                 .column_by_name("value")
@@ -536,10 +939,11 @@ mod tests {
 
         let client = Arc::new(client);
 
-        let mut receiver = client.evm_arrow_finalized_stream(query, StreamConfig::default());
+        let (mut receiver, _handle) =
+            client.evm_arrow_finalized_stream(query, StreamConfig::default());
 
         while let Some(arrow_data) = receiver.recv().await {
-            let arrow_data = arrow_data.unwrap();
+            let arrow_data = arrow_data.unwrap().data;
 
             let tx_hash = arrow_data
                 .traces
@@ -588,7 +992,8 @@ mod tests {
 
         let client = Arc::new(client);
 
-        let mut receiver = client.evm_arrow_finalized_stream(query, StreamConfig::default());
+        let (mut receiver, _handle) =
+            client.evm_arrow_finalized_stream(query, StreamConfig::default());
 
         while let Some(_arrow_data) = receiver.recv().await {
             // let arrow_data = arrow_data.unwrap();
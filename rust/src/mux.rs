@@ -0,0 +1,515 @@
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use arrow::array::{Array, AsArray, BooleanArray, ListArray, UInt64Array};
+use arrow::compute::{cast, filter_record_batch};
+use arrow::datatypes::{DataType, Field, UInt64Type};
+use arrow::record_batch::RecordBatch;
+use futures_lite::StreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+use simd_json::{OwnedValue, StaticNode};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::source::SourceQuery;
+use crate::stream::{events, Event};
+use crate::{Client, StreamConfig, StreamEnd, StreamHandle, StreamItem, StreamReceiver};
+
+/// Rows of a table that are kept, one value per row.
+pub(crate) type Mask = Vec<bool>;
+
+/// Block and transaction index of a row.
+pub(crate) type TxKey = (u64, u64);
+
+/// Block, transaction index and address of a trace or an instruction.
+pub(crate) type ItemKey = (u64, u64, Vec<u64>);
+
+type Sender<R> = mpsc::Sender<Result<StreamItem<R>>>;
+
+/// Returns a copy of the fields where every field that is selected in any of the inputs
+/// is selected.
+pub(crate) fn union_fields<'a, F: Serialize + DeserializeOwned + 'a>(
+    fields: impl Iterator<Item = &'a F>,
+) -> Result<F> {
+    let mut out: Option<OwnedValue> = None;
+    for f in fields {
+        let value = simd_json::serde::to_owned_value(f).context("fields to json")?;
+        match out.as_mut() {
+            Some(out) => or_values(out, &value),
+            None => out = Some(value),
+        }
+    }
+    let out = out.context("no fields to combine")?;
+    simd_json::serde::from_owned_value(out).context("fields from json")
+}
+
+fn or_values(out: &mut OwnedValue, value: &OwnedValue) {
+    match (out, value) {
+        (OwnedValue::Static(StaticNode::Bool(out)), OwnedValue::Static(StaticNode::Bool(v))) => {
+            *out |= *v
+        }
+        (OwnedValue::Object(out), OwnedValue::Object(value)) => {
+            for (k, v) in value.iter() {
+                if let Some(out) = out.get_mut(k) {
+                    or_values(out, v);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+pub(crate) fn and(mask: &mut Mask, other: &[bool]) {
+    for (m, o) in mask.iter_mut().zip(other.iter()) {
+        *m &= *o;
+    }
+}
+
+pub(crate) fn or(mask: &mut Mask, other: &[bool]) {
+    for (m, o) in mask.iter_mut().zip(other.iter()) {
+        *m |= *o;
+    }
+}
+
+pub(crate) fn filter(batch: &RecordBatch, mask: Mask) -> Result<RecordBatch> {
+    filter_record_batch(batch, &BooleanArray::from(mask)).context("filter record batch")
+}
+
+/// Reads an integer column as u64.
+pub(crate) fn u64_values(batch: &RecordBatch, column: &str) -> Result<Vec<Option<u64>>> {
+    let col = batch
+        .column_by_name(column)
+        .with_context(|| format!("get {} col", column))?;
+    let col = cast(col, &DataType::UInt64).with_context(|| format!("cast {} to u64", column))?;
+    Ok(col.as_primitive::<UInt64Type>().iter().collect())
+}
+
+/// Reads a list of integers column as lists of u64.
+pub(crate) fn list_u64_values(batch: &RecordBatch, column: &str) -> Result<Vec<Option<Vec<u64>>>> {
+    let col = batch
+        .column_by_name(column)
+        .with_context(|| format!("get {} col", column))?;
+    let dt = DataType::List(Arc::new(Field::new("item", DataType::UInt64, true)));
+    let col = cast(col, &dt).with_context(|| format!("cast {} to list of u64", column))?;
+    Ok(col
+        .as_list::<i32>()
+        .iter()
+        .map(|v| {
+            v.map(|v| {
+                let v = v.as_any().downcast_ref::<UInt64Array>().unwrap();
+                v.iter().map(|x| x.unwrap_or_default()).collect()
+            })
+        })
+        .collect())
+}
+
+pub(crate) fn tx_keys(batch: &RecordBatch, block: &str, tx: &str) -> Result<Vec<Option<TxKey>>> {
+    let blocks = u64_values(batch, block)?;
+    let txs = u64_values(batch, tx)?;
+    Ok(blocks
+        .into_iter()
+        .zip(txs)
+        .map(|(b, t)| Some((b?, t?)))
+        .collect())
+}
+
+pub(crate) fn item_keys(
+    batch: &RecordBatch,
+    block: &str,
+    tx: &str,
+    address: &str,
+) -> Result<Vec<Option<ItemKey>>> {
+    let txs = tx_keys(batch, block, tx)?;
+    let addresses = list_u64_values(batch, address)?;
+    Ok(txs
+        .into_iter()
+        .zip(addresses)
+        .map(|(tx, a)| {
+            let (b, t) = tx?;
+            Some((b, t, a?))
+        })
+        .collect())
+}
+
+/// Indices and keys of the rows that match.
+pub(crate) fn matched<'a, K: Clone>(
+    mask: &'a [bool],
+    keys: &'a [Option<K>],
+) -> impl Iterator<Item = (usize, K)> + 'a {
+    mask.iter()
+        .zip(keys.iter())
+        .enumerate()
+        .filter(|(_, (m, _))| **m)
+        .filter_map(|(i, (_, k))| Some((i, k.clone()?)))
+}
+
+/// Transaction keys of traces or instructions.
+pub(crate) fn tx_of(keys: &[Option<ItemKey>]) -> Vec<Option<TxKey>> {
+    keys.iter()
+        .map(|k| k.as_ref().map(|(b, t, _)| (*b, *t)))
+        .collect()
+}
+
+/// Rows that have one of the keys.
+pub(crate) fn key_in<K: Eq + Hash>(keys: &[Option<K>], set: &HashSet<K>) -> Mask {
+    keys.iter()
+        .map(|k| k.as_ref().is_some_and(|k| set.contains(k)))
+        .collect()
+}
+
+/// Rows of items that have one of the items in `set` as an ancestor.
+pub(crate) fn descendant_of(keys: &[Option<ItemKey>], set: &HashSet<ItemKey>) -> Mask {
+    keys.iter()
+        .map(|k| match k {
+            Some((b, t, address)) => {
+                (0..address.len()).any(|l| set.contains(&(*b, *t, address[..l].to_vec())))
+            }
+            None => false,
+        })
+        .collect()
+}
+
+/// Returns the ancestors of the item, which can be matched against with `key_in`.
+pub(crate) fn ancestors(key: &ItemKey) -> impl Iterator<Item = ItemKey> + '_ {
+    let (b, t, address) = key;
+    (0..address.len()).map(move |l| (*b, *t, address[..l].to_vec()))
+}
+
+pub(crate) fn in_block_range(
+    batch: &RecordBatch,
+    column: &str,
+    from_block: u64,
+    to_block: Option<u64>,
+) -> Result<Mask> {
+    Ok(u64_values(batch, column)?
+        .into_iter()
+        .map(|n| n.is_some_and(|n| n >= from_block && to_block.is_none_or(|to| n <= to)))
+        .collect())
+}
+
+/// Rows where the binary column is one of the values, every row matches if there are no values.
+pub(crate) fn binary_in(batch: &RecordBatch, column: &str, values: &[Vec<u8>]) -> Result<Mask> {
+    binary_matches(batch, column, values, |v| v)
+}
+
+/// Rows where the binary column starts with one of the values.
+///
+/// All values are expected to have the same length.
+pub(crate) fn binary_prefix_in(
+    batch: &RecordBatch,
+    column: &str,
+    values: &[Vec<u8>],
+) -> Result<Mask> {
+    let len = values.first().map(|v| v.len()).unwrap_or_default();
+    binary_matches(batch, column, values, |v| v.get(..len).unwrap_or(v))
+}
+
+fn binary_matches(
+    batch: &RecordBatch,
+    column: &str,
+    values: &[Vec<u8>],
+    key: impl Fn(&[u8]) -> &[u8],
+) -> Result<Mask> {
+    if values.is_empty() {
+        return Ok(vec![true; batch.num_rows()]);
+    }
+    let set = values.iter().map(|v| v.as_slice()).collect::<HashSet<_>>();
+    let col = batch
+        .column_by_name(column)
+        .with_context(|| format!("get {} col", column))?
+        .as_binary_opt::<i32>()
+        .with_context(|| format!("get {} col as binary", column))?;
+    Ok(col
+        .iter()
+        .map(|v| v.is_some_and(|v| set.contains(key(v))))
+        .collect())
+}
+
+/// Rows where the string column is one of the values, every row matches if there are no values.
+pub(crate) fn utf8_in(batch: &RecordBatch, column: &str, values: &[String]) -> Result<Mask> {
+    if values.is_empty() {
+        return Ok(vec![true; batch.num_rows()]);
+    }
+    let set = values.iter().map(|v| v.as_str()).collect::<HashSet<_>>();
+    let col = batch
+        .column_by_name(column)
+        .with_context(|| format!("get {} col", column))?
+        .as_string_opt::<i32>()
+        .with_context(|| format!("get {} col as string", column))?;
+    Ok(col
+        .iter()
+        .map(|v| v.is_some_and(|v| set.contains(v)))
+        .collect())
+}
+
+fn binary_lists<'a>(batch: &'a RecordBatch, column: &str) -> Result<&'a ListArray> {
+    batch
+        .column_by_name(column)
+        .with_context(|| format!("get {} col", column))?
+        .as_list_opt::<i32>()
+        .with_context(|| format!("get {} col as list", column))
+}
+
+/// Rows where the list of binary values contains one of the values.
+///
+/// Every row matches if there are no values.
+pub(crate) fn list_contains(batch: &RecordBatch, column: &str, values: &[Vec<u8>]) -> Result<Mask> {
+    if values.is_empty() {
+        return Ok(vec![true; batch.num_rows()]);
+    }
+    let set = values.iter().map(|v| v.as_slice()).collect::<HashSet<_>>();
+    binary_lists(batch, column)?
+        .iter()
+        .map(|list| match list {
+            Some(list) => {
+                let list = list.as_binary_opt::<i32>().context("list item as binary")?;
+                Ok(list.iter().any(|v| v.is_some_and(|v| set.contains(v))))
+            }
+            None => Ok(false),
+        })
+        .collect()
+}
+
+/// Rows where the value at `index` of the list of binary values is one of the values.
+///
+/// Every row matches if there are no values.
+pub(crate) fn list_item_in(
+    batch: &RecordBatch,
+    column: &str,
+    index: usize,
+    values: &[Vec<u8>],
+) -> Result<Mask> {
+    if values.is_empty() {
+        return Ok(vec![true; batch.num_rows()]);
+    }
+    let set = values.iter().map(|v| v.as_slice()).collect::<HashSet<_>>();
+    binary_lists(batch, column)?
+        .iter()
+        .map(|list| match list {
+            Some(list) if index < list.len() => {
+                let list = list.as_binary_opt::<i32>().context("list item as binary")?;
+                Ok(list.is_valid(index) && set.contains(list.value(index)))
+            }
+            _ => Ok(false),
+        })
+        .collect()
+}
+
+/// Rows where the boolean column equals the value, every row matches if the value is `None`.
+pub(crate) fn bool_eq(batch: &RecordBatch, column: &str, value: Option<bool>) -> Result<Mask> {
+    let value = match value {
+        Some(v) => v,
+        None => return Ok(vec![true; batch.num_rows()]),
+    };
+    let col = batch
+        .column_by_name(column)
+        .with_context(|| format!("get {} col", column))?
+        .as_boolean_opt()
+        .with_context(|| format!("get {} col as boolean", column))?;
+    Ok(col.iter().map(|v| v == Some(value)).collect())
+}
+
+/// Keeps the blocks that have rows in the other tables, the last block of the response and
+/// every block in range if `include_all_blocks` is set.
+pub(crate) fn block_mask(
+    blocks: &RecordBatch,
+    column: &str,
+    with_rows: &HashSet<u64>,
+    include_all_blocks: bool,
+) -> Result<Mask> {
+    let numbers = u64_values(blocks, column)?;
+    let last = numbers.last().copied().flatten();
+    Ok(numbers
+        .iter()
+        .map(|n| include_all_blocks || n.is_some_and(|n| with_rows.contains(&n)) || *n == last)
+        .collect())
+}
+
+/// Block numbers of the rows that are kept.
+pub(crate) fn kept_blocks(
+    batch: &RecordBatch,
+    column: &str,
+    mask: &[bool],
+    out: &mut HashSet<u64>,
+) -> Result<()> {
+    let numbers = u64_values(batch, column)?;
+    out.extend(
+        numbers
+            .into_iter()
+            .zip(mask.iter())
+            .filter(|(_, m)| **m)
+            .filter_map(|(n, _)| n),
+    );
+    Ok(())
+}
+
+pub(crate) fn spawn_multi_stream<Q: SourceQuery<Client>>(
+    client: Arc<Client>,
+    queries: Vec<Q>,
+    config: StreamConfig,
+) -> Result<(Vec<StreamReceiver<Q::Response>>, StreamHandle)> {
+    let combined = Q::union(&queries).context("combine queries")?;
+
+    let (senders, receivers) = queries
+        .iter()
+        .map(|_| mpsc::channel(config.buffer_size))
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+    let cancel = CancellationToken::new();
+
+    let task = tokio::spawn(run_multi_stream(
+        client,
+        combined,
+        queries,
+        config,
+        senders,
+        cancel.clone(),
+    ));
+
+    Ok((receivers, StreamHandle::new(cancel, task)))
+}
+
+async fn run_multi_stream<Q: SourceQuery<Client>>(
+    client: Arc<Client>,
+    combined: Q,
+    queries: Vec<Q>,
+    config: StreamConfig,
+    senders: Vec<Sender<Q::Response>>,
+    cancel: CancellationToken,
+) -> Result<StreamEnd> {
+    let mut senders = senders.into_iter().map(Some).collect::<Vec<_>>();
+    let mut receiver_closed = false;
+
+    let events = events(&*client, combined, config, None);
+    futures_lite::pin!(events);
+
+    loop {
+        let ev = tokio::select! {
+            _ = cancel.cancelled() => return Ok(StreamEnd::Cancelled),
+            ev = events.next() => ev,
+        };
+
+        let item = match ev {
+            Some(Ok(Event::Data(item))) => item,
+            Some(Ok(Event::Idle(_))) => continue,
+            Some(Ok(Event::End(end))) => return Ok(end),
+            Some(Err(e)) => return Err(fail_all(&senders, e).await),
+            None => return Err(anyhow!("stream ended without an end event")),
+        };
+
+        for (query, sender) in queries.iter().zip(senders.iter_mut()) {
+            let tx = match sender {
+                Some(tx) => tx,
+                None => continue,
+            };
+            if item.to_block < query.cursor() {
+                continue;
+            }
+
+            let data = match Q::demux(&item.data, query) {
+                Ok(data) => data,
+                Err(e) => {
+                    let e = e.context("demux response");
+                    return Err(fail_all(&senders, e).await);
+                }
+            };
+            let to_block = query
+                .to_block()
+                .map_or(item.to_block, |to| to.min(item.to_block));
+            let sub_item = StreamItem {
+                from_block: item.from_block.max(query.cursor()),
+                to_block,
+                head: item.head,
+                num_bytes: item.num_bytes,
+                duration: item.duration,
+                data,
+            };
+
+            tokio::select! {
+                _ = cancel.cancelled() => return Ok(StreamEnd::Cancelled),
+                res = tx.send(Ok(sub_item)) => {
+                    if res.is_err() {
+                        log::debug!("a receiver of the multi stream is closed");
+                        receiver_closed = true;
+                        *sender = None;
+                        continue;
+                    }
+                }
+            }
+
+            if query.to_block() == Some(to_block) {
+                *sender = None;
+            }
+        }
+
+        if senders.iter().all(Option::is_none) {
+            return Ok(if receiver_closed {
+                StreamEnd::ReceiverClosed
+            } else {
+                StreamEnd::ReachedToBlock
+            });
+        }
+    }
+}
+
+/// Sends a copy of the error to every open receiver and returns the original.
+async fn fail_all<R>(senders: &[Option<Sender<R>>], err: anyhow::Error) -> anyhow::Error {
+    for tx in senders.iter().flatten() {
+        tx.send(Err(anyhow!("{:?}", err))).await.ok();
+    }
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evm;
+
+    #[test]
+    fn union_fields_selects_every_field() {
+        let a = evm::Fields {
+            block: evm::BlockFields {
+                number: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let b = evm::Fields {
+            log: evm::LogFields {
+                address: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let fields = union_fields([&a, &b].into_iter()).unwrap();
+        assert!(fields.block.number);
+        assert!(fields.log.address);
+        assert!(!fields.log.data);
+    }
+
+    #[test]
+    fn descendants_and_ancestors() {
+        let keys = vec![
+            Some((1, 0, vec![])),
+            Some((1, 0, vec![0])),
+            Some((1, 0, vec![0, 1])),
+            Some((1, 0, vec![1])),
+            Some((1, 1, vec![0])),
+            None,
+        ];
+
+        let roots = HashSet::from([(1, 0, vec![0])]);
+        assert_eq!(
+            descendant_of(&keys, &roots),
+            [false, false, true, false, false, false]
+        );
+
+        let parents = ancestors(&(1, 0, vec![0, 1])).collect::<HashSet<_>>();
+        assert_eq!(
+            key_in(&keys, &parents),
+            [true, true, false, false, false, false]
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use anyhow::{Context, Result};
+use arrow::array::UInt64Array;
+use arrow::record_batch::RecordBatch;
+
+use crate::{evm, svm};
+
+/// Common interface over `evm::Query` and `svm::Query` so the request and stream machinery
+/// can be shared between them.
+pub(crate) trait PortalQuery: Clone + Send + Sync + 'static {
+    type Response: Send + 'static;
+
+    fn cursor(&self) -> u64;
+    fn set_cursor(&mut self, cursor: u64);
+    fn to_block(&self) -> Option<u64>;
+    fn set_to_block(&mut self, to_block: Option<u64>);
+    /// Select the fields the stream needs to compute the next block.
+    fn select_stream_fields(&mut self);
+    fn select_timestamp_field(&mut self);
+    /// Query that only selects the number and timestamp of every block in the range.
+    fn timestamp_probe(from_block: u64, to_block: u64) -> Self;
+    fn to_json(&self) -> Result<Vec<u8>>;
+    fn parse_response(data: &bytes::Bytes) -> Result<Self::Response>;
+    fn next_block(res: &Self::Response) -> Result<u64>;
+    /// Number of rows of each table of the response.
+    fn row_counts(res: &Self::Response) -> Vec<(&'static str, usize)>;
+    fn block_timestamps(res: &Self::Response) -> Result<Vec<(u64, u64)>>;
+    fn truncate(res: &Self::Response, to_block: u64) -> Result<Self::Response>;
+    /// Split the query into parts with at most `max_values` filter values each.
+    fn split(&self, max_values: usize) -> Vec<Self>;
+    /// Merge the responses of the parts of a split query, they have to end at the same block.
+    fn merge(responses: &[Self::Response]) -> Result<Self::Response>;
+    /// Combine the queries into one that returns everything any of them returns.
+    fn union(queries: &[Self]) -> Result<Self>;
+    /// Keep the rows of a response to a combined query that match `query`.
+    fn demux(res: &Self::Response, query: &Self) -> Result<Self::Response>;
+}
+
+impl PortalQuery for evm::Query {
+    type Response = evm::ArrowResponse;
+
+    fn cursor(&self) -> u64 {
+        self.from_block
+    }
+
+    fn set_cursor(&mut self, cursor: u64) {
+        self.from_block = cursor;
+    }
+
+    fn to_block(&self) -> Option<u64> {
+        self.to_block
+    }
+
+    fn set_to_block(&mut self, to_block: Option<u64>) {
+        self.to_block = to_block;
+    }
+
+    fn select_stream_fields(&mut self) {
+        self.fields.block.number = true;
+    }
+
+    fn select_timestamp_field(&mut self) {
+        self.fields.block.timestamp = true;
+    }
+
+    fn timestamp_probe(from_block: u64, to_block: u64) -> Self {
+        Self {
+            from_block,
+            to_block: Some(to_block),
+            include_all_blocks: true,
+            fields: evm::Fields {
+                block: evm::BlockFields {
+                    number: true,
+                    timestamp: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn to_json(&self) -> Result<Vec<u8>> {
+        Ok(simd_json::to_vec(self)?)
+    }
+
+    fn parse_response(data: &bytes::Bytes) -> Result<Self::Response> {
+        evm::parse_response(data)
+    }
+
+    fn next_block(res: &Self::Response) -> Result<u64> {
+        res.next_block()
+    }
+
+    fn row_counts(res: &Self::Response) -> Vec<(&'static str, usize)> {
+        res.tables()
+            .iter()
+            .map(|(table, batch)| (*table, batch.num_rows()))
+            .collect()
+    }
+
+    fn block_timestamps(res: &Self::Response) -> Result<Vec<(u64, u64)>> {
+        res.block_timestamps()
+    }
+
+    fn truncate(res: &Self::Response, to_block: u64) -> Result<Self::Response> {
+        res.truncate(to_block)
+    }
+
+    fn split(&self, max_values: usize) -> Vec<Self> {
+        self.split(max_values)
+    }
+
+    fn merge(responses: &[Self::Response]) -> Result<Self::Response> {
+        evm::ArrowResponse::merge(responses)
+    }
+
+    fn union(queries: &[Self]) -> Result<Self> {
+        evm::Query::union(queries)
+    }
+
+    fn demux(res: &Self::Response, query: &Self) -> Result<Self::Response> {
+        res.demux(query)
+    }
+}
+
+impl PortalQuery for svm::Query {
+    type Response = svm::ArrowResponse;
+
+    fn cursor(&self) -> u64 {
+        self.from_block
+    }
+
+    fn set_cursor(&mut self, cursor: u64) {
+        self.from_block = cursor;
+    }
+
+    fn to_block(&self) -> Option<u64> {
+        self.to_block
+    }
+
+    fn set_to_block(&mut self, to_block: Option<u64>) {
+        self.to_block = to_block;
+    }
+
+    fn select_stream_fields(&mut self) {
+        self.fields.block.number = true;
+    }
+
+    fn select_timestamp_field(&mut self) {
+        self.fields.block.timestamp = true;
+    }
+
+    fn timestamp_probe(from_block: u64, to_block: u64) -> Self {
+        Self {
+            from_block,
+            to_block: Some(to_block),
+            include_all_blocks: true,
+            fields: svm::Fields {
+                block: svm::BlockFields {
+                    number: true,
+                    timestamp: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn to_json(&self) -> Result<Vec<u8>> {
+        Ok(simd_json::to_vec(self)?)
+    }
+
+    fn parse_response(data: &bytes::Bytes) -> Result<Self::Response> {
+        svm::parse_response(data)
+    }
+
+    fn next_block(res: &Self::Response) -> Result<u64> {
+        res.next_block()
+    }
+
+    fn row_counts(res: &Self::Response) -> Vec<(&'static str, usize)> {
+        res.tables()
+            .iter()
+            .map(|(table, batch)| (*table, batch.num_rows()))
+            .collect()
+    }
+
+    fn block_timestamps(res: &Self::Response) -> Result<Vec<(u64, u64)>> {
+        res.block_timestamps()
+    }
+
+    fn truncate(res: &Self::Response, to_block: u64) -> Result<Self::Response> {
+        res.truncate(to_block)
+    }
+
+    fn split(&self, max_values: usize) -> Vec<Self> {
+        self.split(max_values)
+    }
+
+    fn merge(responses: &[Self::Response]) -> Result<Self::Response> {
+        svm::ArrowResponse::merge(responses)
+    }
+
+    fn union(queries: &[Self]) -> Result<Self> {
+        svm::Query::union(queries)
+    }
+
+    fn demux(res: &Self::Response, query: &Self) -> Result<Self::Response> {
+        res.demux(query)
+    }
+}
+
+/// Slices the batch so it only contains rows of the blocks in `from_block..=to_block`.
+///
+/// Rows are expected to be ordered by the block number column.
+pub(crate) fn slice_blocks(
+    batch: &RecordBatch,
+    column: &str,
+    from_block: u64,
+    to_block: u64,
+) -> Result<RecordBatch> {
+    let numbers = batch
+        .column_by_name(column)
+        .with_context(|| format!("get {} col", column))?
+        .as_any()
+        .downcast_ref::<UInt64Array>()
+        .with_context(|| format!("get {} col as u64", column))?;
+    let start = numbers.values().partition_point(|n| *n < from_block);
+    let end = numbers.values().partition_point(|n| *n <= to_block);
+    Ok(batch.slice(start, end.saturating_sub(start)))
+}
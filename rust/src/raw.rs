@@ -0,0 +1,316 @@
+//! Responses exactly as the portal sent them.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use futures_lite::{Stream, StreamExt};
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+
+use crate::query::PortalQuery;
+use crate::sink::ArrowTables;
+use crate::{stream, Client, StreamConfig, StreamItem};
+
+/// A response body that is split into its lines.
+#[derive(Debug, Clone)]
+pub struct RawResponse {
+    /// The lines of `blocks` along with the newlines between them.
+    pub body: Bytes,
+    /// One block per line of the body, in the order the portal sent them.
+    pub blocks: Vec<RawBlock>,
+}
+
+/// A line of the response, which holds a block and the items in it.
+#[derive(Debug, Clone)]
+pub struct RawBlock {
+    pub number: u64,
+    /// Set if the timestamp of the block was selected.
+    pub timestamp: Option<i64>,
+    /// The JSON object without the trailing newline.
+    pub data: Bytes,
+    /// Where the line ends in the body of the response.
+    end: usize,
+    /// Number of items of each table in the line.
+    row_counts: Vec<(&'static str, usize)>,
+}
+
+impl RawResponse {
+    /// The block to continue from, which is the block after the last block of the response.
+    pub fn next_block(&self) -> Result<u64> {
+        self.blocks
+            .last()
+            .map(|block| block.number + 1)
+            .context("response has no blocks")
+    }
+
+    /// Iterates over the lines of the response.
+    pub fn lines(&self) -> impl Iterator<Item = &Bytes> {
+        self.blocks.iter().map(|block| &block.data)
+    }
+
+    /// Writes the lines of the response, each followed by a newline.
+    pub fn write_to<W: Write>(&self, out: &mut W) -> Result<()> {
+        for line in self.lines() {
+            out.write_all(line).context("write line")?;
+            out.write_all(b"\n").context("write newline")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Line {
+    header: Header,
+    #[serde(flatten)]
+    items: BTreeMap<String, Vec<IgnoredAny>>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    number: u64,
+    timestamp: Option<i64>,
+}
+
+/// Runs the query but keeps the response body instead of parsing it.
+///
+/// Queries aren't split by `ClientConfig::max_filter_values` since the responses of the parts
+/// couldn't be merged without rewriting them.
+#[derive(Debug, Clone)]
+pub(crate) struct Raw<Q>(pub(crate) Q);
+
+impl<Q: PortalQuery> Raw<Q> {
+    /// Selects the block number since pagination needs it.
+    pub(crate) fn new(mut query: Q) -> Self {
+        query.select_stream_fields();
+        Self(query)
+    }
+}
+
+impl<Q: PortalQuery> PortalQuery for Raw<Q>
+where
+    Q::Response: ArrowTables,
+{
+    type Response = RawResponse;
+
+    fn cursor(&self) -> u64 {
+        self.0.cursor()
+    }
+
+    fn set_cursor(&mut self, cursor: u64) {
+        self.0.set_cursor(cursor);
+    }
+
+    fn to_block(&self) -> Option<u64> {
+        self.0.to_block()
+    }
+
+    fn set_to_block(&mut self, to_block: Option<u64>) {
+        self.0.set_to_block(to_block);
+    }
+
+    fn select_stream_fields(&mut self) {
+        self.0.select_stream_fields();
+    }
+
+    fn select_timestamp_field(&mut self) {
+        self.0.select_timestamp_field();
+    }
+
+    fn timestamp_probe(from_block: u64, to_block: u64) -> Self {
+        Self(Q::timestamp_probe(from_block, to_block))
+    }
+
+    fn to_json(&self) -> Result<Vec<u8>> {
+        self.0.to_json()
+    }
+
+    fn parse_response(data: &Bytes) -> Result<Self::Response> {
+        let mut blocks = Vec::new();
+        let mut body_start = None;
+        let mut start = 0;
+
+        for line in data.split(|x| *x == b'\n') {
+            let end = start + line.len();
+            if !line.is_empty() {
+                let parsed: Line = serde_json::from_slice(line).context("parse block header")?;
+                let body_start = *body_start.get_or_insert(start);
+                blocks.push(RawBlock {
+                    number: parsed.header.number,
+                    timestamp: parsed.header.timestamp,
+                    data: data.slice(start..end),
+                    end: end - body_start,
+                    row_counts: row_counts::<Q::Response>(&parsed.items),
+                });
+            }
+            start = end + 1;
+        }
+
+        let body = match (body_start, blocks.last()) {
+            (Some(start), Some(last)) => data.slice(start..start + last.end),
+            _ => Bytes::new(),
+        };
+
+        Ok(RawResponse { body, blocks })
+    }
+
+    fn next_block(res: &Self::Response) -> Result<u64> {
+        res.next_block()
+    }
+
+    fn row_counts(res: &Self::Response) -> Vec<(&'static str, usize)> {
+        let mut counts = Q::Response::TABLE_NAMES
+            .iter()
+            .map(|table| (*table, 0))
+            .collect::<Vec<_>>();
+        for block in res.blocks.iter() {
+            for (count, (_, n)) in counts.iter_mut().zip(block.row_counts.iter()) {
+                count.1 += n;
+            }
+        }
+        counts
+    }
+
+    fn block_timestamps(res: &Self::Response) -> Result<Vec<(u64, u64)>> {
+        res.blocks
+            .iter()
+            .map(|block| {
+                let timestamp = block
+                    .timestamp
+                    .with_context(|| format!("block {} has no timestamp", block.number))?;
+                let timestamp = u64::try_from(timestamp).context("negative timestamp")?;
+                Ok((block.number, timestamp))
+            })
+            .collect()
+    }
+
+    fn truncate(res: &Self::Response, to_block: u64) -> Result<Self::Response> {
+        let len = res.blocks.partition_point(|block| block.number <= to_block);
+        let blocks = res.blocks[..len].to_vec();
+        let body = match blocks.last() {
+            Some(last) => res.body.slice(..last.end),
+            None => Bytes::new(),
+        };
+        Ok(RawResponse { body, blocks })
+    }
+
+    fn split(&self, _max_values: usize) -> Vec<Self> {
+        vec![self.clone()]
+    }
+
+    fn merge(_responses: &[Self::Response]) -> Result<Self::Response> {
+        Err(anyhow!("raw responses can't be merged"))
+    }
+
+    fn union(_queries: &[Self]) -> Result<Self> {
+        Err(anyhow!("queries with raw responses can't be combined"))
+    }
+
+    fn demux(_res: &Self::Response, _query: &Self) -> Result<Self::Response> {
+        Err(anyhow!("raw responses can't be demultiplexed"))
+    }
+}
+
+/// Counts the items of each table in a line, whose keys are the table names in camel case.
+fn row_counts<R: ArrowTables>(
+    items: &BTreeMap<String, Vec<IgnoredAny>>,
+) -> Vec<(&'static str, usize)> {
+    R::TABLE_NAMES
+        .iter()
+        .map(|table| {
+            let n = match *table {
+                "blocks" => 1,
+                _ => items
+                    .iter()
+                    .find(|(key, _)| snake_case(key) == *table)
+                    .map_or(0, |(_, items)| items.len()),
+            };
+            (*table, n)
+        })
+        .collect()
+}
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len() + 4);
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            out.push('_');
+            out.push(c.to_ascii_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Streams the query and writes the lines of every response into `out` before parsing it into
+/// Arrow tables.
+pub(crate) fn tee_stream<'a, Q, W>(
+    client: &'a Client,
+    query: Q,
+    config: StreamConfig,
+    mut out: W,
+) -> impl Stream<Item = Result<StreamItem<Q::Response>>> + Send + 'a
+where
+    Q: PortalQuery,
+    Q::Response: ArrowTables,
+    W: Write + Send + 'a,
+{
+    stream::lazy_stream(client, Raw::new(query), config).map(move |item| {
+        let item = item?;
+        item.data.write_to(&mut out).context("write raw response")?;
+        let data = Q::parse_response(&item.data.body).context("parse response")?;
+        Ok(item.map(|_| data))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evm;
+
+    const BODY: &[u8] = br#"{"header":{"number":10,"timestamp":100},"logs":[{"logIndex":0}]}
+{"header":{"number":11,"timestamp":112},"logs":[{"logIndex":0},{"logIndex":1}],"traces":[]}
+{"header":{"number":13,"timestamp":136}}
+"#;
+
+    #[test]
+    fn splits_lines() {
+        let res = Raw::<evm::Query>::parse_response(&Bytes::from_static(BODY)).unwrap();
+        assert_eq!(res.body.as_ref(), BODY.strip_suffix(b"\n").unwrap());
+        assert_eq!(res.next_block().unwrap(), 14);
+        assert_eq!(
+            res.lines().map(|l| l.len()).sum::<usize>() + 2,
+            res.body.len()
+        );
+        assert_eq!(
+            Raw::<evm::Query>::row_counts(&res),
+            [
+                ("blocks", 3),
+                ("transactions", 0),
+                ("logs", 3),
+                ("traces", 0)
+            ]
+        );
+        assert_eq!(
+            Raw::<evm::Query>::block_timestamps(&res).unwrap(),
+            [(10, 100), (11, 112), (13, 136)]
+        );
+
+        let mut out = Vec::new();
+        res.write_to(&mut out).unwrap();
+        assert_eq!(out, BODY);
+    }
+
+    #[test]
+    fn truncates_body() {
+        let res = Raw::<evm::Query>::parse_response(&Bytes::from_static(BODY)).unwrap();
+        let res = Raw::<evm::Query>::truncate(&res, 12).unwrap();
+        assert_eq!(res.next_block().unwrap(), 12);
+
+        let arrow = evm::parse_response(&res.body).unwrap();
+        assert_eq!(arrow.blocks.num_rows(), 2);
+        assert_eq!(arrow.logs.num_rows(), 3);
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+
+use crate::query::PortalQuery;
+use crate::{evm, svm};
+
+/// A block of a typed response along with the items in it.
+pub(crate) trait BlockRows: DeserializeOwned + Clone + Send + Sync + 'static {
+    fn number(&self) -> u64;
+    fn timestamp(&self) -> Option<u64>;
+    /// Number of items of each table, the header counts as a row of the blocks table.
+    fn row_counts(&self) -> Vec<(&'static str, usize)>;
+    /// Adds the items of the same block from the response to another part of a split query.
+    fn merge(&mut self, other: Self);
+}
+
+/// A query that has a typed response.
+pub(crate) trait RowQuery: PortalQuery {
+    type Block: BlockRows;
+}
+
+impl RowQuery for evm::Query {
+    type Block = evm::BlockData;
+}
+
+impl RowQuery for svm::Query {
+    type Block = svm::BlockData;
+}
+
+/// Runs the query but deserializes the response into typed blocks instead of Arrow tables.
+#[derive(Debug, Clone)]
+pub(crate) struct Rows<Q>(pub(crate) Q);
+
+impl<Q: RowQuery> Rows<Q> {
+    /// Selects the block number since typed blocks can't be parsed without it.
+    pub(crate) fn new(mut query: Q) -> Self {
+        query.select_stream_fields();
+        Self(query)
+    }
+}
+
+impl<Q: RowQuery> PortalQuery for Rows<Q> {
+    type Response = Vec<Q::Block>;
+
+    fn cursor(&self) -> u64 {
+        self.0.cursor()
+    }
+
+    fn set_cursor(&mut self, cursor: u64) {
+        self.0.set_cursor(cursor);
+    }
+
+    fn to_block(&self) -> Option<u64> {
+        self.0.to_block()
+    }
+
+    fn set_to_block(&mut self, to_block: Option<u64>) {
+        self.0.set_to_block(to_block);
+    }
+
+    fn select_stream_fields(&mut self) {
+        self.0.select_stream_fields();
+    }
+
+    fn select_timestamp_field(&mut self) {
+        self.0.select_timestamp_field();
+    }
+
+    fn timestamp_probe(from_block: u64, to_block: u64) -> Self {
+        Self(Q::timestamp_probe(from_block, to_block))
+    }
+
+    fn to_json(&self) -> Result<Vec<u8>> {
+        self.0.to_json()
+    }
+
+    fn parse_response(data: &bytes::Bytes) -> Result<Self::Response> {
+        data.split(|x| *x == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).context("deserialize block"))
+            .collect()
+    }
+
+    fn next_block(res: &Self::Response) -> Result<u64> {
+        res.last()
+            .map(|block| block.number() + 1)
+            .context("response has no blocks")
+    }
+
+    fn row_counts(res: &Self::Response) -> Vec<(&'static str, usize)> {
+        let mut counts = match res.first() {
+            Some(block) => block.row_counts(),
+            None => return Vec::new(),
+        };
+        for block in res.iter().skip(1) {
+            for (count, (_, n)) in counts.iter_mut().zip(block.row_counts()) {
+                count.1 += n;
+            }
+        }
+        counts
+    }
+
+    fn block_timestamps(res: &Self::Response) -> Result<Vec<(u64, u64)>> {
+        res.iter()
+            .map(|block| {
+                let timestamp = block
+                    .timestamp()
+                    .with_context(|| format!("block {} has no timestamp", block.number()))?;
+                Ok((block.number(), timestamp))
+            })
+            .collect()
+    }
+
+    fn truncate(res: &Self::Response, to_block: u64) -> Result<Self::Response> {
+        let len = res.partition_point(|block| block.number() <= to_block);
+        Ok(res[..len].to_vec())
+    }
+
+    fn split(&self, max_values: usize) -> Vec<Self> {
+        self.0.split(max_values).into_iter().map(Self).collect()
+    }
+
+    fn merge(responses: &[Self::Response]) -> Result<Self::Response> {
+        let mut blocks = BTreeMap::<u64, Q::Block>::new();
+        for block in responses.iter().flatten() {
+            match blocks.get_mut(&block.number()) {
+                Some(b) => b.merge(block.clone()),
+                None => {
+                    blocks.insert(block.number(), block.clone());
+                }
+            }
+        }
+        Ok(blocks.into_values().collect())
+    }
+
+    fn union(_queries: &[Self]) -> Result<Self> {
+        Err(anyhow!("queries with typed responses can't be combined"))
+    }
+
+    fn demux(_res: &Self::Response, _query: &Self) -> Result<Self::Response> {
+        Err(anyhow!("typed responses can't be demultiplexed"))
+    }
+}
+
+/// Sorts the items by their key and removes the ones that appear more than once.
+pub(crate) fn dedup_by_key<T, K: Ord>(items: &mut Vec<T>, key: impl Fn(&T) -> K) {
+    items.sort_by_key(|item| key(item));
+    items.dedup_by(|a, b| key(a) == key(b));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_split_responses() {
+        let a = Rows::<evm::Query>::parse_response(&bytes::Bytes::from_static(
+            br#"{"header":{"number":1},"logs":[{"logIndex":0},{"logIndex":2}]}
+{"header":{"number":3},"logs":[{"logIndex":0}]}"#,
+        ))
+        .unwrap();
+        let b = Rows::<evm::Query>::parse_response(&bytes::Bytes::from_static(
+            br#"{"header":{"number":2},"logs":[{"logIndex":0}]}
+{"header":{"number":3},"logs":[{"logIndex":0},{"logIndex":1}]}"#,
+        ))
+        .unwrap();
+
+        let res = Rows::<evm::Query>::merge(&[a, b]).unwrap();
+        let logs = res
+            .iter()
+            .map(|block| {
+                let indices = block.logs.iter().map(|log| log.log_index.unwrap());
+                (block.header.number, indices.collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(logs, [(1, vec![0, 2]), (2, vec![0]), (3, vec![0, 1])]);
+
+        assert_eq!(Rows::<evm::Query>::next_block(&res).unwrap(), 4);
+        assert_eq!(
+            Rows::<evm::Query>::row_counts(&res),
+            [
+                ("blocks", 3),
+                ("transactions", 0),
+                ("logs", 5),
+                ("traces", 0)
+            ]
+        );
+
+        let res = Rows::<evm::Query>::truncate(&res, 2).unwrap();
+        assert_eq!(res.len(), 2);
+    }
+}
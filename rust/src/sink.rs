@@ -0,0 +1,182 @@
+//! Writers that store streamed responses.
+
+use anyhow::{anyhow, Result};
+use arrow::datatypes::Schema;
+use arrow::record_batch::RecordBatch;
+
+use crate::{evm, svm};
+
+#[cfg(feature = "duckdb")]
+pub mod duckdb;
+pub mod ipc;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+#[cfg(any(feature = "sqlite", feature = "duckdb"))]
+pub mod sql;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod text;
+
+/// How binary values, like hashes and addresses, are written as text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryEncoding {
+    /// `0x` prefixed hex, which is used for EVM data.
+    Hex,
+    /// Base58, which is used for Solana keys and signatures.
+    Base58,
+}
+
+/// A response that is made of named tables.
+pub trait ArrowTables: Sized {
+    /// Names of the tables in the order that `tables` returns them.
+    const TABLE_NAMES: &'static [&'static str];
+
+    /// Names of the block number column of each table, in the order of `TABLE_NAMES`.
+    const BLOCK_COLUMNS: &'static [&'static str];
+
+    /// Returns the schema of each table in the order of `TABLE_NAMES`.
+    fn schemas() -> Vec<Schema>;
+
+    /// Returns how the portal encodes the values of a binary column.
+    fn binary_encoding(table: &str, column: &str) -> BinaryEncoding;
+
+    /// Returns every table along with its name.
+    fn tables(&self) -> Vec<(&'static str, &RecordBatch)>;
+
+    /// Builds a response from tables that are in the order of `TABLE_NAMES`.
+    fn from_tables(tables: Vec<RecordBatch>) -> Result<Self>;
+}
+
+impl ArrowTables for evm::ArrowResponse {
+    const TABLE_NAMES: &'static [&'static str] = &["blocks", "transactions", "logs", "traces"];
+    const BLOCK_COLUMNS: &'static [&'static str] =
+        &["number", "block_number", "block_number", "block_number"];
+
+    fn schemas() -> Vec<Schema> {
+        vec![
+            cherry_evm_schema::blocks_schema(),
+            cherry_evm_schema::transactions_schema(),
+            cherry_evm_schema::logs_schema(),
+            cherry_evm_schema::traces_schema(),
+        ]
+    }
+
+    fn binary_encoding(_table: &str, _column: &str) -> BinaryEncoding {
+        BinaryEncoding::Hex
+    }
+
+    fn tables(&self) -> Vec<(&'static str, &RecordBatch)> {
+        evm::ArrowResponse::tables(self).to_vec()
+    }
+
+    fn from_tables(tables: Vec<RecordBatch>) -> Result<Self> {
+        let [blocks, transactions, logs, traces] = table_array(tables)?;
+        Ok(Self {
+            blocks,
+            transactions,
+            logs,
+            traces,
+        })
+    }
+}
+
+impl ArrowTables for svm::ArrowResponse {
+    const TABLE_NAMES: &'static [&'static str] = &[
+        "blocks",
+        "transactions",
+        "instructions",
+        "logs",
+        "balances",
+        "token_balances",
+        "rewards",
+    ];
+    const BLOCK_COLUMNS: &'static [&'static str] = &[
+        "slot",
+        "block_slot",
+        "block_slot",
+        "block_slot",
+        "block_slot",
+        "block_slot",
+        "block_slot",
+    ];
+
+    fn schemas() -> Vec<Schema> {
+        vec![
+            cherry_svm_schema::blocks_schema(),
+            cherry_svm_schema::transactions_schema(),
+            cherry_svm_schema::instructions_schema(),
+            cherry_svm_schema::logs_schema(),
+            cherry_svm_schema::balances_schema(),
+            cherry_svm_schema::token_balances_schema(),
+            cherry_svm_schema::rewards_schema(),
+        ]
+    }
+
+    fn binary_encoding(table: &str, column: &str) -> BinaryEncoding {
+        match (table, column) {
+            ("instructions", "d1" | "d2" | "d4" | "d8") => BinaryEncoding::Hex,
+            _ => BinaryEncoding::Base58,
+        }
+    }
+
+    fn tables(&self) -> Vec<(&'static str, &RecordBatch)> {
+        svm::ArrowResponse::tables(self).to_vec()
+    }
+
+    fn from_tables(tables: Vec<RecordBatch>) -> Result<Self> {
+        let [blocks, transactions, instructions, logs, balances, token_balances, rewards] =
+            table_array(tables)?;
+        Ok(Self {
+            instructions,
+            transactions,
+            logs,
+            balances,
+            token_balances,
+            rewards,
+            blocks,
+        })
+    }
+}
+
+fn table_array<const N: usize>(tables: Vec<RecordBatch>) -> Result<[RecordBatch; N]> {
+    let len = tables.len();
+    tables
+        .try_into()
+        .map_err(|_| anyhow!("expected {} tables but got {}", N, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_names_match_tables() {
+        let res = evm::parse_response(br#"{"header":{"number":1}}"#).unwrap();
+        let names = ArrowTables::tables(&res)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, evm::ArrowResponse::TABLE_NAMES);
+        check_schemas(&res);
+
+        let res = svm::parse_response(br#"{"header":{"number":1}}"#).unwrap();
+        let names = ArrowTables::tables(&res)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, svm::ArrowResponse::TABLE_NAMES);
+        check_schemas(&res);
+    }
+
+    fn check_schemas<R: ArrowTables>(res: &R) {
+        for (((table, batch), schema), column) in res
+            .tables()
+            .into_iter()
+            .zip(R::schemas())
+            .zip(R::BLOCK_COLUMNS)
+        {
+            assert_eq!(batch.schema().as_ref(), &schema, "{}", table);
+            assert!(schema.field_with_name(column).is_ok(), "{}", table);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! DuckDB database with a table per response table.
+
+use std::path::Path;
+
+use ::duckdb::types::{ToSqlOutput, Value, ValueRef};
+use ::duckdb::{appender_params_from_iter, Connection, OptionalExt, ToSql};
+use anyhow::{Context, Result};
+use arrow::datatypes::DataType;
+use arrow::record_batch::RecordBatch;
+
+use super::sql::{self, SqlConnection, SqlSink, SqlValue};
+use super::ArrowTables;
+
+/// Writes responses into DuckDB tables, see `SqlSink`.
+///
+/// Binary columns are stored as blobs. Decimals, lists and structs are stored as text.
+pub type DuckDbSink<R> = SqlSink<Connection, R>;
+
+impl<R: ArrowTables> SqlSink<Connection, R> {
+    /// Opens the database file, creating it and the tables if they don't exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path).context("open database")?;
+        Self::new(conn)
+    }
+}
+
+impl SqlConnection for Connection {
+    fn column_type(data_type: &DataType) -> &'static str {
+        match data_type {
+            DataType::Boolean => "BOOLEAN",
+            DataType::Int8 => "TINYINT",
+            DataType::Int64 => "BIGINT",
+            DataType::UInt8 => "UTINYINT",
+            DataType::UInt16 => "USMALLINT",
+            DataType::UInt32 => "UINTEGER",
+            DataType::UInt64 => "UBIGINT",
+            DataType::Binary => "BLOB",
+            _ => "VARCHAR",
+        }
+    }
+
+    fn write(&mut self, sql: &str, tables: &[(&'static str, &RecordBatch)]) -> Result<()> {
+        let tx = self.transaction().context("begin transaction")?;
+        tx.execute_batch(sql).context("execute statements")?;
+
+        for (table, batch) in tables {
+            let mut appender = tx
+                .appender(table)
+                .with_context(|| format!("create {} appender", table))?;
+            for row in sql::rows(batch).with_context(|| format!("convert {} table", table))? {
+                appender
+                    .append_row(appender_params_from_iter(row.iter()))
+                    .with_context(|| format!("append to {} table", table))?;
+            }
+            appender
+                .flush()
+                .with_context(|| format!("flush {} appender", table))?;
+        }
+
+        tx.commit().context("commit transaction")
+    }
+
+    fn query_u64(&self, sql: &str) -> Result<Option<u64>> {
+        let n = self
+            .query_row(sql, [], |row| row.get::<_, i64>(0))
+            .optional()
+            .context("query row")?;
+        n.map(|n| u64::try_from(n).context("convert to unsigned"))
+            .transpose()
+    }
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(&self) -> ::duckdb::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            Self::Null => ToSqlOutput::Owned(Value::Null),
+            Self::Bool(v) => ToSqlOutput::Owned(Value::Boolean(*v)),
+            Self::Int(v) => ToSqlOutput::Owned(Value::BigInt(*v)),
+            Self::UInt(v) => ToSqlOutput::Owned(Value::UBigInt(*v)),
+            Self::Text(v) => ToSqlOutput::Borrowed(ValueRef::Text(v.as_bytes())),
+            Self::Blob(v) => ToSqlOutput::Borrowed(ValueRef::Blob(v)),
+        })
+    }
+}
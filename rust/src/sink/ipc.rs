@@ -0,0 +1,333 @@
+//! Arrow IPC streams and files with one stream or file per table.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use arrow::compute::concat_batches;
+use arrow::ipc::reader::{FileReader, StreamReader};
+use arrow::ipc::writer::{FileWriter, StreamWriter};
+use arrow::record_batch::{RecordBatch, RecordBatchReader};
+use futures_lite::{Stream, StreamExt};
+
+use super::ArrowTables;
+use crate::StreamItem;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcFormat {
+    /// The streaming format, which can be read while it is being written, e.g. through a pipe.
+    Stream,
+    /// The file format, also known as Feather v2. It needs to be finished before it can be read.
+    File,
+}
+
+impl IpcFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Stream => "arrows",
+            Self::File => "arrow",
+        }
+    }
+}
+
+/// Path of the file of a table in a directory that is written by `IpcWriter::create`.
+pub fn table_path(dir: &Path, table: &str, format: IpcFormat) -> PathBuf {
+    dir.join(format!("{}.{}", table, format.extension()))
+}
+
+enum TableWriter<W: Write> {
+    Stream(StreamWriter<W>),
+    File(FileWriter<W>),
+}
+
+type Open<W> = Box<dyn FnMut(&'static str) -> Result<W> + Send>;
+
+/// Writes every table of the responses into its own IPC stream or file.
+///
+/// Each response becomes one batch in every table, so the responses can be read back one by one
+/// with `IpcReader`.
+pub struct IpcWriter<W: Write> {
+    format: IpcFormat,
+    open: Open<W>,
+    writers: Vec<(&'static str, TableWriter<W>)>,
+}
+
+impl IpcWriter<BufWriter<File>> {
+    /// Writes a `{table}.arrows` or `{table}.arrow` file per table into the directory.
+    pub fn create(dir: impl Into<PathBuf>, format: IpcFormat) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).context("create directory")?;
+
+        Ok(Self::new(format, move |table| {
+            let path = table_path(&dir, table, format);
+            let file = File::create(&path).with_context(|| format!("create {}", path.display()))?;
+            Ok(BufWriter::new(file))
+        }))
+    }
+}
+
+impl<W: Write> IpcWriter<W> {
+    /// `open` is called with the name of each table once the first response is written.
+    pub fn new(
+        format: IpcFormat,
+        open: impl FnMut(&'static str) -> Result<W> + Send + 'static,
+    ) -> Self {
+        Self {
+            format,
+            open: Box::new(open),
+            writers: Vec::new(),
+        }
+    }
+
+    pub fn write<R: ArrowTables>(&mut self, res: &R) -> Result<()> {
+        let tables = res.tables();
+
+        if self.writers.is_empty() {
+            for (table, batch) in tables.iter() {
+                let out = (self.open)(table).with_context(|| format!("open {} output", table))?;
+                let schema = batch.schema();
+                let writer = match self.format {
+                    IpcFormat::Stream => {
+                        StreamWriter::try_new(out, &schema).map(TableWriter::Stream)
+                    }
+                    IpcFormat::File => FileWriter::try_new(out, &schema).map(TableWriter::File),
+                };
+                let writer = writer.with_context(|| format!("create {} writer", table))?;
+                self.writers.push((table, writer));
+            }
+        }
+
+        if tables.len() != self.writers.len() {
+            return Err(anyhow!(
+                "expected {} tables but got {}",
+                self.writers.len(),
+                tables.len()
+            ));
+        }
+
+        for ((table, writer), (_, batch)) in self.writers.iter_mut().zip(tables) {
+            let res = match writer {
+                TableWriter::Stream(w) => w.write(batch),
+                TableWriter::File(w) => w.write(batch),
+            };
+            res.with_context(|| format!("write {} table", table))?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes every item of the stream and finishes the outputs when the stream ends.
+    pub async fn write_stream<R, S>(mut self, stream: S) -> Result<Vec<(&'static str, W)>>
+    where
+        R: ArrowTables,
+        S: Stream<Item = Result<StreamItem<R>>>,
+    {
+        futures_lite::pin!(stream);
+
+        while let Some(item) = stream.next().await {
+            let item = item.context("get stream item")?;
+            self.write(&item.data)?;
+        }
+
+        self.finish()
+    }
+
+    /// Finishes the streams or files and returns the underlying writers along with the names
+    /// of their tables.
+    pub fn finish(self) -> Result<Vec<(&'static str, W)>> {
+        self.writers
+            .into_iter()
+            .map(|(table, writer)| {
+                let out = match writer {
+                    TableWriter::Stream(mut w) => w.finish().and_then(|_| w.into_inner()),
+                    TableWriter::File(mut w) => w.finish().and_then(|_| w.into_inner()),
+                };
+                let mut out = out.with_context(|| format!("finish {} table", table))?;
+                out.flush()
+                    .with_context(|| format!("flush {} output", table))?;
+                Ok((table, out))
+            })
+            .collect()
+    }
+}
+
+/// Reads responses that were written by `IpcWriter`, one response per batch.
+pub struct IpcReader<R> {
+    readers: Vec<Box<dyn RecordBatchReader + Send>>,
+    _response: PhantomData<R>,
+}
+
+impl<R: ArrowTables> IpcReader<R> {
+    /// Reads the files that `IpcWriter::create` wrote into the directory.
+    pub fn open(dir: &Path, format: IpcFormat) -> Result<Self> {
+        let inputs = R::TABLE_NAMES
+            .iter()
+            .map(|table| {
+                let path = table_path(dir, table, format);
+                File::open(&path)
+                    .map(BufReader::new)
+                    .with_context(|| format!("open {}", path.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        match format {
+            IpcFormat::Stream => Self::stream(inputs),
+            IpcFormat::File => Self::file(inputs),
+        }
+    }
+
+    /// Reads IPC streams, one per table in the order of `ArrowTables::TABLE_NAMES`.
+    pub fn stream<I: Read + Send + 'static>(inputs: Vec<I>) -> Result<Self> {
+        check_num_tables::<R>(inputs.len())?;
+        let readers = inputs
+            .into_iter()
+            .zip(R::TABLE_NAMES)
+            .map(|(input, table)| {
+                let reader = StreamReader::try_new(input, None)
+                    .with_context(|| format!("read {} stream", table))?;
+                Ok(Box::new(reader) as Box<dyn RecordBatchReader + Send>)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::new(readers))
+    }
+
+    /// Reads IPC files, one per table in the order of `ArrowTables::TABLE_NAMES`.
+    pub fn file<I: Read + Seek + Send + 'static>(inputs: Vec<I>) -> Result<Self> {
+        check_num_tables::<R>(inputs.len())?;
+        let readers = inputs
+            .into_iter()
+            .zip(R::TABLE_NAMES)
+            .map(|(input, table)| {
+                let reader = FileReader::try_new(input, None)
+                    .with_context(|| format!("read {} file", table))?;
+                Ok(Box::new(reader) as Box<dyn RecordBatchReader + Send>)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::new(readers))
+    }
+
+    fn new(readers: Vec<Box<dyn RecordBatchReader + Send>>) -> Self {
+        Self {
+            readers,
+            _response: PhantomData,
+        }
+    }
+
+    fn read_next(&mut self) -> Result<Option<R>> {
+        let mut tables = Vec::with_capacity(self.readers.len());
+        for (reader, table) in self.readers.iter_mut().zip(R::TABLE_NAMES) {
+            match reader.next() {
+                Some(batch) => tables.push(batch.with_context(|| format!("read {} table", table))?),
+                None => break,
+            }
+        }
+
+        if tables.is_empty() {
+            return Ok(None);
+        }
+        if tables.len() != self.readers.len() {
+            return Err(anyhow!("tables have different numbers of batches"));
+        }
+
+        R::from_tables(tables).map(Some)
+    }
+}
+
+fn check_num_tables<R: ArrowTables>(n: usize) -> Result<()> {
+    if n != R::TABLE_NAMES.len() {
+        return Err(anyhow!(
+            "expected {} tables but got {}",
+            R::TABLE_NAMES.len(),
+            n
+        ));
+    }
+    Ok(())
+}
+
+impl<R: ArrowTables> Iterator for IpcReader<R> {
+    type Item = Result<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_next().transpose()
+    }
+}
+
+/// Concatenates the batches of each table so the whole input is one response.
+pub fn concat<R: ArrowTables>(responses: impl IntoIterator<Item = Result<R>>) -> Result<R> {
+    let responses = responses.into_iter().collect::<Result<Vec<_>>>()?;
+    let first = responses.first().context("no responses")?;
+
+    let tables = first
+        .tables()
+        .into_iter()
+        .enumerate()
+        .map(|(i, (table, batch))| {
+            let batches = responses
+                .iter()
+                .map(|res| res.tables()[i].1.clone())
+                .collect::<Vec<RecordBatch>>();
+            concat_batches(&batch.schema(), &batches)
+                .with_context(|| format!("concat {} table", table))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    R::from_tables(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::evm;
+
+    fn response(number: u64) -> evm::ArrowResponse {
+        let data = format!(
+            r#"{{"header":{{"number":{}}},"logs":[{{"logIndex":0}},{{"logIndex":1}}]}}"#,
+            number
+        );
+        evm::parse_response(data.as_bytes()).unwrap()
+    }
+
+    fn write(format: IpcFormat) -> Vec<Vec<u8>> {
+        let mut writer = IpcWriter::new(format, |_| Ok(Vec::new()));
+        writer.write(&response(1)).unwrap();
+        writer.write(&response(2)).unwrap();
+        writer
+            .finish()
+            .unwrap()
+            .into_iter()
+            .map(|(_, out)| out)
+            .collect()
+    }
+
+    #[test]
+    fn stream_roundtrip() {
+        let outputs = write(IpcFormat::Stream)
+            .into_iter()
+            .map(Cursor::new)
+            .collect();
+        let responses = IpcReader::<evm::ArrowResponse>::stream(outputs)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(responses.len(), 2);
+        for (res, number) in responses.iter().zip([1, 2]) {
+            assert_eq!(res.tables(), response(number).tables());
+        }
+    }
+
+    #[test]
+    fn file_roundtrip() {
+        let outputs = write(IpcFormat::File)
+            .into_iter()
+            .map(Cursor::new)
+            .collect();
+        let reader = IpcReader::<evm::ArrowResponse>::file(outputs).unwrap();
+        let res = concat(reader).unwrap();
+        assert_eq!(res.blocks.num_rows(), 2);
+        assert_eq!(res.logs.num_rows(), 4);
+    }
+}
@@ -0,0 +1,357 @@
+//! Parquet files that are partitioned by block range.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use ::parquet::arrow::ArrowWriter;
+use ::parquet::basic::{Compression, ZstdLevel};
+use ::parquet::file::properties::WriterProperties;
+use anyhow::{anyhow, Context, Result};
+use futures_lite::{Stream, StreamExt};
+
+use super::ArrowTables;
+use crate::StreamItem;
+
+/// A range is complete once the file of this table is written, so it is written last.
+const BLOCKS: &str = "blocks";
+const TMP_EXTENSION: &str = "tmp";
+
+#[derive(Debug, Clone, Copy)]
+pub struct ParquetSinkConfig {
+    /// Start new files once this many rows are written, counting the rows of every table.
+    pub max_rows: Option<usize>,
+    /// Start new files once the tables take up this many bytes after encoding.
+    pub max_bytes: Option<usize>,
+    /// Start new files once the files cover this many blocks.
+    pub max_blocks: Option<u64>,
+    pub compression: Compression,
+}
+
+impl Default for ParquetSinkConfig {
+    fn default() -> Self {
+        Self {
+            max_rows: None,
+            max_bytes: Some(256 << 20),
+            max_blocks: None,
+            compression: Compression::ZSTD(ZstdLevel::default()),
+        }
+    }
+}
+
+/// Writes responses into a directory per table, like `blocks` and `logs`.
+///
+/// Each file is named `{from}-{to}.parquet` after the block range it covers and every table
+/// gets a file for the same ranges. Files are written under a temporary name and renamed once
+/// they are complete.
+///
+/// File IO is blocking.
+pub struct ParquetSink {
+    dir: PathBuf,
+    config: ParquetSinkConfig,
+    cursor: Option<u64>,
+    completed: Option<u64>,
+    part: Option<Part>,
+}
+
+struct Part {
+    from_block: u64,
+    to_block: u64,
+    num_rows: usize,
+    writers: Vec<(&'static str, ArrowWriter<File>)>,
+}
+
+impl ParquetSink {
+    /// Opens the directory, creating it if it doesn't exist.
+    ///
+    /// Files that were left behind by an interrupted write are removed, so the sink resumes after
+    /// the last range that was completed in every table.
+    pub fn open(dir: impl Into<PathBuf>, config: ParquetSinkConfig) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).context("create directory")?;
+
+        let completed = completed_ranges(&dir.join(BLOCKS))
+            .context("read completed ranges")?
+            .into_iter()
+            .map(|(_, to)| to + 1)
+            .max();
+
+        for entry in fs::read_dir(&dir).context("read directory")? {
+            let path = entry.context("read directory entry")?.path();
+            if path.is_dir() {
+                remove_incomplete(&path, completed)
+                    .with_context(|| format!("clean up {}", path.display()))?;
+            }
+        }
+
+        Ok(Self {
+            dir,
+            config,
+            cursor: completed,
+            completed,
+            part: None,
+        })
+    }
+
+    /// Returns the block after the last completed range, which is where a stream should start
+    /// after a restart.
+    ///
+    /// Returns `None` if no range was completed yet.
+    pub fn next_block(&self) -> Option<u64> {
+        self.completed
+    }
+
+    /// Appends the response to the current files and starts new files if one of the limits in
+    /// the config is reached.
+    ///
+    /// Errors if the item doesn't start right after the data that was already written, since
+    /// the files would otherwise overlap or miss blocks.
+    pub fn write<R: ArrowTables>(&mut self, item: &StreamItem<R>) -> Result<()> {
+        if let Some(next) = self.cursor {
+            if item.from_block != next {
+                return Err(anyhow!(
+                    "item starts at block {} but the next block to write is {}",
+                    item.from_block,
+                    next
+                ));
+            }
+        }
+
+        let part = match self.part.as_mut() {
+            Some(part) => part,
+            None => {
+                let part = self.start_part(item)?;
+                self.part.insert(part)
+            }
+        };
+
+        for (table, batch) in item.data.tables() {
+            let (_, writer) = part
+                .writers
+                .iter_mut()
+                .find(|(t, _)| *t == table)
+                .with_context(|| format!("unknown table {}", table))?;
+            writer
+                .write(batch)
+                .with_context(|| format!("write {} table", table))?;
+            part.num_rows += batch.num_rows();
+        }
+        part.to_block = item.to_block;
+        self.cursor = Some(item.to_block + 1);
+
+        let num_bytes = part
+            .writers
+            .iter()
+            .map(|(_, w)| w.bytes_written() + w.in_progress_size())
+            .sum::<usize>();
+        let num_blocks = part.to_block + 1 - part.from_block;
+        let roll = self.config.max_rows.is_some_and(|m| part.num_rows >= m)
+            || self.config.max_bytes.is_some_and(|m| num_bytes >= m)
+            || self.config.max_blocks.is_some_and(|m| num_blocks >= m);
+        if roll {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Finishes the current files.
+    pub fn flush(&mut self) -> Result<()> {
+        let Some(mut part) = self.part.take() else {
+            return Ok(());
+        };
+
+        let name = format!("{}-{}.parquet", part.from_block, part.to_block);
+        part.writers.sort_by_key(|(table, _)| *table == BLOCKS);
+        for (table, writer) in part.writers {
+            let file = writer
+                .into_inner()
+                .with_context(|| format!("finish {} file", table))?;
+            file.sync_all()
+                .with_context(|| format!("sync {} file", table))?;
+            let table_dir = self.dir.join(table);
+            fs::rename(tmp_path(&table_dir, part.from_block), table_dir.join(&name))
+                .with_context(|| format!("rename {} file", table))?;
+        }
+        self.completed = Some(part.to_block + 1);
+
+        Ok(())
+    }
+
+    /// Writes every item of the stream and finishes the current files when the stream ends.
+    ///
+    /// If the stream yields an error, it is returned and the current files are left open.
+    pub async fn write_stream<R, S>(&mut self, stream: S) -> Result<()>
+    where
+        R: ArrowTables,
+        S: Stream<Item = Result<StreamItem<R>>>,
+    {
+        futures_lite::pin!(stream);
+
+        while let Some(item) = stream.next().await {
+            let item = item.context("get stream item")?;
+            self.write(&item)?;
+        }
+
+        self.flush()
+    }
+
+    fn start_part<R: ArrowTables>(&self, item: &StreamItem<R>) -> Result<Part> {
+        let props = WriterProperties::builder()
+            .set_compression(self.config.compression)
+            .build();
+
+        let writers = item
+            .data
+            .tables()
+            .into_iter()
+            .map(|(table, batch)| {
+                let table_dir = self.dir.join(table);
+                fs::create_dir_all(&table_dir)
+                    .with_context(|| format!("create {} directory", table))?;
+                let file = File::create(tmp_path(&table_dir, item.from_block))
+                    .with_context(|| format!("create {} file", table))?;
+                let writer = ArrowWriter::try_new(file, batch.schema(), Some(props.clone()))
+                    .with_context(|| format!("create {} writer", table))?;
+                Ok((table, writer))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Part {
+            from_block: item.from_block,
+            to_block: item.from_block,
+            num_rows: 0,
+            writers,
+        })
+    }
+}
+
+fn tmp_path(table_dir: &Path, from_block: u64) -> PathBuf {
+    table_dir.join(format!("{}.{}", from_block, TMP_EXTENSION))
+}
+
+/// Parses `{from}-{to}.parquet`.
+fn parse_range(name: &str) -> Option<(u64, u64)> {
+    let (from, to) = name.strip_suffix(".parquet")?.split_once('-')?;
+    Some((from.parse().ok()?, to.parse().ok()?))
+}
+
+/// Returns the ranges of the completed files in the directory, which may not exist.
+pub(crate) fn completed_ranges(dir: &Path) -> Result<Vec<(u64, u64)>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut ranges = Vec::new();
+    for entry in fs::read_dir(dir).context("read directory")? {
+        let entry = entry.context("read directory entry")?;
+        if let Some(range) = entry.file_name().to_str().and_then(parse_range) {
+            ranges.push(range);
+        }
+    }
+    ranges.sort_unstable();
+
+    Ok(ranges)
+}
+
+/// Removes temporary files and files of ranges that start at or after `completed`.
+fn remove_incomplete(table_dir: &Path, completed: Option<u64>) -> Result<()> {
+    for entry in fs::read_dir(table_dir).context("read directory")? {
+        let path = entry.context("read directory entry")?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+
+        let is_tmp = path.extension().is_some_and(|ext| ext == TMP_EXTENSION);
+        let is_incomplete =
+            parse_range(name).is_some_and(|(from, _)| completed.is_none_or(|c| from >= c));
+        if is_tmp || is_incomplete {
+            log::debug!("removing incomplete file {}", path.display());
+            fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::*;
+    use crate::test_util::{evm_item, test_dir};
+
+    fn num_rows(path: &Path) -> usize {
+        ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .map(|batch| batch.unwrap().num_rows())
+            .sum()
+    }
+
+    #[test]
+    fn rolls_files_by_block_range() {
+        let dir = test_dir("roll");
+        let config = ParquetSinkConfig {
+            max_blocks: Some(20),
+            ..Default::default()
+        };
+
+        let mut sink = ParquetSink::open(&dir, config).unwrap();
+        assert_eq!(sink.next_block(), None);
+        for (from, to) in [(0, 9), (10, 19), (20, 24)] {
+            sink.write(&evm_item(from, to)).unwrap();
+        }
+        assert_eq!(sink.next_block(), Some(20));
+        sink.flush().unwrap();
+        assert_eq!(sink.next_block(), Some(25));
+
+        assert_eq!(
+            completed_ranges(&dir.join("logs")).unwrap(),
+            [(0, 19), (20, 24)]
+        );
+        assert_eq!(
+            completed_ranges(&dir.join("traces")).unwrap(),
+            [(0, 19), (20, 24)]
+        );
+        assert_eq!(num_rows(&dir.join("logs/0-19.parquet")), 4);
+        assert_eq!(num_rows(&dir.join("blocks/0-19.parquet")), 4);
+        assert_eq!(num_rows(&dir.join("blocks/20-24.parquet")), 2);
+
+        assert!(sink.write(&evm_item(24, 30)).is_err());
+        assert!(sink.write(&evm_item(26, 30)).is_err());
+        sink.write(&evm_item(25, 30)).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resumes_after_last_completed_range() {
+        let dir = test_dir("resume");
+        let config = ParquetSinkConfig {
+            max_rows: Some(1),
+            ..Default::default()
+        };
+
+        let mut sink = ParquetSink::open(&dir, config).unwrap();
+        sink.write(&evm_item(0, 9)).unwrap();
+        // an interrupted write leaves temporary files and files of tables other than blocks
+        sink.config.max_rows = None;
+        sink.write(&evm_item(10, 19)).unwrap();
+        drop(sink);
+        fs::write(dir.join("logs/20-29.parquet"), b"partial").unwrap();
+
+        let sink = ParquetSink::open(&dir, config).unwrap();
+        assert_eq!(sink.next_block(), Some(10));
+        for table in ["blocks", "transactions", "logs", "traces"] {
+            let mut names = fs::read_dir(dir.join(table))
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            assert_eq!(names, ["0-9.parquet"]);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,375 @@
+//! Sinks that write into SQL databases, like `sqlite::SqliteSink` and `duckdb::DuckDbSink`.
+
+use std::marker::PhantomData;
+
+use anyhow::{Context, Result};
+use arrow::array::{Array, AsArray};
+use arrow::datatypes::{
+    DataType, Int64Type, Int8Type, Schema, UInt16Type, UInt32Type, UInt64Type, UInt8Type,
+};
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::{ArrayFormatter, FormatOptions};
+use futures_lite::{Stream, StreamExt};
+
+use super::ArrowTables;
+use crate::StreamItem;
+
+/// Table that holds the block after the last written response.
+pub(crate) const CURSOR_TABLE: &str = "_sqd_cursor";
+
+/// A database connection that `SqlSink` writes through.
+pub trait SqlConnection {
+    /// Returns the type of the column that holds values of the Arrow type.
+    fn column_type(data_type: &DataType) -> &'static str;
+
+    /// Runs the statements and inserts the rows of the tables in a single transaction.
+    fn write(&mut self, sql: &str, tables: &[(&'static str, &RecordBatch)]) -> Result<()>;
+
+    /// Returns the integer in the first column of the first row of the query, if there is a row.
+    fn query_u64(&self, sql: &str) -> Result<Option<u64>>;
+}
+
+/// Writes responses into tables that are created from the schemas of the response, like
+/// `blocks` and `logs`, next to a `_sqd_cursor` table that holds the block to continue from.
+///
+/// Each response is written in a single transaction along with the cursor, so the database never
+/// holds a partially written response. Rows of the blocks that a response covers are replaced,
+/// which makes it safe to write the same range again.
+///
+/// Database IO is blocking.
+pub struct SqlSink<C, R> {
+    conn: C,
+    next_block: Option<u64>,
+    _response: PhantomData<R>,
+}
+
+impl<C: SqlConnection, R: ArrowTables> SqlSink<C, R> {
+    /// Writes into the connection, creating the tables if they don't exist.
+    pub fn new(mut conn: C) -> Result<Self> {
+        conn.write(&create_tables_sql::<R>(C::column_type), &[])
+            .context("create tables")?;
+        let next_block = conn
+            .query_u64(&select_cursor_sql())
+            .context("read cursor")?;
+
+        Ok(Self {
+            conn,
+            next_block,
+            _response: PhantomData,
+        })
+    }
+
+    /// Returns the block after the last written response, which is where a stream should start
+    /// after a restart.
+    ///
+    /// Returns `None` if nothing was written yet.
+    pub fn next_block(&self) -> Option<u64> {
+        self.next_block
+    }
+
+    /// The connection, e.g. to query the tables.
+    pub fn connection(&self) -> &C {
+        &self.conn
+    }
+
+    /// Writes the response and moves the cursor past it.
+    ///
+    /// Rows at or after `item.from_block` are deleted first, so a range that was already written
+    /// is replaced.
+    pub fn write(&mut self, item: &StreamItem<R>) -> Result<()> {
+        let mut sql = delete_after_sql::<R>(item.from_block.checked_sub(1));
+        sql.push_str(&insert_cursor_sql(item.to_block + 1));
+        self.conn
+            .write(&sql, &item.data.tables())
+            .context("write response")?;
+        self.next_block = Some(item.to_block + 1);
+
+        Ok(())
+    }
+
+    /// Deletes the rows of the blocks after `block`, e.g. when the chain was reorganized after
+    /// it, and moves the cursor back to the block after it.
+    pub fn rollback(&mut self, block: u64) -> Result<()> {
+        let next_block = self.next_block.map(|n| n.min(block + 1));
+
+        let mut sql = delete_after_sql::<R>(Some(block));
+        if let Some(next_block) = next_block {
+            sql.push_str(&insert_cursor_sql(next_block));
+        }
+        self.conn.write(&sql, &[]).context("delete rows")?;
+        self.next_block = next_block;
+
+        Ok(())
+    }
+
+    /// Writes every item of the stream.
+    ///
+    /// If the stream yields an error, it is returned and the items before it stay written.
+    pub async fn write_stream<S>(&mut self, stream: S) -> Result<()>
+    where
+        S: Stream<Item = Result<StreamItem<R>>>,
+    {
+        futures_lite::pin!(stream);
+
+        while let Some(item) = stream.next().await {
+            let item = item.context("get stream item")?;
+            self.write(&item)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SqlValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+pub(crate) fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Returns the statements that create the tables of the response and the cursor table if they
+/// don't exist yet.
+pub(crate) fn create_tables_sql<R: ArrowTables>(
+    column_type: fn(&DataType) -> &'static str,
+) -> String {
+    let mut sql = format!(
+        "CREATE TABLE IF NOT EXISTS {} (next_block BIGINT NOT NULL);\n",
+        quote(CURSOR_TABLE)
+    );
+
+    for ((table, schema), block_column) in R::TABLE_NAMES
+        .iter()
+        .zip(R::schemas())
+        .zip(R::BLOCK_COLUMNS)
+    {
+        let columns = schema
+            .fields()
+            .iter()
+            .map(|f| format!("{} {}", quote(f.name()), column_type(f.data_type())))
+            .collect::<Vec<_>>()
+            .join(", ");
+        sql.push_str(&format!(
+            "CREATE TABLE IF NOT EXISTS {} ({});\n",
+            quote(table),
+            columns
+        ));
+        sql.push_str(&format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {} ({});\n",
+            quote(&format!("{}_{}", table, block_column)),
+            quote(table),
+            quote(block_column)
+        ));
+    }
+
+    sql
+}
+
+/// Returns the statements that delete the rows of every table after `block` and the cursor.
+pub(crate) fn delete_after_sql<R: ArrowTables>(block: Option<u64>) -> String {
+    let mut sql = format!("DELETE FROM {};\n", quote(CURSOR_TABLE));
+    for (table, block_column) in R::TABLE_NAMES.iter().zip(R::BLOCK_COLUMNS) {
+        match block {
+            Some(block) => sql.push_str(&format!(
+                "DELETE FROM {} WHERE {} > {};\n",
+                quote(table),
+                quote(block_column),
+                block
+            )),
+            None => sql.push_str(&format!("DELETE FROM {};\n", quote(table))),
+        }
+    }
+    sql
+}
+
+pub(crate) fn insert_cursor_sql(next_block: u64) -> String {
+    format!(
+        "INSERT INTO {} (next_block) VALUES ({});\n",
+        quote(CURSOR_TABLE),
+        next_block
+    )
+}
+
+pub(crate) fn select_cursor_sql() -> String {
+    format!("SELECT next_block FROM {}", quote(CURSOR_TABLE))
+}
+
+/// Converts the batch into rows of values.
+pub(crate) fn rows(batch: &RecordBatch) -> Result<Vec<Vec<SqlValue>>> {
+    let schema = batch.schema();
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(schema.fields())
+        .map(|(array, field)| {
+            column_values(array.as_ref())
+                .with_context(|| format!("convert {} column", field.name()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut rows = vec![Vec::with_capacity(columns.len()); batch.num_rows()];
+    for column in columns {
+        for (row, value) in rows.iter_mut().zip(column) {
+            row.push(value);
+        }
+    }
+
+    Ok(rows)
+}
+
+/// Returns the `INSERT` statement for the rows of a table with the schema.
+#[cfg(feature = "sqlite")]
+pub(crate) fn insert_sql(table: &str, schema: &Schema) -> String {
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|f| quote(f.name()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let params = vec!["?"; schema.fields().len()].join(", ");
+    format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        quote(table),
+        columns,
+        params
+    )
+}
+
+/// Integers and booleans are kept as they are, binary and string columns become blobs and text.
+///
+/// Other columns, like decimals and lists, are formatted as text.
+fn column_values(array: &dyn Array) -> Result<Vec<SqlValue>> {
+    let values = |value: &dyn Fn(usize) -> SqlValue| {
+        (0..array.len())
+            .map(|i| {
+                if array.is_null(i) {
+                    SqlValue::Null
+                } else {
+                    value(i)
+                }
+            })
+            .collect()
+    };
+
+    let values = match array.data_type() {
+        DataType::Boolean => {
+            let array = array.as_boolean();
+            values(&|i| SqlValue::Bool(array.value(i)))
+        }
+        DataType::Int8 => {
+            let array = array.as_primitive::<Int8Type>();
+            values(&|i| SqlValue::Int(array.value(i).into()))
+        }
+        DataType::Int64 => {
+            let array = array.as_primitive::<Int64Type>();
+            values(&|i| SqlValue::Int(array.value(i)))
+        }
+        DataType::UInt8 => {
+            let array = array.as_primitive::<UInt8Type>();
+            values(&|i| SqlValue::Int(array.value(i).into()))
+        }
+        DataType::UInt16 => {
+            let array = array.as_primitive::<UInt16Type>();
+            values(&|i| SqlValue::Int(array.value(i).into()))
+        }
+        DataType::UInt32 => {
+            let array = array.as_primitive::<UInt32Type>();
+            values(&|i| SqlValue::Int(array.value(i).into()))
+        }
+        DataType::UInt64 => {
+            let array = array.as_primitive::<UInt64Type>();
+            values(&|i| SqlValue::UInt(array.value(i)))
+        }
+        DataType::Binary => {
+            let array = array.as_binary::<i32>();
+            values(&|i| SqlValue::Blob(array.value(i).to_vec()))
+        }
+        DataType::Utf8 => {
+            let array = array.as_string::<i32>();
+            values(&|i| SqlValue::Text(array.value(i).to_owned()))
+        }
+        _ => {
+            let formatter = ArrayFormatter::try_new(array, &FormatOptions::default())
+                .context("create formatter")?;
+            values(&|i| SqlValue::Text(formatter.value(i).to_string()))
+        }
+    };
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evm;
+    use crate::test_util::evm_item;
+
+    fn replaces_rolled_back_blocks<C: SqlConnection>(conn: C) {
+        let mut sink = SqlSink::<C, evm::ArrowResponse>::new(conn).unwrap();
+        assert_eq!(sink.next_block(), None);
+        let count = |sink: &SqlSink<C, _>, table: &str| {
+            sink.connection()
+                .query_u64(&format!("SELECT count(*) FROM {}", table))
+                .unwrap()
+                .unwrap()
+        };
+
+        sink.write(&evm_item(0, 9)).unwrap();
+        sink.write(&evm_item(10, 19)).unwrap();
+        assert_eq!(sink.next_block(), Some(20));
+        assert_eq!((count(&sink, "blocks"), count(&sink, "logs")), (4, 4));
+
+        sink.rollback(12).unwrap();
+        assert_eq!(sink.next_block(), Some(13));
+        assert_eq!((count(&sink, "blocks"), count(&sink, "logs")), (3, 4));
+
+        sink.write(&evm_item(10, 15)).unwrap();
+        assert_eq!(sink.next_block(), Some(16));
+        assert_eq!((count(&sink, "blocks"), count(&sink, "logs")), (4, 4));
+
+        let next = sink.connection().query_u64(&select_cursor_sql()).unwrap();
+        assert_eq!(next, Some(16));
+
+        // a restarted sink continues after the cursor
+        let sink = SqlSink::<C, evm::ArrowResponse>::new(sink.conn).unwrap();
+        assert_eq!(sink.next_block(), Some(16));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_replaces_rolled_back_blocks() {
+        replaces_rolled_back_blocks(rusqlite::Connection::open_in_memory().unwrap());
+    }
+
+    #[cfg(feature = "duckdb")]
+    #[test]
+    fn duckdb_replaces_rolled_back_blocks() {
+        replaces_rolled_back_blocks(::duckdb::Connection::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn converts_rows() {
+        let res = evm::parse_response(
+            br#"{"header":{"number":7,"hash":"0x0102","baseFeePerGas":"0x10"}}"#,
+        )
+        .unwrap();
+        let rows = rows(&res.blocks).unwrap();
+        let schema = res.blocks.schema();
+        let value = |row: &[SqlValue], name: &str| row[schema.index_of(name).unwrap()].clone();
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(value(&rows[0], "number"), SqlValue::UInt(7));
+        assert_eq!(value(&rows[0], "hash"), SqlValue::Blob(vec![1, 2]));
+        assert_eq!(
+            value(&rows[0], "base_fee_per_gas"),
+            SqlValue::Text("16".to_owned())
+        );
+        assert_eq!(value(&rows[0], "miner"), SqlValue::Null);
+    }
+}
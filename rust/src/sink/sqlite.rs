@@ -0,0 +1,84 @@
+//! SQLite database with a table per response table.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use arrow::datatypes::DataType;
+use arrow::record_batch::RecordBatch;
+use rusqlite::types::{ToSqlOutput, Value, ValueRef};
+use rusqlite::{params_from_iter, Connection, OptionalExtension, ToSql};
+
+use super::sql::{self, SqlConnection, SqlSink, SqlValue};
+use super::ArrowTables;
+
+/// Writes responses into SQLite tables, see `SqlSink`.
+///
+/// Binary columns are stored as blobs. Decimals, lists and structs are stored as text, as are
+/// `UInt64` values that don't fit into SQLite's signed integers.
+pub type SqliteSink<R> = SqlSink<Connection, R>;
+
+impl<R: ArrowTables> SqlSink<Connection, R> {
+    /// Opens the database file, creating it and the tables if they don't exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path).context("open database")?;
+        Self::new(conn)
+    }
+}
+
+impl SqlConnection for Connection {
+    fn column_type(data_type: &DataType) -> &'static str {
+        match data_type {
+            DataType::Boolean
+            | DataType::Int8
+            | DataType::Int64
+            | DataType::UInt8
+            | DataType::UInt16
+            | DataType::UInt32
+            | DataType::UInt64 => "INTEGER",
+            DataType::Binary => "BLOB",
+            _ => "TEXT",
+        }
+    }
+
+    fn write(&mut self, sql: &str, tables: &[(&'static str, &RecordBatch)]) -> Result<()> {
+        let tx = self.transaction().context("begin transaction")?;
+        tx.execute_batch(sql).context("execute statements")?;
+
+        for (table, batch) in tables {
+            let mut stmt = tx
+                .prepare(&sql::insert_sql(table, &batch.schema()))
+                .with_context(|| format!("prepare {} insert", table))?;
+            for row in sql::rows(batch).with_context(|| format!("convert {} table", table))? {
+                stmt.execute(params_from_iter(row.iter()))
+                    .with_context(|| format!("insert into {} table", table))?;
+            }
+        }
+
+        tx.commit().context("commit transaction")
+    }
+
+    fn query_u64(&self, sql: &str) -> Result<Option<u64>> {
+        let n = self
+            .query_row(sql, [], |row| row.get::<_, i64>(0))
+            .optional()
+            .context("query row")?;
+        n.map(|n| u64::try_from(n).context("convert to unsigned"))
+            .transpose()
+    }
+}
+
+impl ToSql for SqlValue {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            Self::Null => ToSqlOutput::Owned(Value::Null),
+            Self::Bool(v) => ToSqlOutput::Owned(Value::Integer((*v).into())),
+            Self::Int(v) => ToSqlOutput::Owned(Value::Integer(*v)),
+            Self::UInt(v) => match i64::try_from(*v) {
+                Ok(v) => ToSqlOutput::Owned(Value::Integer(v)),
+                Err(_) => ToSqlOutput::Owned(Value::Text(v.to_string())),
+            },
+            Self::Text(v) => ToSqlOutput::Borrowed(ValueRef::Text(v.as_bytes())),
+            Self::Blob(v) => ToSqlOutput::Borrowed(ValueRef::Blob(v)),
+        })
+    }
+}
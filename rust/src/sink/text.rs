@@ -0,0 +1,385 @@
+//! CSV and JSON Lines files with one file per table.
+//!
+//! Binary columns are written in the encoding that the portal uses for them and decimals are
+//! written as decimal strings, so the output can be opened in spreadsheets and read by tools
+//! that don't know about Arrow.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use arrow::array::{Array, ArrayRef, AsArray, ListArray, StringArray, StructArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::json::writer::LineDelimited;
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::{ArrayFormatter, FormatOptions};
+use futures_lite::{Stream, StreamExt};
+
+use super::{ArrowTables, BinaryEncoding};
+use crate::StreamItem;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextFormat {
+    /// Comma separated values with a header row. Lists and structs are written as text.
+    Csv,
+    /// A JSON object per line.
+    JsonLines,
+}
+
+impl TextFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::JsonLines => "jsonl",
+        }
+    }
+}
+
+/// Path of the file of a table in a directory that is written by `TextWriter::create`.
+pub fn table_path(dir: &Path, table: &str, format: TextFormat) -> PathBuf {
+    dir.join(format!("{}.{}", table, format.extension()))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TextOptions {
+    /// Columns to write for each table, in the order they should be written.
+    ///
+    /// Tables that aren't in the map are written with every column.
+    pub columns: BTreeMap<String, Vec<String>>,
+    /// Encoding of every binary column, instead of the one the portal uses for the column.
+    pub binary_encoding: Option<BinaryEncoding>,
+}
+
+enum TableWriter<W: Write> {
+    Csv(Box<arrow::csv::Writer<W>>),
+    JsonLines(arrow::json::Writer<W, LineDelimited>),
+}
+
+type Open<W> = Box<dyn FnMut(&'static str) -> Result<W> + Send>;
+
+/// Writes every table of the responses into its own CSV or JSON Lines output.
+pub struct TextWriter<W: Write> {
+    format: TextFormat,
+    options: TextOptions,
+    open: Open<W>,
+    writers: Vec<(&'static str, TableWriter<W>)>,
+}
+
+impl TextWriter<BufWriter<File>> {
+    /// Writes a `{table}.csv` or `{table}.jsonl` file per table into the directory.
+    pub fn create(
+        dir: impl Into<PathBuf>,
+        format: TextFormat,
+        options: TextOptions,
+    ) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).context("create directory")?;
+
+        Ok(Self::new(format, options, move |table| {
+            let path = table_path(&dir, table, format);
+            let file = File::create(&path).with_context(|| format!("create {}", path.display()))?;
+            Ok(BufWriter::new(file))
+        }))
+    }
+}
+
+impl<W: Write> TextWriter<W> {
+    /// `open` is called with the name of each table once the first response is written.
+    pub fn new(
+        format: TextFormat,
+        options: TextOptions,
+        open: impl FnMut(&'static str) -> Result<W> + Send + 'static,
+    ) -> Self {
+        Self {
+            format,
+            options,
+            open: Box::new(open),
+            writers: Vec::new(),
+        }
+    }
+
+    pub fn write<R: ArrowTables>(&mut self, res: &R) -> Result<()> {
+        let tables = res.tables();
+
+        if self.writers.is_empty() {
+            for (table, _) in tables.iter() {
+                let out = (self.open)(table).with_context(|| format!("open {} output", table))?;
+                let writer = match self.format {
+                    TextFormat::Csv => TableWriter::Csv(Box::new(
+                        arrow::csv::WriterBuilder::new()
+                            .with_header(true)
+                            .build(out),
+                    )),
+                    TextFormat::JsonLines => TableWriter::JsonLines(
+                        arrow::json::WriterBuilder::new()
+                            .with_explicit_nulls(true)
+                            .build(out),
+                    ),
+                };
+                self.writers.push((table, writer));
+            }
+        }
+
+        if tables.len() != self.writers.len() {
+            return Err(anyhow!(
+                "expected {} tables but got {}",
+                self.writers.len(),
+                tables.len()
+            ));
+        }
+
+        for ((table, writer), (_, batch)) in self.writers.iter_mut().zip(tables) {
+            let batch = encode_table::<R>(table, batch, &self.options, self.format)
+                .with_context(|| format!("encode {} table", table))?;
+            let res = match writer {
+                TableWriter::Csv(w) => w.write(&batch),
+                TableWriter::JsonLines(w) => w.write(&batch),
+            };
+            res.with_context(|| format!("write {} table", table))?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes every item of the stream and finishes the outputs when the stream ends.
+    pub async fn write_stream<R, S>(mut self, stream: S) -> Result<Vec<(&'static str, W)>>
+    where
+        R: ArrowTables,
+        S: Stream<Item = Result<StreamItem<R>>>,
+    {
+        futures_lite::pin!(stream);
+
+        while let Some(item) = stream.next().await {
+            let item = item.context("get stream item")?;
+            self.write(&item.data)?;
+        }
+
+        self.finish()
+    }
+
+    /// Flushes the outputs and returns them along with the names of their tables.
+    pub fn finish(self) -> Result<Vec<(&'static str, W)>> {
+        self.writers
+            .into_iter()
+            .map(|(table, writer)| {
+                let mut out = match writer {
+                    TableWriter::Csv(w) => (*w).into_inner(),
+                    TableWriter::JsonLines(mut w) => {
+                        w.finish()
+                            .with_context(|| format!("finish {} table", table))?;
+                        w.into_inner()
+                    }
+                };
+                out.flush()
+                    .with_context(|| format!("flush {} output", table))?;
+                Ok((table, out))
+            })
+            .collect()
+    }
+}
+
+/// Selects the columns of the table and converts them into types that the text writers can
+/// write the way we want.
+fn encode_table<R: ArrowTables>(
+    table: &str,
+    batch: &RecordBatch,
+    options: &TextOptions,
+    format: TextFormat,
+) -> Result<RecordBatch> {
+    let indices = match options.columns.get(table) {
+        Some(columns) => columns
+            .iter()
+            .map(|c| {
+                batch
+                    .schema()
+                    .index_of(c)
+                    .with_context(|| format!("find {} column", c))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => (0..batch.num_columns()).collect(),
+    };
+
+    let schema = batch.schema();
+    let mut fields = Vec::with_capacity(indices.len());
+    let mut columns = Vec::with_capacity(indices.len());
+
+    for i in indices {
+        let field = schema.field(i);
+        let encoding = options
+            .binary_encoding
+            .unwrap_or_else(|| R::binary_encoding(table, field.name()));
+        let mut column = encode_column(batch.column(i), encoding)
+            .with_context(|| format!("encode {} column", field.name()))?;
+        if format == TextFormat::Csv
+            && matches!(column.data_type(), DataType::List(_) | DataType::Struct(_))
+        {
+            column = format_column(column.as_ref())
+                .with_context(|| format!("format {} column", field.name()))?;
+        }
+        fields.push(Field::new(
+            field.name(),
+            column.data_type().clone(),
+            field.is_nullable(),
+        ));
+        columns.push(column);
+    }
+
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).context("create batch")
+}
+
+/// Converts binary values into strings and decimals into decimal strings, including the ones
+/// inside lists and structs.
+fn encode_column(array: &ArrayRef, encoding: BinaryEncoding) -> Result<ArrayRef> {
+    match array.data_type() {
+        DataType::Binary => {
+            let array = array.as_binary::<i32>();
+            let encoded = array
+                .iter()
+                .map(|v| v.map(|v| encode_binary(v, encoding)))
+                .collect::<StringArray>();
+            Ok(Arc::new(encoded))
+        }
+        DataType::Decimal128(..) | DataType::Decimal256(..) => {
+            cast(array, &DataType::Utf8).context("cast decimal to string")
+        }
+        DataType::List(field) => {
+            let array = array.as_list::<i32>();
+            let values = encode_column(array.values(), encoding)?;
+            let field = Field::new(
+                field.name(),
+                values.data_type().clone(),
+                field.is_nullable(),
+            );
+            let array = ListArray::try_new(
+                Arc::new(field),
+                array.offsets().clone(),
+                values,
+                array.nulls().cloned(),
+            )
+            .context("create list")?;
+            Ok(Arc::new(array))
+        }
+        DataType::Struct(fields) => {
+            let array = array.as_struct();
+            let columns = array
+                .columns()
+                .iter()
+                .map(|c| encode_column(c, encoding))
+                .collect::<Result<Vec<_>>>()?;
+            let fields = fields
+                .iter()
+                .zip(columns.iter())
+                .map(|(f, c)| Field::new(f.name(), c.data_type().clone(), f.is_nullable()))
+                .collect::<Vec<_>>();
+            let array = StructArray::try_new(fields.into(), columns, array.nulls().cloned())
+                .context("create struct")?;
+            Ok(Arc::new(array))
+        }
+        _ => Ok(array.clone()),
+    }
+}
+
+fn encode_binary(v: &[u8], encoding: BinaryEncoding) -> String {
+    match encoding {
+        BinaryEncoding::Hex => format!("0x{}", faster_hex::hex_string(v)),
+        BinaryEncoding::Base58 => bs58::encode(v).into_string(),
+    }
+}
+
+/// Formats lists and structs as text since CSV can't hold nested values.
+fn format_column(array: &dyn Array) -> Result<ArrayRef> {
+    let formatter =
+        ArrayFormatter::try_new(array, &FormatOptions::default()).context("create formatter")?;
+    let formatted = (0..array.len())
+        .map(|i| {
+            if array.is_null(i) {
+                None
+            } else {
+                Some(formatter.value(i).to_string())
+            }
+        })
+        .collect::<StringArray>();
+    Ok(Arc::new(formatted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{evm, svm};
+
+    fn write<R: ArrowTables>(
+        res: &R,
+        format: TextFormat,
+        options: TextOptions,
+    ) -> BTreeMap<&'static str, String> {
+        let mut writer = TextWriter::new(format, options, |_| Ok(Vec::new()));
+        writer.write(res).unwrap();
+        writer
+            .finish()
+            .unwrap()
+            .into_iter()
+            .map(|(table, out)| (table, String::from_utf8(out).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn evm_csv() {
+        let res = evm::parse_response(
+            br#"{"header":{"number":7,"hash":"0x0102","baseFeePerGas":"0x10"}}"#,
+        )
+        .unwrap();
+        let options = TextOptions {
+            columns: [(
+                "blocks".to_owned(),
+                vec![
+                    "number".to_owned(),
+                    "hash".to_owned(),
+                    "base_fee_per_gas".to_owned(),
+                ],
+            )]
+            .into(),
+            ..Default::default()
+        };
+
+        let out = write(&res, TextFormat::Csv, options);
+        assert_eq!(out["blocks"], "number,hash,base_fee_per_gas\n7,0x0102,16\n");
+    }
+
+    #[test]
+    fn svm_json_lines() {
+        let res = svm::parse_response(
+            br#"{"header":{"number":1},"instructions":[{"transactionIndex":0,"instructionAddress":[0],"programId":"11111111111111111111111111111112","d1":"0x02"}]}"#,
+        )
+        .unwrap();
+        let options = TextOptions {
+            columns: [(
+                "instructions".to_owned(),
+                vec!["program_id".to_owned(), "d1".to_owned()],
+            )]
+            .into(),
+            ..Default::default()
+        };
+
+        let out = write(&res, TextFormat::JsonLines, options);
+        assert_eq!(
+            out["instructions"],
+            "{\"program_id\":\"11111111111111111111111111111112\",\"d1\":\"0x02\"}\n"
+        );
+    }
+
+    #[test]
+    fn missing_column() {
+        let res = evm::parse_response(br#"{"header":{"number":1}}"#).unwrap();
+        let options = TextOptions {
+            columns: [("blocks".to_owned(), vec!["nope".to_owned()])].into(),
+            ..Default::default()
+        };
+
+        let mut writer = TextWriter::new(TextFormat::Csv, options, |_| Ok(Vec::new()));
+        assert!(writer.write(&res).is_err());
+    }
+}
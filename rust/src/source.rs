@@ -0,0 +1,207 @@
+//! Sources that streams can read blocks from.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures_lite::Stream;
+use reqwest::Url;
+use tokio::sync::mpsc;
+
+use crate::query::PortalQuery;
+use crate::raw::Raw;
+use crate::rows::{RowQuery, Rows};
+use crate::sink::ArrowTables;
+use crate::{evm, stream, svm, Client, StreamConfig, StreamHandle, StreamItem};
+
+#[cfg(feature = "parquet")]
+pub mod parquet;
+
+/// Serves the responses of the portal, so the stream machinery can run against something other
+/// than a portal, like an archive of earlier responses.
+///
+/// `Client` implements it by querying the portal.
+pub trait DataSource: Send + Sync {
+    /// Identifies the source in metrics and traces.
+    fn url(&self) -> &Url;
+
+    /// Returns the last block that can be queried.
+    fn finalized_height(&self) -> impl Future<Output = Result<u64>> + Send;
+
+    /// Runs the query from its `from_block` and returns the response along with the number of
+    /// bytes it was read from.
+    ///
+    /// The response can end before `to_block` but it has to include its last block so streams
+    /// know where to continue. Returns `None` if the source doesn't have `from_block` yet.
+    fn evm_query(
+        &self,
+        query: &evm::Query,
+    ) -> impl Future<Output = Result<Option<(evm::ArrowResponse, usize)>>> + Send;
+
+    /// Same as `evm_query` but for SVM queries.
+    fn svm_query(
+        &self,
+        query: &svm::Query,
+    ) -> impl Future<Output = Result<Option<(svm::ArrowResponse, usize)>>> + Send;
+
+    /// Streams the query without spawning a task, see `Client::evm_arrow_finalized_lazy_stream`.
+    fn evm_arrow_finalized_lazy_stream(
+        &self,
+        query: evm::Query,
+        config: StreamConfig,
+    ) -> impl Stream<Item = Result<StreamItem<evm::ArrowResponse>>> + Send + '_
+    where
+        Self: Sized,
+    {
+        stream::lazy_stream(self, query, config)
+    }
+
+    /// Streams the query in a tokio task, see `Client::evm_arrow_finalized_stream`.
+    fn evm_arrow_finalized_stream(
+        self: Arc<Self>,
+        query: evm::Query,
+        config: StreamConfig,
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<evm::ArrowResponse>>>,
+        StreamHandle,
+    )
+    where
+        Self: Sized + 'static,
+    {
+        stream::spawn_stream(self, query, config, None)
+    }
+
+    /// Same as `evm_arrow_finalized_stream` but ends the stream after the first response
+    /// `pred` returns true for, see `Client::evm_arrow_finalized_stream_until`.
+    fn evm_arrow_finalized_stream_until(
+        self: Arc<Self>,
+        query: evm::Query,
+        config: StreamConfig,
+        pred: impl FnMut(&evm::ArrowResponse) -> bool + Send + 'static,
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<evm::ArrowResponse>>>,
+        StreamHandle,
+    )
+    where
+        Self: Sized + 'static,
+    {
+        stream::spawn_stream(self, query, config, Some(Box::new(pred)))
+    }
+
+    /// Streams the query without spawning a task, see `Client::svm_arrow_finalized_lazy_stream`.
+    fn svm_arrow_finalized_lazy_stream(
+        &self,
+        query: svm::Query,
+        config: StreamConfig,
+    ) -> impl Stream<Item = Result<StreamItem<svm::ArrowResponse>>> + Send + '_
+    where
+        Self: Sized,
+    {
+        stream::lazy_stream(self, query, config)
+    }
+
+    /// Streams the query in a tokio task, see `Client::svm_arrow_finalized_stream`.
+    fn svm_arrow_finalized_stream(
+        self: Arc<Self>,
+        query: svm::Query,
+        config: StreamConfig,
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<svm::ArrowResponse>>>,
+        StreamHandle,
+    )
+    where
+        Self: Sized + 'static,
+    {
+        stream::spawn_stream(self, query, config, None)
+    }
+
+    /// Same as `svm_arrow_finalized_stream` but ends the stream after the first response
+    /// `pred` returns true for, see `Client::svm_arrow_finalized_stream_until`.
+    fn svm_arrow_finalized_stream_until(
+        self: Arc<Self>,
+        query: svm::Query,
+        config: StreamConfig,
+        pred: impl FnMut(&svm::ArrowResponse) -> bool + Send + 'static,
+    ) -> (
+        mpsc::Receiver<Result<StreamItem<svm::ArrowResponse>>>,
+        StreamHandle,
+    )
+    where
+        Self: Sized + 'static,
+    {
+        stream::spawn_stream(self, query, config, Some(Box::new(pred)))
+    }
+}
+
+impl DataSource for Client {
+    fn url(&self) -> &Url {
+        &self.url
+    }
+
+    fn finalized_height(&self) -> impl Future<Output = Result<u64>> + Send {
+        Client::finalized_height(self)
+    }
+
+    fn evm_query(
+        &self,
+        query: &evm::Query,
+    ) -> impl Future<Output = Result<Option<(evm::ArrowResponse, usize)>>> + Send {
+        self.arrow_finalized_query(query)
+    }
+
+    fn svm_query(
+        &self,
+        query: &svm::Query,
+    ) -> impl Future<Output = Result<Option<(svm::ArrowResponse, usize)>>> + Send {
+        self.arrow_finalized_query(query)
+    }
+}
+
+/// A query that can be run against the source `S`.
+///
+/// Typed and raw responses need the response body so only the portal can serve them.
+pub(crate) trait SourceQuery<S>: PortalQuery {
+    fn run(
+        source: &S,
+        query: &Self,
+    ) -> impl Future<Output = Result<Option<(Self::Response, usize)>>> + Send;
+}
+
+impl<S: DataSource> SourceQuery<S> for evm::Query {
+    fn run(
+        source: &S,
+        query: &Self,
+    ) -> impl Future<Output = Result<Option<(Self::Response, usize)>>> + Send {
+        source.evm_query(query)
+    }
+}
+
+impl<S: DataSource> SourceQuery<S> for svm::Query {
+    fn run(
+        source: &S,
+        query: &Self,
+    ) -> impl Future<Output = Result<Option<(Self::Response, usize)>>> + Send {
+        source.svm_query(query)
+    }
+}
+
+impl<Q: RowQuery> SourceQuery<Client> for Rows<Q> {
+    fn run(
+        client: &Client,
+        query: &Self,
+    ) -> impl Future<Output = Result<Option<(Self::Response, usize)>>> + Send {
+        client.arrow_finalized_query(query)
+    }
+}
+
+impl<Q: PortalQuery> SourceQuery<Client> for Raw<Q>
+where
+    Q::Response: ArrowTables,
+{
+    fn run(
+        client: &Client,
+        query: &Self,
+    ) -> impl Future<Output = Result<Option<(Self::Response, usize)>>> + Send {
+        client.arrow_finalized_query(query)
+    }
+}
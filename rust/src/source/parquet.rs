@@ -0,0 +1,242 @@
+//! Replays the Parquet files that are written by `sink::parquet::ParquetSink`.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use anyhow::{anyhow, Context, Result};
+use arrow::array::{new_null_array, ArrayRef, UInt64Array};
+use arrow::compute::{cast, concat_batches};
+use arrow::record_batch::RecordBatch;
+use reqwest::Url;
+
+use super::DataSource;
+use crate::mux::u64_values;
+use crate::query::PortalQuery;
+use crate::sink::parquet::completed_ranges;
+use crate::sink::ArrowTables;
+use crate::{evm, svm};
+
+const BLOCKS: &str = "blocks";
+
+/// Serves responses from a directory that was written by `ParquetSink`, so streams can run
+/// without network access.
+///
+/// Each response covers the rest of the file range that contains `from_block`, and the head is
+/// the end of the last completed range. The block range, filters and `include_all_blocks` of the
+/// query are applied to the archived rows but the columns are served as they were archived, so
+/// the archive has to be written by a query that selects every field the queries need.
+///
+/// File IO is blocking.
+#[derive(Debug, Clone)]
+pub struct ParquetArchiveSource {
+    dir: PathBuf,
+    url: Url,
+}
+
+impl ParquetArchiveSource {
+    /// Opens the directory, ranges that are completed later are picked up by the next query.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = fs::canonicalize(dir.as_ref()).context("resolve directory")?;
+        let url = Url::from_directory_path(&dir)
+            .map_err(|()| anyhow!("{} can't be used as a url", dir.display()))?;
+
+        Ok(Self { dir, url })
+    }
+
+    fn ranges(&self) -> Result<Vec<(u64, u64)>> {
+        completed_ranges(&self.dir.join(BLOCKS)).context("read completed ranges")
+    }
+
+    fn read<Q: PortalQuery>(&self, query: &Q) -> Result<Option<(Q::Response, usize)>>
+    where
+        Q::Response: ArrowTables,
+    {
+        let from_block = query.cursor();
+        let ranges = self.ranges()?;
+        let Some(&(start, end)) = ranges.iter().find(|(_, to)| *to >= from_block) else {
+            return Ok(None);
+        };
+        if start > from_block {
+            return Err(anyhow!(
+                "archive has no data for block {}, the next range starts at block {}",
+                from_block,
+                start
+            ));
+        }
+        let to_block = query.to_block().map_or(end, |tb| tb.min(end));
+
+        let name = format!("{}-{}.parquet", start, end);
+        let mut tables = Vec::with_capacity(Q::Response::TABLE_NAMES.len());
+        let mut num_bytes = 0;
+        for table in Q::Response::TABLE_NAMES {
+            let path = self.dir.join(table).join(&name);
+            let (batch, n) = read_table(&path).with_context(|| format!("read {} table", table))?;
+            tables.push(batch);
+            num_bytes += n;
+        }
+        let archived = Q::Response::from_tables(tables).context("build response")?;
+
+        let mut query = query.clone();
+        query.set_to_block(Some(to_block));
+        let res = Q::demux(&archived, &query).context("apply query")?;
+        let res = with_last_block(&archived, res, to_block).context("add last block")?;
+
+        Ok(Some((res, num_bytes)))
+    }
+}
+
+impl DataSource for ParquetArchiveSource {
+    fn url(&self) -> &Url {
+        &self.url
+    }
+
+    async fn finalized_height(&self) -> Result<u64> {
+        self.ranges()?
+            .last()
+            .map(|(_, to)| *to)
+            .context("archive has no completed ranges")
+    }
+
+    async fn evm_query(&self, query: &evm::Query) -> Result<Option<(evm::ArrowResponse, usize)>> {
+        self.read(query)
+    }
+
+    async fn svm_query(&self, query: &svm::Query) -> Result<Option<(svm::ArrowResponse, usize)>> {
+        self.read(query)
+    }
+}
+
+/// Reads the file into a single batch and returns it along with the size of the file.
+fn read_table(path: &Path) -> Result<(RecordBatch, usize)> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let num_bytes = file.metadata().context("read file metadata")?.len();
+
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file).context("create reader")?;
+    let schema = reader.schema().clone();
+    let batches = reader
+        .build()
+        .context("build reader")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("read batches")?;
+    let batch = concat_batches(&schema, &batches).context("concat batches")?;
+
+    Ok((batch, usize::try_from(num_bytes).unwrap_or(usize::MAX)))
+}
+
+/// Makes sure the response ends with `to_block` like the portal's responses do, so streams
+/// continue after it.
+///
+/// The row is taken from the archive if the block is there and has only the block number
+/// otherwise, like a block without any fields selected.
+fn with_last_block<R: ArrowTables>(archived: &R, res: R, to_block: u64) -> Result<R> {
+    let column = R::BLOCK_COLUMNS[0];
+    let mut tables = res
+        .tables()
+        .into_iter()
+        .map(|(_, batch)| batch.clone())
+        .collect::<Vec<_>>();
+
+    let blocks = &tables[0];
+    if u64_values(blocks, column)?.last() == Some(&Some(to_block)) {
+        return Ok(res);
+    }
+
+    let (_, archived_blocks) = archived.tables()[0];
+    let row = match u64_values(archived_blocks, column)?
+        .iter()
+        .position(|n| *n == Some(to_block))
+    {
+        Some(i) => archived_blocks.slice(i, 1),
+        None => {
+            let columns = blocks
+                .schema()
+                .fields()
+                .iter()
+                .map(|field| {
+                    if field.name() == column {
+                        let number: ArrayRef = Arc::new(UInt64Array::from(vec![to_block]));
+                        cast(&number, field.data_type()).context("cast block number")
+                    } else {
+                        Ok(new_null_array(field.data_type(), 1))
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            RecordBatch::try_new(blocks.schema(), columns).context("build block row")?
+        }
+    };
+    let blocks = concat_batches(&blocks.schema(), [blocks, &row]).context("append block")?;
+    tables[0] = blocks;
+
+    R::from_tables(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::StreamExt;
+
+    use super::*;
+    use crate::sink::parquet::{ParquetSink, ParquetSinkConfig};
+    use crate::test_util::{evm_item, test_dir, ADDRESS_A};
+    use crate::StreamConfig;
+
+    fn block_numbers(res: &evm::ArrowResponse) -> Vec<u64> {
+        u64_values(&res.blocks, "number")
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn replays_archived_ranges() {
+        let dir = test_dir("archive");
+        let config = ParquetSinkConfig {
+            max_blocks: Some(20),
+            ..Default::default()
+        };
+        let mut sink = ParquetSink::open(&dir, config).unwrap();
+        for (from, to) in [(0, 9), (10, 19), (20, 24)] {
+            sink.write(&evm_item(from, to)).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let source = ParquetArchiveSource::open(&dir).unwrap();
+        assert_eq!(source.finalized_height().await.unwrap(), 24);
+
+        let query = evm::Query {
+            from_block: 5,
+            to_block: Some(22),
+            logs: vec![evm::LogRequest {
+                address: vec![ADDRESS_A.to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let items = source
+            .evm_arrow_finalized_lazy_stream(query.clone(), StreamConfig::default())
+            .map(|item| item.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        let ranges = items
+            .iter()
+            .map(|item| (item.from_block, item.to_block))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, [(5, 19), (20, 22)]);
+        assert_eq!(block_numbers(&items[0].data), [10, 19]);
+        assert_eq!(items[0].data.logs.num_rows(), 1);
+        // block 22 isn't archived so it only has the number
+        assert_eq!(block_numbers(&items[1].data), [20, 22]);
+        assert_eq!(items[1].data.logs.num_rows(), 1);
+
+        let query = evm::Query {
+            from_block: 25,
+            ..query
+        };
+        assert!(source.evm_query(&query).await.unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
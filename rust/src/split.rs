@@ -0,0 +1,264 @@
+use anyhow::{Context, Result};
+use arrow::array::UInt32Array;
+use arrow::compute::{concat_batches, take_record_batch};
+use arrow::record_batch::RecordBatch;
+use arrow::row::{RowConverter, SortField};
+
+/// Splits the request into copies that have at most `max_values` filter values in total and
+/// returns them along with the number of values they have.
+///
+/// The portal matches any of the values in a list and the items that match every list, so the
+/// copies together match the same items as the original request. Every list keeps at least one
+/// value, so a copy has more than `max_values` values if it has more non-empty lists than that.
+///
+/// The values are shared evenly between the lists, since the copies are every combination of
+/// the chunks of the lists and their number grows with the product of the chunk counts.
+pub(crate) fn split_request<R: Clone>(
+    mut req: R,
+    max_values: usize,
+    lists: fn(&mut R) -> Vec<&mut Vec<String>>,
+) -> Vec<(R, usize)> {
+    let max_values = max_values.max(1);
+
+    let lens = lists(&mut req)
+        .iter()
+        .map(|list| list.len())
+        .collect::<Vec<_>>();
+    let num_values = lens.iter().sum::<usize>();
+    if num_values <= max_values {
+        return vec![(req, num_values)];
+    }
+
+    // shortest lists first, so the values they don't need go to the longer ones
+    let mut order = (0..lens.len()).filter(|i| lens[*i] > 0).collect::<Vec<_>>();
+    order.sort_by_key(|i| lens[*i]);
+    let mut chunk_sizes = vec![0; lens.len()];
+    let mut budget = max_values;
+    for (n, i) in order.iter().enumerate() {
+        let share = (budget / (order.len() - n)).max(1);
+        chunk_sizes[*i] = lens[*i].min(share);
+        budget = budget.saturating_sub(chunk_sizes[*i]);
+    }
+
+    let mut parts = vec![req];
+    for (i, chunk_size) in chunk_sizes.into_iter().enumerate() {
+        if chunk_size == 0 || chunk_size >= lens[i] {
+            continue;
+        }
+        parts = parts
+            .into_iter()
+            .flat_map(|mut part| {
+                let values = std::mem::take(lists(&mut part)[i]);
+                values
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        let mut part = part.clone();
+                        *lists(&mut part)[i] = chunk.to_vec();
+                        part
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+    }
+
+    parts
+        .into_iter()
+        .map(|mut part| {
+            let num_values = lists(&mut part).iter().map(|list| list.len()).sum();
+            (part, num_values)
+        })
+        .collect()
+}
+
+/// Groups the items in order so the total cost of each group is at most `max_cost`.
+///
+/// Items that cost more than `max_cost` get a group of their own.
+pub(crate) fn pack<T>(items: Vec<(T, usize)>, max_cost: usize) -> Vec<Vec<T>> {
+    let mut groups = Vec::new();
+    let mut group = Vec::new();
+    let mut cost = 0;
+
+    for (item, item_cost) in items {
+        if !group.is_empty() && cost + item_cost > max_cost {
+            groups.push(std::mem::take(&mut group));
+            cost = 0;
+        }
+        group.push(item);
+        cost += item_cost;
+    }
+
+    if !group.is_empty() {
+        groups.push(group);
+    }
+
+    groups
+}
+
+/// Merges a table of every response, see `merge_batches`.
+pub(crate) fn merge_table<T>(
+    responses: &[T],
+    table: impl Fn(&T) -> &RecordBatch,
+    order_by: &[&str],
+) -> Result<RecordBatch> {
+    let batches = responses.iter().map(table).collect::<Vec<_>>();
+    merge_batches(&batches, order_by)
+}
+
+/// Concatenates the batches, sorts the rows by the `order_by` columns and removes duplicate rows.
+///
+/// Rows are compared by all of their columns, so the columns that identify a row have to be
+/// selected to keep distinct rows that have the same values in the other columns.
+pub(crate) fn merge_batches(batches: &[&RecordBatch], order_by: &[&str]) -> Result<RecordBatch> {
+    let schema = batches.first().context("no batches to merge")?.schema();
+    let batch = concat_batches(&schema, batches.iter().copied()).context("concat batches")?;
+
+    let mut columns = Vec::with_capacity(batch.num_columns());
+    for name in order_by {
+        let column = batch
+            .column_by_name(name)
+            .with_context(|| format!("get {} col", name))?;
+        columns.push(column.clone());
+    }
+    for (field, column) in schema.fields().iter().zip(batch.columns()) {
+        if !order_by.contains(&field.name().as_str()) {
+            columns.push(column.clone());
+        }
+    }
+
+    let converter = RowConverter::new(
+        columns
+            .iter()
+            .map(|c| SortField::new(c.data_type().clone()))
+            .collect(),
+    )
+    .context("create row converter")?;
+    let rows = converter
+        .convert_columns(&columns)
+        .context("convert columns to rows")?;
+
+    let num_rows = u32::try_from(batch.num_rows()).context("too many rows to merge")?;
+    let mut indices = (0..num_rows).collect::<Vec<_>>();
+    indices.sort_unstable_by(|a, b| rows.row(*a as usize).cmp(&rows.row(*b as usize)));
+    indices.dedup_by(|a, b| rows.row(*a as usize) == rows.row(*b as usize));
+
+    take_record_batch(&batch, &UInt32Array::from(indices)).context("take merged rows")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{AsArray, StringArray, UInt64Array};
+    use arrow::datatypes::UInt64Type;
+    use std::sync::Arc;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Req {
+        a: Vec<String>,
+        b: Vec<String>,
+    }
+
+    fn lists(req: &mut Req) -> Vec<&mut Vec<String>> {
+        vec![&mut req.a, &mut req.b]
+    }
+
+    fn values(prefix: &str, n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("{}{}", prefix, i)).collect()
+    }
+
+    #[test]
+    fn split_request_budgets_total_values() {
+        let req = Req {
+            a: values("a", 5),
+            b: values("b", 3),
+        };
+
+        let parts = split_request(req.clone(), 2, lists);
+        assert_eq!(parts.len(), 15);
+        for (part, num_values) in parts.iter() {
+            assert_eq!(part.a.len() + part.b.len(), *num_values);
+            assert!(*num_values <= 2);
+        }
+        assert_eq!(parts[0].0.a, ["a0"]);
+        assert_eq!(parts[0].0.b, ["b0"]);
+
+        // every combination of values is still matched by one of the parts
+        for a in req.a.iter() {
+            for b in req.b.iter() {
+                assert!(parts
+                    .iter()
+                    .any(|(p, _)| p.a.contains(a) && p.b.contains(b)));
+            }
+        }
+
+        // the values are shared between the lists
+        let parts = split_request(req.clone(), 5, lists);
+        let sizes = parts
+            .iter()
+            .map(|(p, n)| (p.a.len(), p.b.len(), *n))
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [(3, 2, 5), (3, 1, 4), (2, 2, 4), (2, 1, 3)]);
+
+        assert_eq!(split_request(req.clone(), 8, lists), [(req, 8)]);
+    }
+
+    #[test]
+    fn split_request_balances_long_lists() {
+        let req = Req {
+            a: values("a", 5000),
+            b: values("b", 5000),
+        };
+
+        let parts = split_request(req, 1000, lists);
+        assert_eq!(parts.len(), 100);
+        for (part, num_values) in parts.iter() {
+            assert_eq!((part.a.len(), part.b.len(), *num_values), (500, 500, 1000));
+        }
+    }
+
+    #[test]
+    fn split_request_keeps_a_value_per_list() {
+        let req = Req {
+            a: values("a", 2),
+            b: values("b", 1),
+        };
+
+        let parts = split_request(req, 1, lists);
+        let sizes = parts.iter().map(|(_, n)| *n).collect::<Vec<_>>();
+        assert_eq!(sizes, [2, 2]);
+    }
+
+    #[test]
+    fn pack_respects_max_cost() {
+        let groups = pack(vec![("a", 2), ("b", 2), ("c", 5), ("d", 1)], 4);
+        assert_eq!(groups, [vec!["a", "b"], vec!["c"], vec!["d"]]);
+    }
+
+    #[test]
+    fn merge_batches_sorts_and_dedups() {
+        let batch = |numbers: Vec<u64>, names: Vec<&str>| {
+            RecordBatch::try_from_iter([
+                (
+                    "name",
+                    Arc::new(StringArray::from(names)) as arrow::array::ArrayRef,
+                ),
+                ("number", Arc::new(UInt64Array::from(numbers)) as _),
+            ])
+            .unwrap()
+        };
+
+        let a = batch(vec![1, 3, 5], vec!["x", "y", "z"]);
+        let b = batch(vec![2, 3, 3], vec!["w", "y", "q"]);
+        let merged = merge_batches(&[&a, &b], &["number"]).unwrap();
+
+        let numbers = merged
+            .column_by_name("number")
+            .unwrap()
+            .as_primitive::<UInt64Type>();
+        assert_eq!(numbers.values(), &[1, 2, 3, 3, 5]);
+        let names = merged.column_by_name("name").unwrap().as_string::<i32>();
+        assert_eq!(
+            names.iter().flatten().collect::<Vec<_>>(),
+            ["x", "w", "q", "y", "z"]
+        );
+    }
+}
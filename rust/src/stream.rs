@@ -0,0 +1,709 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use futures_lite::{Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::source::{DataSource, SourceQuery};
+use crate::{telemetry, PortalQuery, StreamConfig};
+
+/// Reason a stream stopped without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEnd {
+    /// Every block up to the query's `to_block` was returned.
+    ///
+    /// Streams limited by `StreamConfig::stop_at_start_head` end this way too.
+    ReachedToBlock,
+    /// `StreamConfig::max_blocks` blocks were returned before the query's `to_block`.
+    MaxBlocks,
+    /// `StreamConfig::max_rows`, `StreamConfig::stop_at_timestamp` or the stop predicate
+    /// of the stream was reached.
+    StopConditionMet,
+    /// The portal had no more data and `StreamConfig::stop_on_head` was set.
+    ReachedHead,
+    /// `StreamHandle::cancel` was called.
+    Cancelled,
+    /// The receiver was dropped.
+    ReceiverClosed,
+}
+
+/// Controls a stream task that was spawned by one of the `*_arrow_finalized_stream` methods.
+///
+/// Dropping the handle does not stop the stream, it keeps running until the receiver is dropped.
+#[derive(Debug)]
+pub struct StreamHandle {
+    cancel: CancellationToken,
+    task: JoinHandle<Result<StreamEnd>>,
+}
+
+impl StreamHandle {
+    pub(crate) fn new(cancel: CancellationToken, task: JoinHandle<Result<StreamEnd>>) -> Self {
+        Self { cancel, task }
+    }
+
+    /// Stops the stream, interrupting any in-flight request or sleep.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Waits for the stream task to exit.
+    ///
+    /// Returns the error that stopped the stream if there was one.
+    /// The same error is also sent to the receiver if it is still open.
+    pub async fn join(self) -> Result<StreamEnd> {
+        self.task.await.context("join stream task")?
+    }
+}
+
+/// Receiving end of a spawned stream.
+pub type StreamReceiver<T> = mpsc::Receiver<Result<StreamItem<T>>>;
+
+/// Ends a stream after the first response it returns true for, that response is yielded as well.
+pub type StopPredicate<T> = Box<dyn FnMut(&T) -> bool + Send>;
+
+/// A response yielded by a stream along with information about the request that produced it.
+#[derive(Debug)]
+pub struct StreamItem<T> {
+    /// First block of the scanned range.
+    pub from_block: u64,
+    /// Last block of the scanned range.
+    ///
+    /// Blocks in `from_block..=to_block` that don't appear in `data` had no matching items.
+    pub to_block: u64,
+    /// Finalized head of the portal as last seen by the stream, it is never behind `to_block`.
+    ///
+    /// It is only refreshed once the stream moves past it. If refreshing fails, the last known
+    /// head or `to_block` is used instead.
+    pub head: u64,
+    /// Size of the response body.
+    pub num_bytes: usize,
+    /// Time it took to get the response, including retries.
+    pub duration: Duration,
+    pub data: T,
+}
+
+impl<T> StreamItem<T> {
+    /// Number of blocks in the scanned range.
+    pub fn num_blocks(&self) -> u64 {
+        self.to_block + 1 - self.from_block
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> StreamItem<U> {
+        StreamItem {
+            from_block: self.from_block,
+            to_block: self.to_block,
+            head: self.head,
+            num_bytes: self.num_bytes,
+            duration: self.duration,
+            data: f(self.data),
+        }
+    }
+}
+
+/// Item of the streams created by the `*_arrow_finalized_event_stream` methods.
+#[derive(Debug)]
+pub enum StreamEvent<T> {
+    /// A response from the portal. It carries the progress of the stream as well.
+    Data(StreamItem<T>),
+    /// The portal has no data for the cursor yet.
+    Idle(Idle),
+}
+
+/// Emitted while a stream is waiting for the portal to finalize new blocks.
+#[derive(Debug, Clone, Copy)]
+pub struct Idle {
+    /// Next block the stream is going to request.
+    pub cursor: u64,
+    /// Latest finalized head of the portal.
+    pub head: u64,
+    /// Time since the stream started waiting.
+    pub waited: Duration,
+    /// Time until the portal is polled again.
+    pub next_poll_in: Duration,
+}
+
+pub(crate) enum Event<R> {
+    Data(StreamItem<R>),
+    Idle(Idle),
+    End(StreamEnd),
+}
+
+/// Decides how long to wait before polling the portal again when a stream is at the head.
+struct HeadPoller {
+    min: Duration,
+    max: Duration,
+    backoff: Duration,
+    last_head: Option<(u64, Instant)>,
+    block_time: Option<Duration>,
+}
+
+impl HeadPoller {
+    fn new(config: &StreamConfig) -> Self {
+        let min = Duration::from_millis(config.head_poll_interval_millis);
+        let max = Duration::from_millis(config.max_head_poll_interval_millis).max(min);
+
+        Self {
+            min,
+            max,
+            backoff: min,
+            last_head: None,
+            block_time: None,
+        }
+    }
+
+    fn observe_head(&mut self, head: u64, now: Instant) {
+        match self.last_head {
+            Some((last, at)) if head > last => {
+                let block_time =
+                    now.duration_since(at) / u32::try_from(head - last).unwrap_or(u32::MAX);
+                // smooth it out so a single late update doesn't throw off the estimate
+                self.block_time = Some(match self.block_time {
+                    Some(bt) => (bt + block_time) / 2,
+                    None => block_time,
+                });
+                self.last_head = Some((head, now));
+            }
+            Some(_) => (),
+            None => self.last_head = Some((head, now)),
+        }
+    }
+
+    fn next_delay(&mut self, cursor: u64, head: u64) -> Duration {
+        let delay = match self.block_time {
+            Some(bt) => {
+                let blocks_left = cursor.saturating_sub(head).max(1);
+                bt * u32::try_from(blocks_left).unwrap_or(u32::MAX)
+            }
+            None => {
+                let delay = self.backoff;
+                self.backoff = (self.backoff * 2).min(self.max);
+                delay
+            }
+        };
+
+        delay.clamp(self.min, self.max)
+    }
+
+    fn reset_backoff(&mut self) {
+        self.backoff = self.min;
+    }
+}
+
+/// Polls the source lazily in the caller's task.
+///
+/// Always yields an `Event::End` as the last item unless an error is yielded first.
+pub(crate) fn events<S: DataSource, Q: SourceQuery<S>>(
+    source: &S,
+    query: Q,
+    config: StreamConfig,
+    mut stop_when: Option<StopPredicate<Q::Response>>,
+) -> impl Stream<Item = Result<Event<Q::Response>>> + Send + '_ {
+    let mut query = query;
+    // we need this to iterate
+    query.select_stream_fields();
+    if config.stop_at_timestamp.is_some() {
+        query.select_timestamp_field();
+    }
+    // last block that `max_blocks` allows, if it comes before the query's `to_block`
+    let max_blocks_end = config
+        .max_blocks
+        .filter(|max_blocks| *max_blocks > 0)
+        .and_then(|max_blocks| query.cursor().checked_add(max_blocks - 1))
+        .filter(|last| match query.to_block() {
+            Some(tb) => *last < tb,
+            None => true,
+        });
+    if let Some(last) = max_blocks_end {
+        query.set_to_block(Some(last));
+    }
+
+    let span = telemetry::stream_span(source.url(), query.cursor());
+
+    async_stream::stream! {
+        if config.max_blocks == Some(0) {
+            yield Ok(Event::End(StreamEnd::MaxBlocks));
+            return;
+        }
+
+        if config.stop_at_start_head {
+            match source.finalized_height().await.context("get finalized height") {
+                Ok(h) => query.set_to_block(Some(query.to_block().map_or(h, |tb| tb.min(h)))),
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+        }
+
+        let mut num_rows = 0;
+
+        let mut head_wait_start = None;
+        // last known finalized head
+        let mut head: Option<u64> = None;
+        let mut poller = HeadPoller::new(&config);
+
+        loop {
+            if let Some(tb) = query.to_block() {
+                if tb < query.cursor() {
+                    let end = if max_blocks_end == Some(tb) {
+                        StreamEnd::MaxBlocks
+                    } else {
+                        StreamEnd::ReachedToBlock
+                    };
+                    yield Ok(Event::End(end));
+                    return;
+                }
+            }
+
+            let start = Instant::now();
+            let res = Q::run(source, &query);
+            let res = telemetry::instrument(res, span.clone()).await;
+            let duration = start.elapsed();
+            let res = match res.context("run query") {
+                Ok(r) => r,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            let (res, num_bytes) = match res {
+                Some(r) => r,
+                None => {
+                    if config.stop_on_head {
+                        yield Ok(Event::End(StreamEnd::ReachedHead));
+                        return;
+                    }
+                    let wait_start = *head_wait_start.get_or_insert_with(Instant::now);
+
+                    let h = match source.finalized_height().await.context("get finalized height") {
+                        Ok(h) => h,
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
+                    };
+                    let now = Instant::now();
+                    head = Some(h);
+                    poller.observe_head(h, now);
+
+                    let delay = poller.next_delay(query.cursor(), h);
+                    log::debug!("waiting for block {}, head is at {}", query.cursor(), h);
+                    yield Ok(Event::Idle(Idle {
+                        cursor: query.cursor(),
+                        head: h,
+                        waited: now.duration_since(wait_start),
+                        next_poll_in: delay,
+                    }));
+
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            };
+
+            let next_block = match Q::next_block(&res).context("get next block from response") {
+                Ok(nb) => nb,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+
+            if let Some(start) = head_wait_start.take() {
+                telemetry::record_head_wait(source.url(), start.elapsed());
+            }
+            poller.reset_backoff();
+
+            let last_block = next_block - 1;
+            let item_head = match head {
+                Some(h) if h >= last_block => h,
+                _ => match source.finalized_height().await.context("get finalized height") {
+                    Ok(h) => {
+                        head = Some(h);
+                        poller.observe_head(h, Instant::now());
+                        h.max(last_block)
+                    }
+                    Err(e) => {
+                        // the response is already fetched, so keep it and report the blocks it has
+                        log::warn!("failed to refresh finalized head: {:?}", e);
+                        head.map_or(last_block, |h| h.max(last_block))
+                    }
+                },
+            };
+
+            let item = StreamItem {
+                from_block: query.cursor(),
+                to_block: last_block,
+                head: item_head,
+                num_bytes,
+                duration,
+                data: res,
+            };
+
+            let (item, mut stop) = match config.stop_at_timestamp {
+                Some(ts) => match cut_at_timestamp::<Q>(item, ts) {
+                    Ok((Some(item), stop)) => (item, stop),
+                    Ok((None, _)) => {
+                        yield Ok(Event::End(StreamEnd::StopConditionMet));
+                        return;
+                    }
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                },
+                None => (item, false),
+            };
+
+            if let Some(max_rows) = config.max_rows {
+                num_rows += Q::row_counts(&item.data)
+                    .iter()
+                    .filter(|(name, _)| *name != "blocks")
+                    .map(|(_, n)| *n as u64)
+                    .sum::<u64>();
+                stop |= num_rows >= max_rows;
+            }
+
+            if let Some(pred) = stop_when.as_mut() {
+                stop |= pred(&item.data);
+            }
+
+            query.set_cursor(next_block);
+            telemetry::record_cursor(&span, next_block);
+
+            yield Ok(Event::Data(item));
+
+            if stop {
+                yield Ok(Event::End(StreamEnd::StopConditionMet));
+                return;
+            }
+        }
+    }
+}
+
+pub(crate) fn lazy_stream<S: DataSource, Q: SourceQuery<S>>(
+    source: &S,
+    query: Q,
+    config: StreamConfig,
+) -> impl Stream<Item = Result<StreamItem<Q::Response>>> + Send + '_ {
+    events(source, query, config, None).filter_map(|ev| match ev {
+        Ok(Event::Data(res)) => Some(Ok(res)),
+        Ok(Event::Idle(_)) | Ok(Event::End(_)) => None,
+        Err(e) => Some(Err(e)),
+    })
+}
+
+pub(crate) fn event_stream<S: DataSource, Q: SourceQuery<S>>(
+    source: &S,
+    query: Q,
+    config: StreamConfig,
+) -> impl Stream<Item = Result<StreamEvent<Q::Response>>> + Send + '_ {
+    events(source, query, config, None).filter_map(|ev| match ev {
+        Ok(Event::Data(res)) => Some(Ok(StreamEvent::Data(res))),
+        Ok(Event::Idle(idle)) => Some(Ok(StreamEvent::Idle(idle))),
+        Ok(Event::End(_)) => None,
+        Err(e) => Some(Err(e)),
+    })
+}
+
+/// Truncates the item before the first block that has a timestamp at or after `timestamp`.
+///
+/// Returns `None` if there is nothing left after truncating and true if the item was truncated.
+fn cut_at_timestamp<Q: PortalQuery>(
+    mut item: StreamItem<Q::Response>,
+    timestamp: u64,
+) -> Result<(Option<StreamItem<Q::Response>>, bool)> {
+    let timestamps = Q::block_timestamps(&item.data).context("get block timestamps")?;
+    let cutoff = match timestamps.into_iter().find(|(_, t)| *t >= timestamp) {
+        Some((n, _)) => n,
+        None => return Ok((Some(item), false)),
+    };
+
+    if cutoff == item.from_block {
+        return Ok((None, true));
+    }
+
+    item.to_block = cutoff - 1;
+    item.data = Q::truncate(&item.data, item.to_block).context("truncate response")?;
+
+    Ok((Some(item), true))
+}
+
+/// Yields items from the stream until `pred` returns true for one, that item is yielded as well.
+///
+/// Works with the streams returned by the `*_arrow_finalized_lazy_stream` methods,
+/// spawned and blocking streams take the predicate in their `*_arrow_finalized_stream_until`
+/// methods instead.
+pub fn stop_when<T, S, F>(stream: S, mut pred: F) -> impl Stream<Item = Result<StreamItem<T>>>
+where
+    S: Stream<Item = Result<StreamItem<T>>>,
+    F: FnMut(&T) -> bool,
+{
+    async_stream::stream! {
+        futures_lite::pin!(stream);
+
+        while let Some(item) = stream.next().await {
+            let stop = matches!(&item, Ok(item) if pred(&item.data));
+            yield item;
+            if stop {
+                break;
+            }
+        }
+    }
+}
+
+pub(crate) fn spawn_stream<S: DataSource + 'static, Q: SourceQuery<S>>(
+    source: Arc<S>,
+    query: Q,
+    config: StreamConfig,
+    stop_when: Option<StopPredicate<Q::Response>>,
+) -> (
+    mpsc::Receiver<Result<StreamItem<Q::Response>>>,
+    StreamHandle,
+) {
+    let (tx, rx) = mpsc::channel(config.buffer_size);
+    let cancel = CancellationToken::new();
+
+    let task = tokio::spawn(run_stream(
+        source,
+        query,
+        config,
+        stop_when,
+        tx,
+        cancel.clone(),
+    ));
+
+    (rx, StreamHandle::new(cancel, task))
+}
+
+async fn run_stream<S: DataSource, Q: SourceQuery<S>>(
+    source: Arc<S>,
+    query: Q,
+    config: StreamConfig,
+    stop_when: Option<StopPredicate<Q::Response>>,
+    tx: mpsc::Sender<Result<StreamItem<Q::Response>>>,
+    cancel: CancellationToken,
+) -> Result<StreamEnd> {
+    let events = events(&*source, query, config, stop_when);
+    futures_lite::pin!(events);
+
+    loop {
+        let ev = tokio::select! {
+            _ = cancel.cancelled() => return Ok(StreamEnd::Cancelled),
+            ev = events.next() => ev,
+        };
+
+        let res = match ev {
+            Some(Ok(Event::Data(res))) => res,
+            Some(Ok(Event::Idle(_))) => continue,
+            Some(Ok(Event::End(end))) => return Ok(end),
+            Some(Err(e)) => return Err(fail(&tx, e).await),
+            None => return Err(anyhow!("stream ended without an end event")),
+        };
+
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(StreamEnd::Cancelled),
+            res = tx.send(Ok(res)) => {
+                if res.is_err() {
+                    log::debug!("receiver is closed so quitting stream");
+                    return Ok(StreamEnd::ReceiverClosed);
+                }
+            }
+        }
+    }
+}
+
+/// Forwards a copy of the error to the receiver and returns the original for `StreamHandle::join`.
+async fn fail<T>(tx: &mpsc::Sender<Result<T>>, err: anyhow::Error) -> anyhow::Error {
+    tx.send(Err(anyhow!("{:?}", err))).await.ok();
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::stream_item;
+    use crate::{evm, svm, Client, ClientConfig};
+
+    fn evm_item() -> StreamItem<evm::ArrowResponse> {
+        stream_item(
+            10,
+            13,
+            r#"{"header":{"number":10,"timestamp":100},"logs":[{"logIndex":0}]}
+{"header":{"number":11,"timestamp":112},"logs":[{"logIndex":0},{"logIndex":1}]}
+{"header":{"number":13,"timestamp":136},"logs":[{"logIndex":0}]}
+"#,
+        )
+    }
+
+    #[test]
+    fn cut_at_timestamp_truncates_response() {
+        let (item, stop) = cut_at_timestamp::<evm::Query>(evm_item(), 112).unwrap();
+        let item = item.unwrap();
+        assert!(stop);
+        assert_eq!((item.from_block, item.to_block), (10, 10));
+        assert_eq!(item.data.blocks.num_rows(), 1);
+        assert_eq!(item.data.logs.num_rows(), 1);
+
+        let (item, stop) = cut_at_timestamp::<evm::Query>(evm_item(), 120).unwrap();
+        let item = item.unwrap();
+        assert!(stop);
+        assert_eq!((item.from_block, item.to_block), (10, 12));
+        assert_eq!(item.data.logs.num_rows(), 3);
+
+        let (item, stop) = cut_at_timestamp::<evm::Query>(evm_item(), 137).unwrap();
+        assert!(!stop);
+        assert_eq!(item.unwrap().to_block, 13);
+
+        let (item, stop) = cut_at_timestamp::<evm::Query>(evm_item(), 100).unwrap();
+        assert!(stop);
+        assert!(item.is_none());
+    }
+
+    #[tokio::test]
+    async fn stop_when_includes_matching_item() {
+        let items = (0..5).map(|i| {
+            Ok(StreamItem {
+                from_block: i,
+                to_block: i,
+                head: 10,
+                num_bytes: 0,
+                duration: Duration::ZERO,
+                data: i,
+            })
+        });
+
+        let stream = stop_when(futures_lite::stream::iter(items), |data| *data == 2);
+        let blocks = stream
+            .map(|item| item.unwrap().data)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(blocks, [0, 1, 2]);
+    }
+
+    /// Serves `evm_item` but fails to return the finalized head.
+    struct NoHeadSource(reqwest::Url);
+
+    impl DataSource for NoHeadSource {
+        fn url(&self) -> &reqwest::Url {
+            &self.0
+        }
+
+        async fn finalized_height(&self) -> Result<u64> {
+            Err(anyhow!("head is unavailable"))
+        }
+
+        async fn evm_query(&self, _: &evm::Query) -> Result<Option<(evm::ArrowResponse, usize)>> {
+            let item = evm_item();
+            Ok(Some((item.data, item.num_bytes)))
+        }
+
+        async fn svm_query(&self, _: &svm::Query) -> Result<Option<(svm::ArrowResponse, usize)>> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_response_when_head_refresh_fails() {
+        let source = NoHeadSource("http://127.0.0.1:1".parse().unwrap());
+        let query = evm::Query {
+            from_block: 10,
+            to_block: Some(13),
+            ..Default::default()
+        };
+
+        let events = events(&source, query, StreamConfig::default(), None);
+        futures_lite::pin!(events);
+
+        let item = match events.next().await {
+            Some(Ok(Event::Data(item))) => item,
+            _ => panic!("expected a response"),
+        };
+        assert_eq!((item.from_block, item.to_block, item.head), (10, 13, 13));
+        assert!(matches!(
+            events.next().await,
+            Some(Ok(Event::End(StreamEnd::ReachedToBlock)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn spawned_stream_stops_when_predicate_matches() {
+        let source = Arc::new(NoHeadSource("http://127.0.0.1:1".parse().unwrap()));
+        let query = evm::Query {
+            from_block: 10,
+            ..Default::default()
+        };
+        let pred: StopPredicate<evm::ArrowResponse> =
+            Box::new(|res: &evm::ArrowResponse| res.logs.num_rows() > 0);
+
+        let (mut rx, handle) = spawn_stream(source, query, StreamConfig::default(), Some(pred));
+
+        let item = rx.recv().await.unwrap().unwrap();
+        assert_eq!((item.from_block, item.to_block), (10, 13));
+        assert!(rx.recv().await.is_none());
+        assert_eq!(handle.join().await.unwrap(), StreamEnd::StopConditionMet);
+    }
+
+    #[tokio::test]
+    async fn max_blocks_zero_ends_immediately() {
+        let client = Client::new(
+            "http://127.0.0.1:1".parse().unwrap(),
+            ClientConfig::default(),
+        );
+
+        let config = StreamConfig {
+            max_blocks: Some(0),
+            ..Default::default()
+        };
+
+        let events = events(&client, evm::Query::default(), config, None);
+        futures_lite::pin!(events);
+
+        assert!(matches!(
+            events.next().await,
+            Some(Ok(Event::End(StreamEnd::MaxBlocks)))
+        ));
+    }
+
+    fn poller() -> HeadPoller {
+        HeadPoller::new(&StreamConfig {
+            head_poll_interval_millis: 100,
+            max_head_poll_interval_millis: 1_000,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn head_poller_backs_off_until_block_time_is_known() {
+        let mut poller = poller();
+
+        let delays = (0..5)
+            .map(|_| poller.next_delay(11, 10).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [100, 200, 400, 800, 1_000]);
+
+        poller.reset_backoff();
+        assert_eq!(poller.next_delay(11, 10), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn head_poller_uses_block_time() {
+        let mut poller = poller();
+
+        let start = Instant::now();
+        poller.observe_head(10, start);
+        poller.observe_head(12, start + Duration::from_millis(600));
+
+        assert_eq!(poller.next_delay(13, 12), Duration::from_millis(300));
+        assert_eq!(poller.next_delay(14, 12), Duration::from_millis(600));
+        // clamped to the configured range
+        assert_eq!(poller.next_delay(100, 12), Duration::from_millis(1_000));
+        assert_eq!(poller.next_delay(5, 12), Duration::from_millis(300));
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::{Context, Result};
-use arrow::array::{builder, UInt64Array};
+use arrow::array::builder::{BinaryBuilder, ListBuilder, UInt64Builder};
+use arrow::array::{Int64Array, UInt64Array};
 use arrow::record_batch::RecordBatch;
 use cherry_svm_schema::{
     BalancesBuilder, BlocksBuilder, InstructionsBuilder, LogsBuilder, RewardsBuilder,
@@ -9,6 +10,19 @@ use serde::{Deserialize, Serialize};
 use simd_json::base::{TypedValue, ValueAsScalar};
 use simd_json::derived::TypedScalarValue;
 
+use crate::query::slice_blocks;
+use crate::split::{merge_table, pack, split_request};
+
+mod builder;
+mod mux;
+mod rows;
+
+pub use builder::QueryBuilder;
+pub use rows::{
+    AddressTableLookup, Balance, Block, BlockData, Instruction, LoadedAddresses, Log, Pubkey,
+    Reward, Signature, TokenBalance, Transaction, TransactionVersion,
+};
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Query {
@@ -26,18 +40,13 @@ pub struct Query {
     pub rewards: Vec<RewardRequest>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum QueryType {
+    #[default]
     Solana,
 }
 
-impl Default for QueryType {
-    fn default() -> Self {
-        Self::Solana
-    }
-}
-
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct InstructionRequest {
@@ -73,11 +82,32 @@ pub struct InstructionRequest {
     pub a8: Vec<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub a9: Vec<String>,
-    pub is_committed: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub a10: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub a11: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub a12: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub a13: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub a14: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub a15: Vec<String>,
+    /// Match instructions that have any of these accounts in any position.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mentions_account: Vec<String>,
+    /// Only match instructions that were (or were not) committed, `None` matches both.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_committed: Option<bool>,
     pub transaction: bool,
+    pub transaction_balances: bool,
     pub transaction_token_balances: bool,
+    pub transaction_instructions: bool,
     pub logs: bool,
     pub inner_instructions: bool,
+    /// Include the instructions that invoked the matched instruction.
+    pub parent_instructions: bool,
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -85,7 +115,12 @@ pub struct InstructionRequest {
 pub struct TransactionRequest {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub fee_payer: Vec<String>,
+    /// Match transactions that have any of these accounts in their account keys.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mentions_account: Vec<String>,
     pub instructions: bool,
+    pub balances: bool,
+    pub token_balances: bool,
     pub logs: bool,
 }
 
@@ -135,6 +170,164 @@ pub struct TokenBalanceRequest {
 pub struct RewardRequest {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub pubkey: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reward_type: Vec<String>,
+}
+
+impl Query {
+    /// Splits the query into queries over the same block range that have at most `max_values`
+    /// filter values each, so they fit into a portal request.
+    ///
+    /// The key fields of every table are selected in the parts so their responses can be merged
+    /// with `ArrowResponse::merge`.
+    pub(crate) fn split(&self, max_values: usize) -> Vec<Query> {
+        let mut requests = Vec::new();
+        for req in self.instructions.iter() {
+            let parts = split_request(req.clone(), max_values, InstructionRequest::filters);
+            requests.extend(
+                parts
+                    .into_iter()
+                    .map(|(req, n)| (Request::Instruction(Box::new(req)), n)),
+            );
+        }
+        for req in self.transactions.iter() {
+            let parts = split_request(req.clone(), max_values, TransactionRequest::filters);
+            requests.extend(
+                parts
+                    .into_iter()
+                    .map(|(req, n)| (Request::Transaction(req), n)),
+            );
+        }
+        for req in self.logs.iter() {
+            let parts = split_request(req.clone(), max_values, LogRequest::filters);
+            requests.extend(parts.into_iter().map(|(req, n)| (Request::Log(req), n)));
+        }
+        for req in self.balances.iter() {
+            let parts = split_request(req.clone(), max_values, BalanceRequest::filters);
+            requests.extend(parts.into_iter().map(|(req, n)| (Request::Balance(req), n)));
+        }
+        for req in self.token_balances.iter() {
+            let parts = split_request(req.clone(), max_values, TokenBalanceRequest::filters);
+            requests.extend(
+                parts
+                    .into_iter()
+                    .map(|(req, n)| (Request::TokenBalance(req), n)),
+            );
+        }
+        for req in self.rewards.iter() {
+            let parts = split_request(req.clone(), max_values, RewardRequest::filters);
+            requests.extend(parts.into_iter().map(|(req, n)| (Request::Reward(req), n)));
+        }
+
+        let groups = pack(requests, max_values);
+        if groups.len() <= 1 {
+            return vec![self.clone()];
+        }
+
+        groups
+            .into_iter()
+            .map(|group| {
+                let mut query = Query {
+                    instructions: Vec::new(),
+                    transactions: Vec::new(),
+                    logs: Vec::new(),
+                    balances: Vec::new(),
+                    token_balances: Vec::new(),
+                    rewards: Vec::new(),
+                    ..self.clone()
+                };
+                builder::select_join_keys(&mut query.fields);
+                for req in group {
+                    match req {
+                        Request::Instruction(req) => query.instructions.push(*req),
+                        Request::Transaction(req) => query.transactions.push(req),
+                        Request::Log(req) => query.logs.push(req),
+                        Request::Balance(req) => query.balances.push(req),
+                        Request::TokenBalance(req) => query.token_balances.push(req),
+                        Request::Reward(req) => query.rewards.push(req),
+                    }
+                }
+                query
+            })
+            .collect()
+    }
+}
+
+enum Request {
+    Instruction(Box<InstructionRequest>),
+    Transaction(TransactionRequest),
+    Log(LogRequest),
+    Balance(BalanceRequest),
+    TokenBalance(TokenBalanceRequest),
+    Reward(RewardRequest),
+}
+
+impl InstructionRequest {
+    fn filters(&mut self) -> Vec<&mut Vec<String>> {
+        vec![
+            &mut self.program_id,
+            &mut self.d1,
+            &mut self.d2,
+            &mut self.d3,
+            &mut self.d4,
+            &mut self.d8,
+            &mut self.a0,
+            &mut self.a1,
+            &mut self.a2,
+            &mut self.a3,
+            &mut self.a4,
+            &mut self.a5,
+            &mut self.a6,
+            &mut self.a7,
+            &mut self.a8,
+            &mut self.a9,
+            &mut self.a10,
+            &mut self.a11,
+            &mut self.a12,
+            &mut self.a13,
+            &mut self.a14,
+            &mut self.a15,
+            &mut self.mentions_account,
+        ]
+    }
+}
+
+impl TransactionRequest {
+    fn filters(&mut self) -> Vec<&mut Vec<String>> {
+        vec![&mut self.fee_payer, &mut self.mentions_account]
+    }
+}
+
+impl LogRequest {
+    fn filters(&mut self) -> Vec<&mut Vec<String>> {
+        vec![&mut self.program_id, &mut self.kind]
+    }
+}
+
+impl BalanceRequest {
+    fn filters(&mut self) -> Vec<&mut Vec<String>> {
+        vec![&mut self.account]
+    }
+}
+
+impl TokenBalanceRequest {
+    fn filters(&mut self) -> Vec<&mut Vec<String>> {
+        vec![
+            &mut self.account,
+            &mut self.pre_program_id,
+            &mut self.post_program_id,
+            &mut self.pre_mint,
+            &mut self.post_mint,
+            &mut self.pre_owner,
+            &mut self.post_owner,
+        ]
+    }
+}
+
+impl RewardRequest {
+    fn filters(&mut self) -> Vec<&mut Vec<String>> {
+        vec![&mut self.pubkey, &mut self.reward_type]
+    }
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -352,6 +545,7 @@ pub struct BlockFields {
     pub hash: bool,
     pub parent_number: bool,
     pub parent_hash: bool,
+    pub height: bool,
     pub timestamp: bool,
 }
 
@@ -362,6 +556,7 @@ impl BlockFields {
             hash: true,
             parent_number: true,
             parent_hash: true,
+            height: true,
             timestamp: true,
         }
     }
@@ -379,6 +574,114 @@ pub struct ArrowResponse {
 }
 
 impl ArrowResponse {
+    /// Returns every table along with its name.
+    pub fn tables(&self) -> [(&'static str, &RecordBatch); 7] {
+        [
+            ("blocks", &self.blocks),
+            ("transactions", &self.transactions),
+            ("instructions", &self.instructions),
+            ("logs", &self.logs),
+            ("balances", &self.balances),
+            ("token_balances", &self.token_balances),
+            ("rewards", &self.rewards),
+        ]
+    }
+
+    /// Returns the slot and timestamp of each block in the response.
+    ///
+    /// Requires `BlockFields::timestamp` to be selected.
+    pub fn block_timestamps(&self) -> Result<Vec<(u64, u64)>> {
+        let slots = self
+            .blocks
+            .column_by_name("slot")
+            .context("get slot col")?
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .context("get slot col as u64")?;
+        let timestamps = self
+            .blocks
+            .column_by_name("timestamp")
+            .context("get timestamp col")?
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .context("get timestamp col as i64")?;
+
+        slots
+            .iter()
+            .zip(timestamps.iter())
+            .map(|(n, t)| {
+                let n = n.context("null block slot")?;
+                let t = t.context("null block timestamp")?;
+                let t = u64::try_from(t).context("negative timestamp")?;
+                Ok((n, t))
+            })
+            .collect()
+    }
+
+    /// Returns a copy of the response that only contains data up to and including `to_block`.
+    pub fn truncate(&self, to_block: u64) -> Result<Self> {
+        self.slice(0, to_block)
+    }
+
+    /// Returns a copy of the response that only contains data of the blocks in
+    /// `from_block..=to_block`.
+    pub fn slice(&self, from_block: u64, to_block: u64) -> Result<Self> {
+        let slice =
+            |batch: &RecordBatch, column: &str| slice_blocks(batch, column, from_block, to_block);
+        Ok(Self {
+            instructions: slice(&self.instructions, "block_slot").context("instructions")?,
+            transactions: slice(&self.transactions, "block_slot").context("transactions")?,
+            logs: slice(&self.logs, "block_slot").context("logs")?,
+            balances: slice(&self.balances, "block_slot").context("balances")?,
+            token_balances: slice(&self.token_balances, "block_slot").context("token balances")?,
+            rewards: slice(&self.rewards, "block_slot").context("rewards")?,
+            blocks: slice(&self.blocks, "slot").context("blocks")?,
+        })
+    }
+
+    /// Merges the responses of queries that were split from the same query.
+    ///
+    /// The responses have to cover the same block range, use `truncate` to cut them to the same
+    /// block first. Rows are sorted in block order and rows that appear in more than one response
+    /// are only kept once.
+    pub(crate) fn merge(responses: &[Self]) -> Result<Self> {
+        Ok(Self {
+            blocks: merge_table(responses, |r| &r.blocks, &["slot"]).context("blocks")?,
+            transactions: merge_table(
+                responses,
+                |r| &r.transactions,
+                &["block_slot", "transaction_index"],
+            )
+            .context("transactions")?,
+            instructions: merge_table(
+                responses,
+                |r| &r.instructions,
+                &["block_slot", "transaction_index", "instruction_address"],
+            )
+            .context("instructions")?,
+            logs: merge_table(
+                responses,
+                |r| &r.logs,
+                &["block_slot", "transaction_index", "log_index"],
+            )
+            .context("logs")?,
+            balances: merge_table(
+                responses,
+                |r| &r.balances,
+                &["block_slot", "transaction_index", "account"],
+            )
+            .context("balances")?,
+            token_balances: merge_table(
+                responses,
+                |r| &r.token_balances,
+                &["block_slot", "transaction_index", "account"],
+            )
+            .context("token balances")?,
+            rewards: merge_table(responses, |r| &r.rewards, &["block_slot", "pubkey"])
+                .context("rewards")?,
+        })
+    }
+
     pub fn next_block(&self) -> Result<u64> {
         let numbers = self
             .blocks
@@ -395,8 +698,28 @@ impl ArrowResponse {
     }
 }
 
+pub(crate) fn parse_response(data: &[u8]) -> Result<ArrowResponse> {
+    let mut parser = ArrowResponseParser::default();
+
+    let lines = data.split(|x| *x == b'\n');
+    let mut scratch = Vec::new();
+
+    for line in lines {
+        if line.is_empty() {
+            continue;
+        }
+
+        scratch.extend_from_slice(line);
+        let tape = simd_json::to_tape(&mut scratch).context("json to tape")?;
+        parser.parse_tape(&tape).context("parse tape")?;
+        scratch.clear();
+    }
+
+    Ok(parser.finish())
+}
+
 #[derive(Default)]
-pub(crate) struct ArrowResponseParser {
+struct ArrowResponseParser {
     instructions: InstructionsBuilder,
     transactions: TransactionsBuilder,
     logs: LogsBuilder,
@@ -407,7 +730,7 @@ pub(crate) struct ArrowResponseParser {
 }
 
 impl ArrowResponseParser {
-    pub(crate) fn parse_tape(&mut self, tape: &simd_json::tape::Tape<'_>) -> Result<()> {
+    fn parse_tape(&mut self, tape: &simd_json::tape::Tape<'_>) -> Result<()> {
         let obj = tape.as_value().as_object().context("tape as object")?;
         let header = obj.get("header").context("get header")?;
 
@@ -660,13 +983,13 @@ impl ArrowResponseParser {
 
                 let account_key = get_tape_base58(&v, "accountKey")?;
                 atl_builder
-                    .field_builder::<builder::BinaryBuilder>(0)
+                    .field_builder::<BinaryBuilder>(0)
                     .unwrap()
                     .append_option(account_key);
 
                 {
                     let b = atl_builder
-                        .field_builder::<builder::ListBuilder<builder::UInt64Builder>>(1)
+                        .field_builder::<ListBuilder<UInt64Builder>>(1)
                         .unwrap();
 
                     let v = get_tape_array_of_u64(&v, "writableIndexes")?;
@@ -674,7 +997,7 @@ impl ArrowResponseParser {
                 }
                 {
                     let b = atl_builder
-                        .field_builder::<builder::ListBuilder<builder::UInt64Builder>>(2)
+                        .field_builder::<ListBuilder<UInt64Builder>>(2)
                         .unwrap();
 
                     let v = get_tape_array_of_u64(&v, "readonlyIndexes")?;
@@ -878,7 +1201,7 @@ impl ArrowResponseParser {
         Ok(BlockInfo { slot, hash })
     }
 
-    pub(crate) fn finish(self) -> ArrowResponse {
+    fn finish(self) -> ArrowResponse {
         ArrowResponse {
             instructions: self.instructions.finish(),
             transactions: self.transactions.finish(),
@@ -1172,3 +1495,138 @@ fn get_tape_base58(obj: &simd_json::tape::Object<'_, '_>, name: &str) -> Result<
         .with_context(|| format!("decode_base58({})", name))
         .map(Some)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_json<T: Serialize>(value: &T) -> simd_json::OwnedValue {
+        simd_json::to_owned_value(&mut simd_json::to_vec(value).unwrap()).unwrap()
+    }
+
+    fn expected(json: &str) -> simd_json::OwnedValue {
+        simd_json::to_owned_value(&mut json.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn instruction_request_json() {
+        let req = InstructionRequest {
+            a15: vec!["a".to_owned()],
+            mentions_account: vec!["b".to_owned()],
+            transaction_balances: true,
+            transaction_instructions: true,
+            parent_instructions: true,
+            ..Default::default()
+        };
+        // is_committed is left out when it is `None`
+        assert_eq!(
+            to_json(&req),
+            expected(
+                r#"{"a15":["a"],"mentionsAccount":["b"],"transaction":false,
+                "transactionBalances":true,"transactionTokenBalances":false,
+                "transactionInstructions":true,"logs":false,"innerInstructions":false,
+                "parentInstructions":true}"#
+            )
+        );
+
+        let req = InstructionRequest {
+            is_committed: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(to_json(&req)["isCommitted"], false);
+    }
+
+    #[test]
+    fn transaction_request_json() {
+        let req = TransactionRequest {
+            mentions_account: vec!["a".to_owned()],
+            balances: true,
+            token_balances: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            to_json(&req),
+            expected(
+                r#"{"mentionsAccount":["a"],"instructions":false,"balances":true,
+                "tokenBalances":true,"logs":false}"#
+            )
+        );
+    }
+
+    #[test]
+    fn reward_request_json() {
+        let req = RewardRequest {
+            reward_type: vec!["voting".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(to_json(&req), expected(r#"{"rewardType":["voting"]}"#));
+    }
+
+    #[test]
+    fn block_fields_json() {
+        let fields = BlockFields {
+            height: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            to_json(&fields),
+            expected(
+                r#"{"number":false,"hash":false,"parentNumber":false,"parentHash":false,
+                "height":true,"timestamp":false}"#
+            )
+        );
+    }
+
+    #[test]
+    fn split_large_filter_lists() {
+        let query = Query {
+            instructions: vec![InstructionRequest {
+                a0: vec!["a".to_owned(); 3],
+                a1: vec!["b".to_owned(); 3],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        // both lists are matched, so every part needs a value of each
+        let parts = query.split(2);
+        assert_eq!(parts.len(), 9);
+        for part in parts.iter() {
+            assert_eq!(part.instructions.len(), 1);
+            assert_eq!(
+                part.instructions[0].a0.len() + part.instructions[0].a1.len(),
+                2
+            );
+            assert!(part.fields.instruction.instruction_address);
+        }
+    }
+
+    #[test]
+    fn merge_split_responses() {
+        let a = parse_response(
+            concat!(
+                r#"{"header":{"number":1},"instructions":["#,
+                r#"{"transactionIndex":0,"instructionAddress":[0]}]}"#,
+                "\n",
+                r#"{"header":{"number":2}}"#,
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let b = parse_response(
+            concat!(
+                r#"{"header":{"number":1},"instructions":["#,
+                r#"{"transactionIndex":0,"instructionAddress":[0]},"#,
+                r#"{"transactionIndex":0,"instructionAddress":[0,1]}]}"#,
+                "\n",
+                r#"{"header":{"number":2}}"#,
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let merged = ArrowResponse::merge(&[a, b]).unwrap();
+        assert_eq!(merged.blocks.num_rows(), 2);
+        assert_eq!(merged.instructions.num_rows(), 2);
+    }
+}
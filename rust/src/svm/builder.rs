@@ -0,0 +1,372 @@
+use super::{
+    BalanceRequest, Fields, InstructionRequest, LogRequest, Query, RewardRequest,
+    TokenBalanceRequest, TransactionRequest,
+};
+use crate::validate::{
+    check_base58, check_block_range, check_one_of, normalize_hex, ValidationError,
+};
+
+const PUBKEY_LEN: usize = 32;
+
+const LOG_KINDS: &[&str] = &["log", "data", "other"];
+const REWARD_TYPES: &[&str] = &["fee", "rent", "staking", "voting"];
+
+/// Builds an `svm::Query`, validating and normalising the filters before anything is sent.
+///
+/// `build` also selects the fields that the requests filter on or need to join
+/// the returned items to each other.
+#[derive(Debug, Clone, Default)]
+pub struct QueryBuilder {
+    query: Query,
+}
+
+impl Query {
+    pub fn builder() -> QueryBuilder {
+        QueryBuilder::default()
+    }
+}
+
+impl QueryBuilder {
+    pub fn from_block(mut self, from_block: u64) -> Self {
+        self.query.from_block = from_block;
+        self
+    }
+
+    pub fn to_block(mut self, to_block: u64) -> Self {
+        self.query.to_block = Some(to_block);
+        self
+    }
+
+    pub fn include_all_blocks(mut self, include_all_blocks: bool) -> Self {
+        self.query.include_all_blocks = include_all_blocks;
+        self
+    }
+
+    /// Sets the selected fields, `build` adds to these.
+    pub fn fields(mut self, fields: Fields) -> Self {
+        self.query.fields = fields;
+        self
+    }
+
+    pub fn instruction(mut self, req: InstructionRequest) -> Self {
+        self.query.instructions.push(req);
+        self
+    }
+
+    pub fn transaction(mut self, req: TransactionRequest) -> Self {
+        self.query.transactions.push(req);
+        self
+    }
+
+    pub fn log(mut self, req: LogRequest) -> Self {
+        self.query.logs.push(req);
+        self
+    }
+
+    pub fn balance(mut self, req: BalanceRequest) -> Self {
+        self.query.balances.push(req);
+        self
+    }
+
+    pub fn token_balance(mut self, req: TokenBalanceRequest) -> Self {
+        self.query.token_balances.push(req);
+        self
+    }
+
+    pub fn reward(mut self, req: RewardRequest) -> Self {
+        self.query.rewards.push(req);
+        self
+    }
+
+    pub fn build(self) -> Result<Query, ValidationError> {
+        let mut query = self.query;
+
+        check_block_range(query.from_block, query.to_block)?;
+
+        for (i, req) in query.instructions.iter_mut().enumerate() {
+            let path = |name: &str| format!("instructions[{}].{}", i, name);
+            check_base58(&path("program_id"), &mut req.program_id, PUBKEY_LEN)?;
+            normalize_hex(&path("d1"), &mut req.d1, 1)?;
+            normalize_hex(&path("d2"), &mut req.d2, 2)?;
+            normalize_hex(&path("d3"), &mut req.d3, 3)?;
+            normalize_hex(&path("d4"), &mut req.d4, 4)?;
+            normalize_hex(&path("d8"), &mut req.d8, 8)?;
+            for (name, accounts) in [
+                ("a0", &mut req.a0),
+                ("a1", &mut req.a1),
+                ("a2", &mut req.a2),
+                ("a3", &mut req.a3),
+                ("a4", &mut req.a4),
+                ("a5", &mut req.a5),
+                ("a6", &mut req.a6),
+                ("a7", &mut req.a7),
+                ("a8", &mut req.a8),
+                ("a9", &mut req.a9),
+                ("a10", &mut req.a10),
+                ("a11", &mut req.a11),
+                ("a12", &mut req.a12),
+                ("a13", &mut req.a13),
+                ("a14", &mut req.a14),
+                ("a15", &mut req.a15),
+                ("mentions_account", &mut req.mentions_account),
+            ] {
+                check_base58(&path(name), accounts, PUBKEY_LEN)?;
+            }
+        }
+
+        for (i, req) in query.transactions.iter_mut().enumerate() {
+            let path = |name: &str| format!("transactions[{}].{}", i, name);
+            check_base58(&path("fee_payer"), &mut req.fee_payer, PUBKEY_LEN)?;
+            check_base58(
+                &path("mentions_account"),
+                &mut req.mentions_account,
+                PUBKEY_LEN,
+            )?;
+        }
+
+        for (i, req) in query.logs.iter_mut().enumerate() {
+            let path = |name: &str| format!("logs[{}].{}", i, name);
+            check_base58(&path("program_id"), &mut req.program_id, PUBKEY_LEN)?;
+            check_one_of(&path("kind"), &req.kind, LOG_KINDS)?;
+        }
+
+        for (i, req) in query.balances.iter_mut().enumerate() {
+            let path = |name: &str| format!("balances[{}].{}", i, name);
+            check_base58(&path("account"), &mut req.account, PUBKEY_LEN)?;
+        }
+
+        for (i, req) in query.token_balances.iter_mut().enumerate() {
+            let path = |name: &str| format!("token_balances[{}].{}", i, name);
+            for (name, keys) in [
+                ("account", &mut req.account),
+                ("pre_program_id", &mut req.pre_program_id),
+                ("post_program_id", &mut req.post_program_id),
+                ("pre_mint", &mut req.pre_mint),
+                ("post_mint", &mut req.post_mint),
+                ("pre_owner", &mut req.pre_owner),
+                ("post_owner", &mut req.post_owner),
+            ] {
+                check_base58(&path(name), keys, PUBKEY_LEN)?;
+            }
+        }
+
+        for (i, req) in query.rewards.iter_mut().enumerate() {
+            let path = |name: &str| format!("rewards[{}].{}", i, name);
+            check_base58(&path("pubkey"), &mut req.pubkey, PUBKEY_LEN)?;
+            check_one_of(&path("reward_type"), &req.reward_type, REWARD_TYPES)?;
+        }
+
+        select_required_fields(&mut query);
+
+        Ok(query)
+    }
+}
+
+/// Selects the fields that the requests filter on and the keys of the related items.
+pub(super) fn select_required_fields(query: &mut Query) {
+    let fields = &mut query.fields;
+
+    fields.block.number = true;
+
+    for req in query.instructions.iter() {
+        select_instruction_keys(fields);
+        fields.instruction.program_id |= !req.program_id.is_empty();
+        fields.instruction.d1 |= !req.d1.is_empty();
+        fields.instruction.d2 |= !req.d2.is_empty();
+        // there is no d3 field, it is read from the data
+        fields.instruction.data |= !req.d3.is_empty();
+        fields.instruction.d4 |= !req.d4.is_empty();
+        fields.instruction.d8 |= !req.d8.is_empty();
+        fields.instruction.accounts |= [
+            &req.a0,
+            &req.a1,
+            &req.a2,
+            &req.a3,
+            &req.a4,
+            &req.a5,
+            &req.a6,
+            &req.a7,
+            &req.a8,
+            &req.a9,
+            &req.a10,
+            &req.a11,
+            &req.a12,
+            &req.a13,
+            &req.a14,
+            &req.a15,
+            &req.mentions_account,
+        ]
+        .iter()
+        .any(|a| !a.is_empty());
+        fields.instruction.is_committed |= req.is_committed.is_some();
+        if req.transaction {
+            select_transaction_keys(fields);
+        }
+        if req.transaction_balances {
+            select_balance_keys(fields);
+        }
+        if req.transaction_token_balances {
+            select_token_balance_keys(fields);
+        }
+        if req.logs {
+            select_log_keys(fields);
+        }
+    }
+
+    for req in query.transactions.iter() {
+        select_transaction_keys(fields);
+        fields.transaction.fee_payer |= !req.fee_payer.is_empty();
+        fields.transaction.account_keys |= !req.mentions_account.is_empty();
+        if req.instructions {
+            select_instruction_keys(fields);
+        }
+        if req.balances {
+            select_balance_keys(fields);
+        }
+        if req.token_balances {
+            select_token_balance_keys(fields);
+        }
+        if req.logs {
+            select_log_keys(fields);
+        }
+    }
+
+    for req in query.logs.iter() {
+        select_log_keys(fields);
+        fields.log.program_id |= !req.program_id.is_empty();
+        fields.log.kind |= !req.kind.is_empty();
+        if req.transaction {
+            select_transaction_keys(fields);
+        }
+        if req.instruction {
+            select_instruction_keys(fields);
+        }
+    }
+
+    for req in query.balances.iter() {
+        select_balance_keys(fields);
+        if req.transaction {
+            select_transaction_keys(fields);
+        }
+        if req.transaction_instructions {
+            select_instruction_keys(fields);
+        }
+    }
+
+    for req in query.token_balances.iter() {
+        select_token_balance_keys(fields);
+        fields.token_balance.pre_program_id |= !req.pre_program_id.is_empty();
+        fields.token_balance.post_program_id |= !req.post_program_id.is_empty();
+        fields.token_balance.pre_mint |= !req.pre_mint.is_empty();
+        fields.token_balance.post_mint |= !req.post_mint.is_empty();
+        fields.token_balance.pre_owner |= !req.pre_owner.is_empty();
+        fields.token_balance.post_owner |= !req.post_owner.is_empty();
+        if req.transaction {
+            select_transaction_keys(fields);
+        }
+        if req.transaction_instructions {
+            select_instruction_keys(fields);
+        }
+    }
+
+    for req in query.rewards.iter() {
+        fields.reward.pubkey = true;
+        fields.reward.reward_type |= !req.reward_type.is_empty();
+    }
+}
+
+/// Selects the fields that identify the rows of every table.
+pub(super) fn select_join_keys(fields: &mut Fields) {
+    fields.block.number = true;
+    select_instruction_keys(fields);
+    select_transaction_keys(fields);
+    select_log_keys(fields);
+    select_balance_keys(fields);
+    select_token_balance_keys(fields);
+    fields.reward.pubkey = true;
+}
+
+fn select_instruction_keys(fields: &mut Fields) {
+    fields.instruction.transaction_index = true;
+    fields.instruction.instruction_address = true;
+}
+
+fn select_transaction_keys(fields: &mut Fields) {
+    fields.transaction.transaction_index = true;
+}
+
+fn select_log_keys(fields: &mut Fields) {
+    fields.log.transaction_index = true;
+    fields.log.log_index = true;
+    fields.log.instruction_address = true;
+}
+
+fn select_balance_keys(fields: &mut Fields) {
+    fields.balance.transaction_index = true;
+    fields.balance.account = true;
+}
+
+fn select_token_balance_keys(fields: &mut Fields) {
+    fields.token_balance.transaction_index = true;
+    fields.token_balance.account = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::ValidationErrorKind;
+
+    const TOKEN_PROGRAM: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
+
+    #[test]
+    fn build_normalizes_and_selects_fields() {
+        let query = Query::builder()
+            .from_block(10)
+            .instruction(InstructionRequest {
+                program_id: vec![TOKEN_PROGRAM.to_owned()],
+                d1: vec!["0C".to_owned()],
+                transaction: true,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(query.instructions[0].d1, ["0x0c"]);
+        assert!(query.fields.block.number);
+        assert!(query.fields.instruction.program_id);
+        assert!(query.fields.instruction.d1);
+        assert!(query.fields.instruction.instruction_address);
+        assert!(query.fields.transaction.transaction_index);
+        assert!(!query.fields.log.log_index);
+    }
+
+    #[test]
+    fn build_rejects_invalid_filters() {
+        let err = Query::builder()
+            .instruction(InstructionRequest {
+                d8: vec!["0x0102".to_owned()],
+                ..Default::default()
+            })
+            .build()
+            .unwrap_err();
+        assert_eq!(err.path, "instructions[0].d8[0]");
+        assert!(matches!(
+            err.kind,
+            ValidationErrorKind::InvalidLength {
+                expected: 8,
+                actual: 2,
+                ..
+            }
+        ));
+
+        let err = Query::builder()
+            .transaction(TransactionRequest {
+                fee_payer: vec!["not-base58".to_owned()],
+                ..Default::default()
+            })
+            .build()
+            .unwrap_err();
+        assert_eq!(err.path, "transactions[0].fee_payer[0]");
+        assert!(matches!(err.kind, ValidationErrorKind::InvalidBase58(_)));
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use arrow::record_batch::RecordBatch;
+
+use super::{builder, decode_base58, decode_prefixed_hex, ArrowResponse, Query};
+use super::{InstructionRequest, LogRequest, RewardRequest, TokenBalanceRequest};
+use crate::mux::{
+    ancestors, and, binary_in, binary_prefix_in, block_mask, bool_eq, descendant_of, filter,
+    in_block_range, item_keys, kept_blocks, key_in, list_contains, list_item_in, matched, or,
+    tx_keys, tx_of, union_fields, utf8_in, Mask,
+};
+
+impl Query {
+    /// Combines the queries into one query that returns everything that any of them returns.
+    ///
+    /// The fields that are needed to tell which rows match which query are selected.
+    pub(crate) fn union(queries: &[Query]) -> Result<Query> {
+        let first = queries.first().context("no queries to combine")?;
+
+        let mut query = Query {
+            type_: first.type_.clone(),
+            from_block: queries
+                .iter()
+                .map(|q| q.from_block)
+                .min()
+                .unwrap_or_default(),
+            to_block: queries
+                .iter()
+                .map(|q| q.to_block)
+                .collect::<Option<Vec<_>>>()
+                .and_then(|to| to.into_iter().max()),
+            include_all_blocks: queries.iter().any(|q| q.include_all_blocks),
+            fields: union_fields(queries.iter().map(|q| &q.fields)).context("combine fields")?,
+            instructions: queries
+                .iter()
+                .flat_map(|q| q.instructions.clone())
+                .collect(),
+            transactions: queries
+                .iter()
+                .flat_map(|q| q.transactions.clone())
+                .collect(),
+            logs: queries.iter().flat_map(|q| q.logs.clone()).collect(),
+            balances: queries.iter().flat_map(|q| q.balances.clone()).collect(),
+            token_balances: queries
+                .iter()
+                .flat_map(|q| q.token_balances.clone())
+                .collect(),
+            rewards: queries.iter().flat_map(|q| q.rewards.clone()).collect(),
+        };
+        builder::select_required_fields(&mut query);
+        builder::select_join_keys(&mut query.fields);
+
+        Ok(query)
+    }
+}
+
+impl ArrowResponse {
+    /// Returns the rows of a response to a combined query that `query` would have returned
+    /// on its own.
+    ///
+    /// The columns are not changed so fields that were selected by the other queries are
+    /// filled in too.
+    pub(crate) fn demux(&self, query: &Query) -> Result<Self> {
+        let instruction_keys = item_keys(
+            &self.instructions,
+            "block_slot",
+            "transaction_index",
+            "instruction_address",
+        )?;
+        let instruction_txs = tx_of(&instruction_keys);
+        let log_keys = item_keys(
+            &self.logs,
+            "block_slot",
+            "transaction_index",
+            "instruction_address",
+        )?;
+        let log_txs = tx_keys(&self.logs, "block_slot", "transaction_index")?;
+        let tx_txs = tx_keys(&self.transactions, "block_slot", "transaction_index")?;
+        let balance_txs = tx_keys(&self.balances, "block_slot", "transaction_index")?;
+        let token_balance_txs = tx_keys(&self.token_balances, "block_slot", "transaction_index")?;
+
+        let mut instructions = vec![false; self.instructions.num_rows()];
+        let mut transactions = vec![false; self.transactions.num_rows()];
+        let mut logs = vec![false; self.logs.num_rows()];
+        let mut balances = vec![false; self.balances.num_rows()];
+        let mut token_balances = vec![false; self.token_balances.num_rows()];
+
+        let mut txs_of = HashSet::new();
+        let mut instructions_of = HashSet::new();
+        let mut logs_of = HashSet::new();
+        let mut balances_of = HashSet::new();
+        let mut token_balances_of = HashSet::new();
+        let mut logs_of_instructions = HashSet::new();
+        let mut instructions_of_logs = HashSet::new();
+        let mut inner_of = HashSet::new();
+        let mut parents_of = HashSet::new();
+
+        for req in query.instructions.iter() {
+            let mask = instruction_mask(&self.instructions, req).context("match instructions")?;
+            for (i, key) in matched(&mask, &instruction_keys) {
+                instructions[i] = true;
+                let tx = (key.0, key.1);
+                if req.transaction {
+                    txs_of.insert(tx);
+                }
+                if req.transaction_balances {
+                    balances_of.insert(tx);
+                }
+                if req.transaction_token_balances {
+                    token_balances_of.insert(tx);
+                }
+                if req.transaction_instructions {
+                    instructions_of.insert(tx);
+                }
+                if req.parent_instructions {
+                    parents_of.extend(ancestors(&key));
+                }
+                if req.inner_instructions {
+                    inner_of.insert(key.clone());
+                }
+                if req.logs {
+                    logs_of_instructions.insert(key);
+                }
+            }
+        }
+
+        for req in query.transactions.iter() {
+            let mut mask = binary_in(
+                &self.transactions,
+                "fee_payer",
+                &decode_base58_all(&req.fee_payer)?,
+            )?;
+            and(
+                &mut mask,
+                &list_contains(
+                    &self.transactions,
+                    "account_keys",
+                    &decode_base58_all(&req.mentions_account)?,
+                )?,
+            );
+            for (i, key) in matched(&mask, &tx_txs) {
+                transactions[i] = true;
+                if req.instructions {
+                    instructions_of.insert(key);
+                }
+                if req.balances {
+                    balances_of.insert(key);
+                }
+                if req.token_balances {
+                    token_balances_of.insert(key);
+                }
+                if req.logs {
+                    logs_of.insert(key);
+                }
+            }
+        }
+
+        for req in query.logs.iter() {
+            let mask = log_mask(&self.logs, req).context("match logs")?;
+            for (i, key) in matched(&mask, &log_keys) {
+                logs[i] = true;
+                if req.transaction {
+                    txs_of.insert((key.0, key.1));
+                }
+                if req.instruction {
+                    instructions_of_logs.insert(key);
+                }
+            }
+        }
+
+        for req in query.balances.iter() {
+            let mask = binary_in(&self.balances, "account", &decode_base58_all(&req.account)?)?;
+            for (i, key) in matched(&mask, &balance_txs) {
+                balances[i] = true;
+                if req.transaction {
+                    txs_of.insert(key);
+                }
+                if req.transaction_instructions {
+                    instructions_of.insert(key);
+                }
+            }
+        }
+
+        for req in query.token_balances.iter() {
+            let mask =
+                token_balance_mask(&self.token_balances, req).context("match token balances")?;
+            for (i, key) in matched(&mask, &token_balance_txs) {
+                token_balances[i] = true;
+                if req.transaction {
+                    txs_of.insert(key);
+                }
+                if req.transaction_instructions {
+                    instructions_of.insert(key);
+                }
+            }
+        }
+
+        let mut rewards = vec![false; self.rewards.num_rows()];
+        for req in query.rewards.iter() {
+            or(
+                &mut rewards,
+                &reward_mask(&self.rewards, req).context("match rewards")?,
+            );
+        }
+
+        or(&mut transactions, &key_in(&tx_txs, &txs_of));
+        or(
+            &mut instructions,
+            &key_in(&instruction_txs, &instructions_of),
+        );
+        or(&mut instructions, &key_in(&instruction_keys, &parents_of));
+        or(
+            &mut instructions,
+            &key_in(&instruction_keys, &instructions_of_logs),
+        );
+        or(
+            &mut instructions,
+            &descendant_of(&instruction_keys, &inner_of),
+        );
+        or(&mut logs, &key_in(&log_txs, &logs_of));
+        or(&mut logs, &key_in(&log_keys, &logs_of_instructions));
+        or(&mut balances, &key_in(&balance_txs, &balances_of));
+        or(
+            &mut token_balances,
+            &key_in(&token_balance_txs, &token_balances_of),
+        );
+
+        let range = |batch: &RecordBatch, column: &str| {
+            in_block_range(batch, column, query.from_block, query.to_block)
+        };
+        and(&mut instructions, &range(&self.instructions, "block_slot")?);
+        and(&mut transactions, &range(&self.transactions, "block_slot")?);
+        and(&mut logs, &range(&self.logs, "block_slot")?);
+        and(&mut balances, &range(&self.balances, "block_slot")?);
+        and(
+            &mut token_balances,
+            &range(&self.token_balances, "block_slot")?,
+        );
+        and(&mut rewards, &range(&self.rewards, "block_slot")?);
+
+        let mut with_rows = HashSet::new();
+        for (batch, mask) in [
+            (&self.instructions, &instructions),
+            (&self.transactions, &transactions),
+            (&self.logs, &logs),
+            (&self.balances, &balances),
+            (&self.token_balances, &token_balances),
+            (&self.rewards, &rewards),
+        ] {
+            kept_blocks(batch, "block_slot", mask, &mut with_rows)?;
+        }
+        let mut blocks = block_mask(&self.blocks, "slot", &with_rows, query.include_all_blocks)?;
+        and(&mut blocks, &range(&self.blocks, "slot")?);
+
+        Ok(Self {
+            blocks: filter(&self.blocks, blocks).context("blocks")?,
+            transactions: filter(&self.transactions, transactions).context("transactions")?,
+            instructions: filter(&self.instructions, instructions).context("instructions")?,
+            logs: filter(&self.logs, logs).context("logs")?,
+            balances: filter(&self.balances, balances).context("balances")?,
+            token_balances: filter(&self.token_balances, token_balances)
+                .context("token balances")?,
+            rewards: filter(&self.rewards, rewards).context("rewards")?,
+        })
+    }
+}
+
+fn decode_base58_all(values: &[String]) -> Result<Vec<Vec<u8>>> {
+    values.iter().map(|v| decode_base58(v.trim())).collect()
+}
+
+fn decode_hex_all(values: &[String]) -> Result<Vec<Vec<u8>>> {
+    values
+        .iter()
+        .map(|v| {
+            let digits = v.strip_prefix("0x").unwrap_or(v);
+            decode_prefixed_hex(&format!("0x{}", digits))
+                .with_context(|| format!("decode filter value {}", v))
+        })
+        .collect()
+}
+
+fn instruction_mask(instructions: &RecordBatch, req: &InstructionRequest) -> Result<Mask> {
+    let batch = instructions;
+
+    let mut mask = binary_in(batch, "program_id", &decode_base58_all(&req.program_id)?)?;
+    for (column, values) in [
+        ("d1", &req.d1),
+        ("d2", &req.d2),
+        ("d4", &req.d4),
+        ("d8", &req.d8),
+    ] {
+        and(
+            &mut mask,
+            &binary_in(batch, column, &decode_hex_all(values)?)?,
+        );
+    }
+    // there is no d3 column so it is matched against the start of the data
+    and(
+        &mut mask,
+        &binary_prefix_in(batch, "data", &decode_hex_all(&req.d3)?)?,
+    );
+
+    let accounts = [
+        &req.a0, &req.a1, &req.a2, &req.a3, &req.a4, &req.a5, &req.a6, &req.a7, &req.a8, &req.a9,
+    ];
+    for (i, values) in accounts.iter().enumerate() {
+        let column = format!("a{}", i);
+        and(
+            &mut mask,
+            &binary_in(batch, &column, &decode_base58_all(values)?)?,
+        );
+    }
+    let rest = [&req.a10, &req.a11, &req.a12, &req.a13, &req.a14, &req.a15];
+    for (i, values) in rest.iter().enumerate() {
+        and(
+            &mut mask,
+            &list_item_in(batch, "rest_of_accounts", i, &decode_base58_all(values)?)?,
+        );
+    }
+
+    if !req.mentions_account.is_empty() {
+        let values = decode_base58_all(&req.mentions_account)?;
+        let mut mentions = list_contains(batch, "rest_of_accounts", &values)?;
+        for i in 0..accounts.len() {
+            or(
+                &mut mentions,
+                &binary_in(batch, &format!("a{}", i), &values)?,
+            );
+        }
+        and(&mut mask, &mentions);
+    }
+
+    and(
+        &mut mask,
+        &bool_eq(batch, "is_committed", req.is_committed)?,
+    );
+
+    Ok(mask)
+}
+
+fn log_mask(logs: &RecordBatch, req: &LogRequest) -> Result<Mask> {
+    let mut mask = binary_in(logs, "program_id", &decode_base58_all(&req.program_id)?)?;
+    and(&mut mask, &utf8_in(logs, "kind", &req.kind)?);
+    Ok(mask)
+}
+
+fn token_balance_mask(batch: &RecordBatch, req: &TokenBalanceRequest) -> Result<Mask> {
+    let mut mask = vec![true; batch.num_rows()];
+    for (column, values) in [
+        ("account", &req.account),
+        ("pre_program_id", &req.pre_program_id),
+        ("post_program_id", &req.post_program_id),
+        ("pre_mint", &req.pre_mint),
+        ("post_mint", &req.post_mint),
+        ("pre_owner", &req.pre_owner),
+        ("post_owner", &req.post_owner),
+    ] {
+        and(
+            &mut mask,
+            &binary_in(batch, column, &decode_base58_all(values)?)?,
+        );
+    }
+    Ok(mask)
+}
+
+fn reward_mask(rewards: &RecordBatch, req: &RewardRequest) -> Result<Mask> {
+    let mut mask = binary_in(rewards, "pubkey", &decode_base58_all(&req.pubkey)?)?;
+    and(
+        &mut mask,
+        &utf8_in(rewards, "reward_type", &req.reward_type)?,
+    );
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svm::{parse_response, BalanceRequest};
+
+    const A: &str = "11111111111111111111111111111111";
+    const B: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
+
+    #[test]
+    fn demux_keeps_matching_rows() {
+        let data = [
+            simd_json::json!({
+                "header": {"number": 1},
+                "instructions": [
+                    {"transactionIndex": 0, "instructionAddress": [0], "programId": A},
+                    {"transactionIndex": 0, "instructionAddress": [0, 0], "programId": B},
+                    {"transactionIndex": 1, "instructionAddress": [0], "programId": B},
+                ],
+                "balances": [{"transactionIndex": 0, "account": A}],
+            }),
+            simd_json::json!({"header": {"number": 2}}),
+        ]
+        .iter()
+        .map(|block| simd_json::to_string(block).unwrap())
+        .collect::<Vec<_>>()
+        .join("\n");
+        let res = parse_response(data.as_bytes()).unwrap();
+
+        let query = Query {
+            instructions: vec![InstructionRequest {
+                program_id: vec![A.to_owned()],
+                inner_instructions: true,
+                transaction_balances: true,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let demuxed = res.demux(&query).unwrap();
+        assert_eq!(demuxed.instructions.num_rows(), 2);
+        assert_eq!(demuxed.balances.num_rows(), 1);
+        assert_eq!(demuxed.blocks.num_rows(), 2);
+
+        let query = Query {
+            balances: vec![BalanceRequest {
+                account: vec![B.to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let demuxed = res.demux(&query).unwrap();
+        assert_eq!(demuxed.instructions.num_rows(), 0);
+        assert_eq!(demuxed.balances.num_rows(), 0);
+        assert_eq!(demuxed.blocks.num_rows(), 1);
+    }
+}
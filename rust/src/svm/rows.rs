@@ -0,0 +1,387 @@
+//! Typed blocks that are deserialized straight from the portal response.
+
+use std::fmt;
+use std::str::FromStr;
+
+use alloy_primitives::Bytes;
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::rows::{dedup_by_key, BlockRows};
+
+/// A block along with its items that matched the query.
+///
+/// Fields that weren't selected in the query are `None`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockData {
+    pub header: Block,
+    #[serde(default)]
+    pub transactions: Vec<Transaction>,
+    #[serde(default)]
+    pub instructions: Vec<Instruction>,
+    #[serde(default)]
+    pub logs: Vec<Log>,
+    #[serde(default)]
+    pub balances: Vec<Balance>,
+    #[serde(default)]
+    pub token_balances: Vec<TokenBalance>,
+    #[serde(default)]
+    pub rewards: Vec<Reward>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Block {
+    /// Slot of the block, always selected by the client.
+    pub number: u64,
+    pub hash: Option<Pubkey>,
+    pub parent_number: Option<u64>,
+    pub parent_hash: Option<Pubkey>,
+    pub height: Option<u64>,
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Transaction {
+    pub transaction_index: Option<u32>,
+    pub version: Option<TransactionVersion>,
+    pub account_keys: Option<Vec<Pubkey>>,
+    pub address_table_lookups: Option<Vec<AddressTableLookup>>,
+    pub num_readonly_signed_accounts: Option<u32>,
+    pub num_readonly_unsigned_accounts: Option<u32>,
+    pub num_required_signatures: Option<u32>,
+    pub recent_blockhash: Option<Pubkey>,
+    pub signatures: Option<Vec<Signature>>,
+    /// The error as it was returned by the node.
+    pub err: Option<serde_json::Value>,
+    #[serde(default, with = "bigint")]
+    pub fee: Option<u64>,
+    #[serde(default, with = "bigint")]
+    pub compute_units_consumed: Option<u64>,
+    pub loaded_addresses: Option<LoadedAddresses>,
+    pub fee_payer: Option<Pubkey>,
+    pub has_dropped_log_messages: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawVersion", into = "RawVersion")]
+pub enum TransactionVersion {
+    Legacy,
+    V(u8),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawVersion {
+    Name(String),
+    Number(u8),
+}
+
+impl TryFrom<RawVersion> for TransactionVersion {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawVersion) -> anyhow::Result<Self> {
+        match raw {
+            RawVersion::Name(name) if name == "legacy" => Ok(Self::Legacy),
+            RawVersion::Name(name) => Err(anyhow!("unknown transaction version {}", name)),
+            RawVersion::Number(n) => Ok(Self::V(n)),
+        }
+    }
+}
+
+impl From<TransactionVersion> for RawVersion {
+    fn from(version: TransactionVersion) -> Self {
+        match version {
+            TransactionVersion::Legacy => Self::Name("legacy".to_owned()),
+            TransactionVersion::V(n) => Self::Number(n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AddressTableLookup {
+    pub account_key: Option<Pubkey>,
+    pub writable_indexes: Option<Vec<u64>>,
+    pub readonly_indexes: Option<Vec<u64>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoadedAddresses {
+    pub readonly: Vec<Pubkey>,
+    pub writable: Vec<Pubkey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Instruction {
+    pub transaction_index: Option<u32>,
+    pub instruction_address: Option<Vec<u32>>,
+    pub program_id: Option<Pubkey>,
+    pub accounts: Option<Vec<Pubkey>>,
+    #[serde(default, with = "base58")]
+    pub data: Option<Vec<u8>>,
+    pub d1: Option<Bytes>,
+    pub d2: Option<Bytes>,
+    pub d4: Option<Bytes>,
+    pub d8: Option<Bytes>,
+    pub error: Option<String>,
+    #[serde(default, with = "bigint")]
+    pub compute_units_consumed: Option<u64>,
+    pub is_committed: Option<bool>,
+    pub has_dropped_log_messages: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Log {
+    pub transaction_index: Option<u32>,
+    pub log_index: Option<u32>,
+    pub instruction_address: Option<Vec<u32>>,
+    pub program_id: Option<Pubkey>,
+    pub kind: Option<String>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Balance {
+    pub transaction_index: Option<u32>,
+    pub account: Option<Pubkey>,
+    #[serde(default, with = "bigint")]
+    pub pre: Option<u64>,
+    #[serde(default, with = "bigint")]
+    pub post: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenBalance {
+    pub transaction_index: Option<u32>,
+    pub account: Option<Pubkey>,
+    pub pre_mint: Option<Pubkey>,
+    pub post_mint: Option<Pubkey>,
+    pub pre_decimals: Option<u16>,
+    pub post_decimals: Option<u16>,
+    pub pre_program_id: Option<Pubkey>,
+    pub post_program_id: Option<Pubkey>,
+    pub pre_owner: Option<Pubkey>,
+    pub post_owner: Option<Pubkey>,
+    #[serde(default, with = "bigint")]
+    pub pre_amount: Option<u64>,
+    #[serde(default, with = "bigint")]
+    pub post_amount: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reward {
+    pub pubkey: Option<Pubkey>,
+    #[serde(default, with = "bigint")]
+    pub lamports: Option<i64>,
+    #[serde(default, with = "bigint")]
+    pub post_balance: Option<u64>,
+    pub reward_type: Option<String>,
+    pub commission: Option<u8>,
+}
+
+/// A 32 byte key, which is written in base58. Block hashes use the same encoding.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pubkey(pub [u8; 32]);
+
+/// A 64 byte transaction signature, which is written in base58.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Signature(pub [u8; 64]);
+
+macro_rules! impl_base58 {
+    ($name:ident, $len:expr) => {
+        impl FromStr for $name {
+            type Err = anyhow::Error;
+
+            fn from_str(s: &str) -> anyhow::Result<Self> {
+                let mut out = [0; $len];
+                let len = bs58::decode(s)
+                    .onto(&mut out)
+                    .with_context(|| format!("base58 decode val {}", s))?;
+                if len != $len {
+                    return Err(anyhow!("expected {} bytes but got {}", $len, len));
+                }
+                Ok(Self(out))
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&bs58::encode(self.0).into_string())
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+impl_base58!(Pubkey, 32);
+impl_base58!(Signature, 64);
+
+/// Integers that the portal sends as decimal strings.
+mod bigint {
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &Option<T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(v) => serializer.collect_str(v),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| s.parse().map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+/// Byte strings of any length that are written in base58.
+mod base58 {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(v) => serializer.serialize_str(&bs58::encode(v).into_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| {
+                bs58::decode(&s)
+                    .into_vec()
+                    .map_err(serde::de::Error::custom)
+            })
+            .transpose()
+    }
+}
+
+impl BlockRows for BlockData {
+    fn number(&self) -> u64 {
+        self.header.number
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        self.header.timestamp.and_then(|t| u64::try_from(t).ok())
+    }
+
+    fn row_counts(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("blocks", 1),
+            ("transactions", self.transactions.len()),
+            ("instructions", self.instructions.len()),
+            ("logs", self.logs.len()),
+            ("balances", self.balances.len()),
+            ("token_balances", self.token_balances.len()),
+            ("rewards", self.rewards.len()),
+        ]
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.transactions.extend(other.transactions);
+        dedup_by_key(&mut self.transactions, |tx| tx.transaction_index);
+        self.instructions.extend(other.instructions);
+        dedup_by_key(&mut self.instructions, |ins| {
+            (ins.transaction_index, ins.instruction_address.clone())
+        });
+        self.logs.extend(other.logs);
+        dedup_by_key(&mut self.logs, |log| (log.transaction_index, log.log_index));
+        self.balances.extend(other.balances);
+        dedup_by_key(&mut self.balances, |b| (b.transaction_index, b.account));
+        self.token_balances.extend(other.token_balances);
+        dedup_by_key(&mut self.token_balances, |b| {
+            (b.transaction_index, b.account)
+        });
+        self.rewards.extend(other.rewards);
+        dedup_by_key(&mut self.rewards, |r| r.pubkey);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserializes_block() {
+        let block: BlockData = serde_json::from_str(
+            r#"{
+                "header":{"number":5,"timestamp":100},
+                "transactions":[{"transactionIndex":1,"version":"legacy","fee":"5000","feePayer":"11111111111111111111111111111112"}],
+                "instructions":[{"transactionIndex":1,"instructionAddress":[0],"data":"3yZe7d","d1":"0x02"}],
+                "rewards":[{"pubkey":"11111111111111111111111111111112","lamports":"-10"}]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(block.header.number, 5);
+        assert_eq!(block.header.timestamp, Some(100));
+
+        let tx = &block.transactions[0];
+        assert_eq!(tx.version, Some(TransactionVersion::Legacy));
+        assert_eq!(tx.fee, Some(5000));
+        let mut key = [0; 32];
+        key[31] = 1;
+        assert_eq!(tx.fee_payer, Some(Pubkey(key)));
+        assert_eq!(
+            tx.fee_payer.unwrap().to_string(),
+            "11111111111111111111111111111112"
+        );
+
+        let ins = &block.instructions[0];
+        assert_eq!(ins.data, Some(b"test".to_vec()));
+        assert_eq!(ins.d1, Some(Bytes::from_static(&[2])));
+
+        assert_eq!(block.rewards[0].lamports, Some(-10));
+
+        let json = serde_json::to_string(&block).unwrap();
+        assert_eq!(serde_json::from_str::<BlockData>(&json).unwrap(), block);
+    }
+
+    #[test]
+    fn rejects_short_pubkey() {
+        assert!("3yZe7d".parse::<Pubkey>().is_err());
+    }
+}
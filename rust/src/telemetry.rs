@@ -0,0 +1,122 @@
+//! Metrics and tracing hooks.
+//!
+//! Everything here is a no-op unless the `metrics` or `tracing` features are enabled.
+//!
+//! Metrics are labeled with the dataset url:
+//! - `sqd_portal_request_duration_seconds` (histogram): time spent on a request including retries.
+//! - `sqd_portal_response_bytes` (histogram): size of the response body.
+//! - `sqd_portal_request_retries_total` (counter): number of retried requests.
+//! - `sqd_portal_cache_hits_total` (counter): number of responses served from the cache.
+//! - `sqd_portal_blocks_scanned_total` (counter): size of the block range covered by responses.
+//! - `sqd_portal_last_scanned_block` (gauge): last block covered by a response.
+//! - `sqd_portal_rows_total` (counter): number of rows received, also labeled with the table.
+//! - `sqd_portal_head_wait_seconds` (histogram): time a stream spent waiting for new blocks.
+
+#![cfg_attr(
+    not(all(feature = "metrics", feature = "tracing")),
+    allow(unused_variables)
+)]
+
+use std::future::Future;
+use std::time::Duration;
+
+use reqwest::Url;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+pub(crate) fn stream_span(url: &Url, cursor: u64) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::info_span!("portal_stream", dataset = %url, cursor)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        Span
+    }
+}
+
+pub(crate) fn request_span(url: &Url, cursor: Option<u64>) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::info_span!("portal_request", dataset = %url, cursor)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        Span
+    }
+}
+
+pub(crate) fn record_cursor(span: &Span, cursor: u64) {
+    #[cfg(feature = "tracing")]
+    span.record("cursor", cursor);
+}
+
+pub(crate) fn instrument<F: Future>(fut: F, span: Span) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::Instrument::instrument(fut, span)
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        fut
+    }
+}
+
+pub(crate) fn record_request(url: &Url, elapsed: Duration, response_bytes: Option<usize>) {
+    #[cfg(feature = "metrics")]
+    {
+        let dataset = url.to_string();
+        metrics::histogram!("sqd_portal_request_duration_seconds", "dataset" => dataset.clone())
+            .record(elapsed.as_secs_f64());
+        if let Some(n) = response_bytes {
+            metrics::histogram!("sqd_portal_response_bytes", "dataset" => dataset).record(n as f64);
+        }
+    }
+}
+
+pub(crate) fn record_retry(url: &Url) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("sqd_portal_request_retries_total", "dataset" => url.to_string())
+        .increment(1);
+}
+
+#[cfg(feature = "cache")]
+pub(crate) fn record_cache_hit(url: &Url) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("sqd_portal_cache_hits_total", "dataset" => url.to_string()).increment(1);
+}
+
+/// Records the block range `[from_block, to_block]` covered by a response and its row counts.
+pub(crate) fn record_response(
+    url: &Url,
+    from_block: u64,
+    to_block: Option<u64>,
+    row_counts: &[(&'static str, usize)],
+) {
+    #[cfg(feature = "metrics")]
+    {
+        let dataset = url.to_string();
+        if let Some(to_block) = to_block {
+            metrics::counter!("sqd_portal_blocks_scanned_total", "dataset" => dataset.clone())
+                .increment((to_block + 1).saturating_sub(from_block));
+            metrics::gauge!("sqd_portal_last_scanned_block", "dataset" => dataset.clone())
+                .set(to_block as f64);
+        }
+        for (table, num_rows) in row_counts {
+            let dataset = dataset.clone();
+            metrics::counter!("sqd_portal_rows_total", "dataset" => dataset, "table" => *table)
+                .increment(*num_rows as u64);
+        }
+    }
+}
+
+pub(crate) fn record_head_wait(url: &Url, elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!("sqd_portal_head_wait_seconds", "dataset" => url.to_string())
+        .record(elapsed.as_secs_f64());
+}
@@ -0,0 +1,58 @@
+//! Fixtures shared by the tests.
+
+use std::time::Duration;
+
+use crate::{evm, StreamItem};
+
+#[cfg(any(feature = "parquet", feature = "sqlite", feature = "duckdb"))]
+pub(crate) const ADDRESS_A: &str = "0x0000000000000000000000000000000000000001";
+#[cfg(any(feature = "parquet", feature = "sqlite", feature = "duckdb"))]
+pub(crate) const ADDRESS_B: &str = "0x0000000000000000000000000000000000000002";
+
+/// Returns a directory for the test under the temporary directory, removing what a previous run
+/// left there.
+#[cfg(any(feature = "parquet", feature = "cache"))]
+pub(crate) fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("sqd-portal-client-{}-{}", name, std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    dir
+}
+
+/// Parses the response body into an item that covers `from_block..=to_block`.
+pub(crate) fn stream_item(
+    from_block: u64,
+    to_block: u64,
+    data: &str,
+) -> StreamItem<evm::ArrowResponse> {
+    StreamItem {
+        from_block,
+        to_block,
+        head: to_block,
+        num_bytes: data.len(),
+        duration: Duration::ZERO,
+        data: evm::parse_response(data.as_bytes()).unwrap(),
+    }
+}
+
+/// An item with blocks `from_block` and `to_block`, the first one has a log of `ADDRESS_A` and a
+/// log of `ADDRESS_B` in the same transaction.
+#[cfg(any(feature = "parquet", feature = "sqlite", feature = "duckdb"))]
+pub(crate) fn evm_item(from_block: u64, to_block: u64) -> StreamItem<evm::ArrowResponse> {
+    let data = [
+        simd_json::json!({
+            "header": {"number": from_block},
+            "logs": [
+                {"logIndex": 0, "transactionIndex": 0, "address": ADDRESS_A},
+                {"logIndex": 1, "transactionIndex": 0, "address": ADDRESS_B},
+            ],
+        }),
+        simd_json::json!({"header": {"number": to_block}}),
+    ]
+    .iter()
+    .map(|block| simd_json::to_string(block).unwrap())
+    .collect::<Vec<_>>()
+    .join("\n");
+
+    stream_item(from_block, to_block, &data)
+}
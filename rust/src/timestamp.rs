@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::ops::Range;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{Client, PortalQuery};
+
+/// Probes are cleared after the cache grows past this many blocks.
+const MAX_CACHED_BLOCKS: usize = 1_000_000;
+
+/// Timestamps of the blocks seen while resolving timestamps, keyed by block number.
+#[derive(Default)]
+pub(crate) struct TimestampCache {
+    blocks: Mutex<BTreeMap<u64, u64>>,
+}
+
+impl TimestampCache {
+    fn insert(&self, blocks: &[(u64, u64)]) {
+        let mut cache = self.blocks.lock().unwrap();
+        if cache.len() + blocks.len() > MAX_CACHED_BLOCKS {
+            cache.clear();
+        }
+        cache.extend(blocks.iter().copied());
+    }
+
+    /// Returns the first block after the last cached block that is before `timestamp` and
+    /// the first cached block at or after `timestamp`.
+    fn bounds(&self, timestamp: u64) -> (u64, Option<u64>) {
+        let cache = self.blocks.lock().unwrap();
+
+        let lo = cache
+            .iter()
+            .filter(|(_, t)| **t < timestamp)
+            .map(|(n, _)| *n + 1)
+            .max()
+            .unwrap_or(0);
+        let hi = cache
+            .iter()
+            .filter(|(_, t)| **t >= timestamp)
+            .map(|(n, _)| *n)
+            .min();
+
+        (lo, hi)
+    }
+}
+
+/// Finds the first block that has a timestamp at or after `timestamp`, or `None` if there is
+/// no such block at or below `head`.
+///
+/// `probe(from, to)` returns the number and timestamp of every block in the given range.
+/// Blocks that are missing from its result are assumed to not exist, like skipped Solana slots.
+async fn search<F, Fut>(
+    cache: &TimestampCache,
+    head: u64,
+    timestamp: u64,
+    mut probe: F,
+) -> Result<Option<u64>>
+where
+    F: FnMut(u64, u64) -> Fut,
+    Fut: Future<Output = Result<Vec<(u64, u64)>>>,
+{
+    // the answer is the first existing block in lo..hi that is at or after the timestamp,
+    // or `answer` if there is none.
+    let (mut lo, mut answer) = cache.bounds(timestamp);
+    answer = answer.filter(|n| *n <= head);
+    let mut hi = answer.unwrap_or(head + 1);
+
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+
+        let blocks = probe(mid, hi - 1).await.context("probe blocks")?;
+        cache.insert(&blocks);
+
+        let last = match blocks.last() {
+            Some((n, _)) => *n,
+            None => {
+                // there are no blocks in mid..hi
+                hi = mid;
+                continue;
+            }
+        };
+
+        match blocks.iter().position(|(_, t)| *t >= timestamp) {
+            None => lo = last + 1,
+            Some(0) => {
+                answer = Some(blocks[0].0);
+                hi = mid;
+            }
+            Some(i) => {
+                // previous block is before the timestamp and there is nothing in between
+                return Ok(Some(blocks[i].0));
+            }
+        }
+    }
+
+    Ok(answer)
+}
+
+impl Client {
+    pub(crate) async fn block_at_timestamp<Q: PortalQuery>(
+        &self,
+        timestamp: u64,
+    ) -> Result<Option<u64>> {
+        let head = self
+            .finalized_height()
+            .await
+            .context("get finalized height")?;
+
+        search(
+            &self.timestamp_cache,
+            head,
+            timestamp,
+            |from, to| async move {
+                let query = Q::timestamp_probe(from, to);
+                let res = self
+                    .arrow_finalized_query(&query)
+                    .await
+                    .context("run probe query")?;
+                match res {
+                    Some((res, _)) => Q::block_timestamps(&res),
+                    None => Ok(Vec::new()),
+                }
+            },
+        )
+        .await
+    }
+
+    pub(crate) async fn query_for_time_range<Q: PortalQuery>(
+        &self,
+        mut query: Q,
+        time_range: Range<u64>,
+    ) -> Result<Q> {
+        let start = self
+            .block_at_timestamp::<Q>(time_range.start)
+            .await
+            .context("resolve start of the range")?;
+        let end = self
+            .block_at_timestamp::<Q>(time_range.end)
+            .await
+            .context("resolve end of the range")?;
+        let (from_block, to_block) = block_range(&time_range, start, end)?;
+
+        query.set_cursor(from_block);
+        query.set_to_block(to_block);
+
+        Ok(query)
+    }
+}
+
+/// Returns the blocks of the time range, given the first blocks at or after its start and end.
+///
+/// Errors if the range has no blocks, e.g. when its end resolves to the first block of the chain.
+fn block_range(
+    time_range: &Range<u64>,
+    start: Option<u64>,
+    end: Option<u64>,
+) -> Result<(u64, Option<u64>)> {
+    let from_block = start.with_context(|| {
+        format!(
+            "start of the range ({}) is after the finalized head",
+            time_range.start
+        )
+    })?;
+
+    match end {
+        Some(end) if end <= from_block => Err(anyhow!(
+            "time range {}..{} has no blocks",
+            time_range.start,
+            time_range.end
+        )),
+        end => Ok((from_block, end.map(|n| n - 1))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blocks 0..100 with a timestamp of 10 * number, every block divisible by 3 is skipped.
+    async fn probe(from: u64, to: u64) -> Result<Vec<(u64, u64)>> {
+        Ok((from..=to.min(99))
+            .filter(|n| n % 3 != 0)
+            .take(5)
+            .map(|n| (n, n * 10))
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn search_finds_first_block_at_or_after_timestamp() {
+        for (timestamp, expected) in [
+            (0, Some(1)),
+            (10, Some(1)),
+            (11, Some(2)),
+            (295, Some(31)),
+            (300, Some(31)),
+            (980, Some(98)),
+            (990, None),
+            (5000, None),
+        ] {
+            let cache = TimestampCache::default();
+            let res = search(&cache, 99, timestamp, probe).await.unwrap();
+            assert_eq!(res, expected, "timestamp {}", timestamp);
+
+            // should give the same answer when it can use the cached probes
+            let res = search(&cache, 99, timestamp, probe).await.unwrap();
+            assert_eq!(res, expected, "cached timestamp {}", timestamp);
+        }
+    }
+
+    #[tokio::test]
+    async fn search_respects_head() {
+        let cache = TimestampCache::default();
+        assert_eq!(search(&cache, 40, 500, probe).await.unwrap(), None);
+        assert_eq!(search(&cache, 40, 400, probe).await.unwrap(), Some(40));
+    }
+
+    #[test]
+    fn block_range_excludes_end() {
+        assert_eq!(
+            block_range(&(10..50), Some(1), Some(5)).unwrap(),
+            (1, Some(4))
+        );
+        assert_eq!(block_range(&(10..5000), Some(1), None).unwrap(), (1, None));
+        assert!(block_range(&(5000..6000), None, None).is_err());
+        // the end resolves to the first block so no block is before it
+        assert!(block_range(&(0..5), Some(0), Some(0)).is_err());
+        assert!(block_range(&(11..12), Some(2), Some(2)).is_err());
+    }
+}
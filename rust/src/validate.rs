@@ -0,0 +1,210 @@
+use std::fmt;
+
+/// Error returned by the query builders when an input can't be sent to the portal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    /// Location of the invalid value in the query, e.g. `logs[0].address[1]`.
+    pub path: String,
+    pub kind: ValidationErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationErrorKind {
+    InvalidHex(String),
+    InvalidBase58(String),
+    /// Decoded value has the wrong number of bytes.
+    InvalidLength {
+        value: String,
+        expected: usize,
+        actual: usize,
+    },
+    /// Value is not one of the values the portal accepts for this filter.
+    UnknownValue {
+        value: String,
+        expected: &'static [&'static str],
+    },
+    /// `to_block` is less than `from_block`.
+    InvalidBlockRange {
+        from_block: u64,
+        to_block: u64,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {}: ", self.path)?;
+        match &self.kind {
+            ValidationErrorKind::InvalidHex(v) => write!(f, "{:?} is not valid hex", v),
+            ValidationErrorKind::InvalidBase58(v) => write!(f, "{:?} is not valid base58", v),
+            ValidationErrorKind::InvalidLength {
+                value,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{:?} is {} bytes long, expected {} bytes",
+                value, actual, expected
+            ),
+            ValidationErrorKind::UnknownValue { value, expected } => {
+                write!(f, "{:?} is not one of {:?}", value, expected)
+            }
+            ValidationErrorKind::InvalidBlockRange {
+                from_block,
+                to_block,
+            } => write!(
+                f,
+                "to_block ({}) is less than from_block ({})",
+                to_block, from_block
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+pub(crate) fn check_block_range(
+    from_block: u64,
+    to_block: Option<u64>,
+) -> Result<(), ValidationError> {
+    match to_block {
+        Some(to_block) if to_block < from_block => Err(ValidationError {
+            path: "to_block".to_owned(),
+            kind: ValidationErrorKind::InvalidBlockRange {
+                from_block,
+                to_block,
+            },
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Normalises every value to a lowercase `0x` prefixed hex string of `len` bytes.
+///
+/// The prefix is added if it is missing.
+pub(crate) fn normalize_hex(
+    path: &str,
+    values: &mut [String],
+    len: usize,
+) -> Result<(), ValidationError> {
+    for (i, value) in values.iter_mut().enumerate() {
+        let err = |kind| ValidationError {
+            path: format!("{}[{}]", path, i),
+            kind,
+        };
+
+        let digits = value.strip_prefix("0x").unwrap_or(value);
+        if digits.len() % 2 != 0 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(err(ValidationErrorKind::InvalidHex(value.clone())));
+        }
+        if digits.len() / 2 != len {
+            return Err(err(ValidationErrorKind::InvalidLength {
+                value: value.clone(),
+                expected: len,
+                actual: digits.len() / 2,
+            }));
+        }
+
+        *value = format!("0x{}", digits.to_ascii_lowercase());
+    }
+
+    Ok(())
+}
+
+/// Checks that every value is a base58 string that decodes to `len` bytes.
+pub(crate) fn check_base58(
+    path: &str,
+    values: &mut [String],
+    len: usize,
+) -> Result<(), ValidationError> {
+    for (i, value) in values.iter_mut().enumerate() {
+        let err = |kind| ValidationError {
+            path: format!("{}[{}]", path, i),
+            kind,
+        };
+
+        let trimmed = value.trim();
+        let decoded = bs58::decode(trimmed)
+            .with_alphabet(bs58::Alphabet::BITCOIN)
+            .into_vec()
+            .map_err(|_| err(ValidationErrorKind::InvalidBase58(value.clone())))?;
+        if decoded.len() != len {
+            return Err(err(ValidationErrorKind::InvalidLength {
+                value: value.clone(),
+                expected: len,
+                actual: decoded.len(),
+            }));
+        }
+
+        *value = trimmed.to_owned();
+    }
+
+    Ok(())
+}
+
+/// Checks that every value is one of `expected`.
+pub(crate) fn check_one_of(
+    path: &str,
+    values: &[String],
+    expected: &'static [&'static str],
+) -> Result<(), ValidationError> {
+    for (i, value) in values.iter().enumerate() {
+        if !expected.contains(&value.as_str()) {
+            return Err(ValidationError {
+                path: format!("{}[{}]", path, i),
+                kind: ValidationErrorKind::UnknownValue {
+                    value: value.clone(),
+                    expected,
+                },
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_is_normalized() {
+        let mut values = vec!["0xABcd".to_owned(), "ef01".to_owned()];
+        normalize_hex("x", &mut values, 2).unwrap();
+        assert_eq!(values, ["0xabcd", "0xef01"]);
+    }
+
+    #[test]
+    fn invalid_hex_is_rejected() {
+        let mut values = vec!["0xabcd".to_owned(), "0xabc".to_owned()];
+        let err = normalize_hex("logs[0].address", &mut values, 2).unwrap_err();
+        assert_eq!(err.path, "logs[0].address[1]");
+        assert_eq!(
+            err.kind,
+            ValidationErrorKind::InvalidHex("0xabc".to_owned())
+        );
+
+        let mut values = vec!["0xabcdef".to_owned()];
+        let err = normalize_hex("x", &mut values, 2).unwrap_err();
+        assert_eq!(
+            err.kind,
+            ValidationErrorKind::InvalidLength {
+                value: "0xabcdef".to_owned(),
+                expected: 2,
+                actual: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn base58_is_checked() {
+        let mut values = vec!["11111111111111111111111111111111".to_owned()];
+        check_base58("x", &mut values, 32).unwrap();
+
+        let mut values = vec!["0OIl".to_owned()];
+        let err = check_base58("x", &mut values, 32).unwrap_err();
+        assert_eq!(
+            err.kind,
+            ValidationErrorKind::InvalidBase58("0OIl".to_owned())
+        );
+    }
+}